@@ -1,20 +1,17 @@
 use bencher::{black_box, Bencher};
-use slp_server_rust::test::{client_connect, make_packet, make_server, recv_packet};
+use slp_server_rust::test::{client_connect, make_packet, make_sharded_server, recv_packet};
 use smoltcp::wire::*;
 use tokio::runtime::{self, Runtime};
 
-fn relay_n(b: &mut Bencher, count: usize, clinet_count: usize) {
+fn relay_n(b: &mut Bencher, count: usize, clinet_count: usize, shards: usize) {
     let rt = rt();
 
     b.iter(|| {
         rt.block_on(async {
-            let (_server, addr) = make_server().await;
+            let (_server, addr) = make_sharded_server(shards).await;
             let mut sockets = vec![];
             for i in 0..clinet_count {
-                let packet = make_packet(
-                    Ipv4Address::new(10, 13, 37, 100 + i as u8),
-                    Ipv4Address::new(10, 13, 255, 255),
-                );
+                let packet = make_packet(client_ip(i), Ipv4Address::new(10, 13, 255, 255));
                 let socket = client_connect(addr).await;
                 socket.send(&packet).await.unwrap();
                 sockets.push(socket);
@@ -35,24 +32,44 @@ fn relay_n(b: &mut Bencher, count: usize, clinet_count: usize) {
     });
 }
 
+fn client_ip(i: usize) -> Ipv4Address {
+    Ipv4Address::new(10, 13, 37 + (i / 100) as u8, 100 + (i % 100) as u8)
+}
+
 fn relay_1000(b: &mut Bencher) {
-    relay_n(b, 1000, 2)
+    relay_n(b, 1000, 2, 1)
 }
 
 fn relay_2000(b: &mut Bencher) {
-    relay_n(b, 2000, 2)
+    relay_n(b, 2000, 2, 1)
 }
 
 fn broadcast_1000_10(b: &mut Bencher) {
-    relay_n(b, 1000, 10)
+    relay_n(b, 1000, 10, 1)
 }
 
 fn broadcast_1000_20(b: &mut Bencher) {
-    relay_n(b, 1000, 20)
+    relay_n(b, 1000, 20, 1)
 }
 
 fn broadcast_1000_50(b: &mut Bencher) {
-    relay_n(b, 1000, 50)
+    relay_n(b, 1000, 50, 1)
+}
+
+fn broadcast_1000_100(b: &mut Bencher) {
+    relay_n(b, 1000, 100, 1)
+}
+
+fn broadcast_1000_200(b: &mut Bencher) {
+    relay_n(b, 1000, 200, 1)
+}
+
+fn sharded_broadcast_1000_50(b: &mut Bencher) {
+    relay_n(b, 1000, 50, 4)
+}
+
+fn sharded_broadcast_1000_200(b: &mut Bencher) {
+    relay_n(b, 1000, 200, 4)
 }
 
 fn rt() -> Runtime {
@@ -69,7 +86,15 @@ bencher::benchmark_group!(
     broadcast,
     broadcast_1000_10,
     broadcast_1000_20,
-    broadcast_1000_50
+    broadcast_1000_50,
+    broadcast_1000_100,
+    broadcast_1000_200
+);
+
+bencher::benchmark_group!(
+    sharded,
+    sharded_broadcast_1000_50,
+    sharded_broadcast_1000_200
 );
 
-bencher::benchmark_main!(relay, broadcast);
+bencher::benchmark_main!(relay, broadcast, sharded);
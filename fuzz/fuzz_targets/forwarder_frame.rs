@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use slp_server_rust::slp::{ForwarderFrame, Parser};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ForwarderFrame::parse(data);
+});
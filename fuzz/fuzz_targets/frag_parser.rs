@@ -0,0 +1,22 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use slp_server_rust::slp::{ForwarderFrame, FragParser, Parser, SocketAddr, MAX_REASSEMBLED_SIZE};
+
+// The input is a sequence of frames, each prefixed by the sending peer and a
+// big-endian length, so partial packets from several peers interleave.
+fuzz_target!(|data: &[u8]| {
+    let mut parser = FragParser::new();
+    let mut rest = data;
+    while let [peer, hi, lo, tail @ ..] = rest {
+        let len = (u16::from_be_bytes([*hi, *lo]) as usize).min(tail.len());
+        let (frame, tail) = tail.split_at(len);
+        rest = tail;
+
+        let peer = SocketAddr::from(([127, 0, 0, 1], *peer as u16 % 4));
+        if let Ok(ForwarderFrame::Ipv4Frag(frag)) = ForwarderFrame::parse(frame) {
+            if let Some(packet) = parser.process(peer, &frag) {
+                assert!(packet.len() <= MAX_REASSEMBLED_SIZE);
+            }
+        }
+    }
+});
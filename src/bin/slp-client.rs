@@ -0,0 +1,77 @@
+use clap::Parser;
+use env_logger::Env;
+use slp_server_rust::client::ClientBuilder;
+use std::time::Duration;
+use tokio::net::lookup_host;
+use tokio::time::sleep;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "slp-client",
+    version = std::env!("CARGO_PKG_VERSION"),
+    about = "Checks that a switch-lan-play server is reachable. Exits with 1 when no ping is answered.",
+)]
+struct Opt {
+    /// Server address, host:port
+    server: String,
+    /// Pings to send
+    #[arg(short, long, default_value_t = 4)]
+    count: u32,
+    /// Milliseconds between pings
+    #[arg(long, default_value_t = 1000)]
+    interval: u64,
+    /// Milliseconds to wait for each reply
+    #[arg(long, default_value_t = 1000)]
+    timeout: u64,
+    /// Print nothing, only set the exit code
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("slp_server_rust=warn")).init();
+    let opt = Opt::parse();
+
+    let server = lookup_host(&opt.server)
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Can't resolve server"))?;
+    let client = ClientBuilder::new()
+        .keepalive_interval(None)
+        .connect(server)
+        .await?;
+
+    let mut rtts = vec![];
+    for i in 0..opt.count {
+        if i > 0 {
+            sleep(Duration::from_millis(opt.interval)).await;
+        }
+        match client.ping(Duration::from_millis(opt.timeout)).await {
+            Ok(rtt) => {
+                if !opt.quiet {
+                    println!("reply from {}: seq={} time={:.1?}", server, i, rtt);
+                }
+                rtts.push(rtt);
+            }
+            Err(e) => {
+                if !opt.quiet {
+                    println!("no reply from {}: seq={} {}", server, i, e);
+                }
+            }
+        }
+    }
+
+    if !opt.quiet {
+        print!("{} sent, {} received", opt.count, rtts.len());
+        if let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) {
+            let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+            print!(", rtt min/avg/max = {:.1?}/{:.1?}/{:.1?}", min, avg, max);
+        }
+        println!();
+    }
+    if rtts.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
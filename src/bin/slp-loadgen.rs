@@ -0,0 +1,501 @@
+use clap::Parser;
+use env_logger::Env;
+use slp_server_rust::client::{Client, ClientBuilder};
+use slp_server_rust::plugin::ldn_mitm::lan_protocol::{LanMessage, NetworkInfo, NETWORK_INFO_SIZE};
+use slp_server_rust::plugin::ldn_mitm::{make_udp, parse_udp, LdnConfig};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::net::lookup_host;
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, sleep, timeout_at, Duration, Instant, MissedTickBehavior};
+
+/// UDP port of the generated LAN traffic
+const LOAD_PORT: u16 = 5000;
+/// Marks generated payloads: magic, kind, then the send time
+const MAGIC: &[u8; 4] = b"SLPL";
+const HEADER_LEN: usize = 4 + 1 + 8;
+const KIND_UNICAST: u8 = 0;
+const KIND_BROADCAST: u8 = 1;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "slp-loadgen",
+    version = std::env!("CARGO_PKG_VERSION"),
+    about = "Simulates many switch-lan-play clients against a server and reports throughput, latency and loss. \
+             Each client uses its own UDP socket, so raise the open file limit for thousands of them.",
+)]
+struct Opt {
+    /// Server address, host:port
+    server: String,
+    /// Simulated clients
+    #[arg(short, long, default_value_t = 100)]
+    clients: usize,
+    /// Seconds to generate traffic for
+    #[arg(short, long, default_value_t = 10)]
+    duration: u64,
+    /// Packets per second sent by each client
+    #[arg(short, long, default_value_t = 10.0)]
+    rate: f64,
+    /// Relative share of keepalives
+    #[arg(long, default_value_t = 1)]
+    keepalive_weight: u32,
+    /// Relative share of unicast LAN packets, to a random other client
+    #[arg(long, default_value_t = 8)]
+    unicast_weight: u32,
+    /// Relative share of broadcast LAN packets
+    #[arg(long, default_value_t = 1)]
+    broadcast_weight: u32,
+    /// UDP payload bytes of a LAN packet
+    #[arg(long, default_value_t = 200)]
+    size: usize,
+    /// Share of LAN packets sent with `--frag-size` bytes instead
+    #[arg(long, default_value_t = 0.0)]
+    frag_ratio: f64,
+    /// UDP payload bytes of a large LAN packet, fragmented when above the pmtu
+    #[arg(long, default_value_t = 3000)]
+    frag_size: usize,
+    /// Largest packet a client sends in one frame
+    #[arg(long, default_value_t = 1300)]
+    pmtu: u16,
+    /// Clients acting as LDN hosts, announcing a room and answering scans
+    #[arg(long, default_value_t = 0)]
+    ldn_hosts: usize,
+    /// Milliseconds to keep receiving after the last packet is sent
+    #[arg(long, default_value_t = 1000)]
+    drain: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    keepalive: AtomicU64,
+    unicast: AtomicU64,
+    broadcast: AtomicU64,
+    fragmented: AtomicU64,
+    sent_bytes: AtomicU64,
+    send_errors: AtomicU64,
+    recv_unicast: AtomicU64,
+    recv_broadcast: AtomicU64,
+    recv_bytes: AtomicU64,
+    announcements: AtomicU64,
+}
+
+impl Counters {
+    fn add(counter: &AtomicU64, v: u64) {
+        counter.fetch_add(v, Ordering::Relaxed);
+    }
+    fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+}
+
+/// xorshift64*, good enough to pick traffic
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+    fn chance(&mut self, p: f64) -> bool {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64 <= p && p > 0.0
+    }
+}
+
+/// Latencies in microseconds, in log-linear buckets of about 3%
+struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    max: u64,
+}
+
+impl Histogram {
+    const SUB_BITS: u32 = 5;
+    const LINEAR: u64 = 1 << (Self::SUB_BITS + 1);
+
+    fn new() -> Self {
+        Histogram {
+            buckets: vec![0; Self::LINEAR as usize + 64 * (1 << Self::SUB_BITS)],
+            count: 0,
+            max: 0,
+        }
+    }
+    fn index(v: u64) -> usize {
+        if v < Self::LINEAR {
+            return v as usize;
+        }
+        let exp = 63 - v.leading_zeros();
+        let sub = (v >> (exp - Self::SUB_BITS)) & ((1 << Self::SUB_BITS) - 1);
+        (Self::LINEAR + ((exp - Self::SUB_BITS - 1) as u64) * (1 << Self::SUB_BITS) + sub) as usize
+    }
+    /// The lowest value in a bucket
+    fn value(index: usize) -> u64 {
+        let index = index as u64;
+        if index < Self::LINEAR {
+            return index;
+        }
+        let exp = (index - Self::LINEAR) / (1 << Self::SUB_BITS) + Self::SUB_BITS as u64 + 1;
+        let sub = (index - Self::LINEAR) % (1 << Self::SUB_BITS);
+        (1 << exp) | (sub << (exp - Self::SUB_BITS as u64))
+    }
+    fn record(&mut self, v: u64) {
+        self.buckets[Self::index(v)] += 1;
+        self.count += 1;
+        self.max = self.max.max(v);
+    }
+    fn merge(&mut self, other: &Histogram) {
+        for (a, b) in self.buckets.iter_mut().zip(&other.buckets) {
+            *a += b;
+        }
+        self.count += other.count;
+        self.max = self.max.max(other.max);
+    }
+    fn percentile(&self, p: f64) -> u64 {
+        let rank = ((self.count as f64) * p).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Self::value(i).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// LAN address of the `i`th client, clear of the server and broadcast
+/// addresses
+fn client_ip(i: usize) -> Ipv4Addr {
+    Ipv4Addr::new(10, 13, 1 + (i / 250) as u8, 2 + (i % 250) as u8)
+}
+
+/// Copies of `broadcast` packets the clients should receive: each one
+/// reaches every client but its sender.
+fn broadcast_deliveries(broadcast: u64, clients: usize) -> u64 {
+    broadcast * clients.saturating_sub(1) as u64
+}
+
+/// Percentage of `expected` packets that were not received
+fn loss(received: u64, expected: u64) -> f64 {
+    if expected == 0 {
+        0.0
+    } else {
+        100.0 * (1.0 - received as f64 / expected as f64)
+    }
+}
+
+fn payload(kind: u8, start: Instant, size: usize) -> Vec<u8> {
+    let sent = start.elapsed().as_micros() as u64;
+    let mut payload = Vec::with_capacity(size.max(HEADER_LEN));
+    payload.extend_from_slice(MAGIC);
+    payload.push(kind);
+    payload.extend_from_slice(&sent.to_be_bytes());
+    payload.resize(size.max(HEADER_LEN), 0);
+    payload
+}
+
+fn scan_response(ip: Ipv4Addr, config: &LdnConfig) -> Vec<u8> {
+    let mut info = vec![0u8; NETWORK_INFO_SIZE];
+    info[0..8].copy_from_slice(&0x0100_0000_0000_0000u64.to_le_bytes());
+    info[8..12].copy_from_slice(&ip.octets());
+    // the buffer has the right size, so this can't fail
+    let info = NetworkInfo::new(info).unwrap();
+    let message = LanMessage::ScanResponse(info).emit(true);
+    make_udp(ip, config.server_addr, config.port, &message)
+}
+
+struct Run {
+    opt: Opt,
+    start: Instant,
+    deadline: Instant,
+    counters: Counters,
+    ldn: LdnConfig,
+}
+
+impl Run {
+    async fn send_task(self: Arc<Self>, client: Arc<Client>, index: usize) {
+        let opt = &self.opt;
+        let ip = client_ip(index);
+        let mut rng = Rng::new(index as u64 + 1);
+        let total_weight = opt.keepalive_weight + opt.unicast_weight + opt.broadcast_weight;
+        if opt.rate <= 0.0 || total_weight == 0 {
+            return;
+        }
+        let period = Duration::from_secs_f64(1.0 / opt.rate);
+        let offset = Duration::from_micros(rng.below(period.as_micros() as u64 + 1));
+        let mut timer = interval_at(self.start + offset, period);
+        timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            if timer.tick().await >= self.deadline {
+                return;
+            }
+            let pick = rng.below(total_weight as u64) as u32;
+            if pick < opt.keepalive_weight {
+                match client.keepalive().await {
+                    Ok(_) => Counters::add(&self.counters.keepalive, 1),
+                    Err(_) => Counters::add(&self.counters.send_errors, 1),
+                }
+                continue;
+            }
+            let (kind, dst) = if pick < opt.keepalive_weight + opt.unicast_weight && opt.clients > 1
+            {
+                let mut other = rng.below(opt.clients as u64 - 1) as usize;
+                if other >= index {
+                    other += 1;
+                }
+                (KIND_UNICAST, client_ip(other))
+            } else {
+                (KIND_BROADCAST, self.ldn.broadcast_addr)
+            };
+            let large = rng.chance(opt.frag_ratio);
+            let size = if large { opt.frag_size } else { opt.size };
+            let packet = make_udp(ip, dst, LOAD_PORT, &payload(kind, self.start, size));
+            match client.send_ipv4(&packet).await {
+                Ok(_) => {
+                    let counter = match kind {
+                        KIND_UNICAST => &self.counters.unicast,
+                        _ => &self.counters.broadcast,
+                    };
+                    Counters::add(counter, 1);
+                    Counters::add(&self.counters.sent_bytes, packet.len() as u64);
+                    if packet.len() > opt.pmtu as usize {
+                        Counters::add(&self.counters.fragmented, 1);
+                    }
+                }
+                Err(_) => Counters::add(&self.counters.send_errors, 1),
+            }
+        }
+    }
+    async fn recv_task(self: Arc<Self>, client: Arc<Client>, index: usize) -> Histogram {
+        let mut histogram = Histogram::new();
+        let is_host = index < self.opt.ldn_hosts;
+        let ip = client_ip(index);
+        let end = self.deadline + Duration::from_millis(self.opt.drain);
+        while let Ok(Some(packet)) = timeout_at(end, client.recv_ipv4()).await {
+            let now = self.start.elapsed().as_micros() as u64;
+            if let Some(payload) = parse_udp(&packet, LOAD_PORT) {
+                if payload.len() < HEADER_LEN || &payload[0..4] != MAGIC {
+                    continue;
+                }
+                let mut sent = [0u8; 8];
+                sent.copy_from_slice(&payload[5..HEADER_LEN]);
+                histogram.record(now.saturating_sub(u64::from_be_bytes(sent)));
+                let counter = match payload[4] {
+                    KIND_UNICAST => &self.counters.recv_unicast,
+                    _ => &self.counters.recv_broadcast,
+                };
+                Counters::add(counter, 1);
+                Counters::add(&self.counters.recv_bytes, packet.len() as u64);
+            } else if is_host {
+                let scan = parse_udp(&packet, self.ldn.port).map(LanMessage::parse);
+                if let Some(Ok(LanMessage::Scan)) = scan {
+                    if client
+                        .send_ipv4(&scan_response(ip, &self.ldn))
+                        .await
+                        .is_ok()
+                    {
+                        Counters::add(&self.counters.announcements, 1);
+                    }
+                }
+            }
+        }
+        histogram
+    }
+    fn report(&self, histogram: &Histogram) {
+        let c = &self.counters;
+        let secs = self.opt.duration.max(1) as f64;
+        let (unicast, broadcast) = (Counters::get(&c.unicast), Counters::get(&c.broadcast));
+        let sent = Counters::get(&c.keepalive) + unicast + broadcast;
+        let (recv_unicast, recv_broadcast) = (
+            Counters::get(&c.recv_unicast),
+            Counters::get(&c.recv_broadcast),
+        );
+        let received = recv_unicast + recv_broadcast;
+        let mbps = |bytes: u64| bytes as f64 * 8.0 / secs / 1e6;
+
+        println!(
+            "clients: {} ({} ldn hosts), {}s at {}/s each",
+            self.opt.clients, self.opt.ldn_hosts, self.opt.duration, self.opt.rate
+        );
+        println!(
+            "sent:     {} frames ({} keepalive, {} unicast, {} broadcast, {} fragmented), {} errors",
+            sent,
+            Counters::get(&c.keepalive),
+            unicast,
+            broadcast,
+            Counters::get(&c.fragmented),
+            Counters::get(&c.send_errors)
+        );
+        println!(
+            "          {:.0} packets/s, {:.2} Mbit/s of LAN packets",
+            sent as f64 / secs,
+            mbps(Counters::get(&c.sent_bytes))
+        );
+        println!(
+            "received: {} LAN packets, {:.0} packets/s, {:.2} Mbit/s",
+            received,
+            received as f64 / secs,
+            mbps(Counters::get(&c.recv_bytes))
+        );
+        println!(
+            "loss:     unicast {:.2}%, broadcast {:.2}%",
+            loss(recv_unicast, unicast),
+            loss(
+                recv_broadcast,
+                broadcast_deliveries(broadcast, self.opt.clients)
+            )
+        );
+        if histogram.count > 0 {
+            let ms = |us: u64| us as f64 / 1000.0;
+            println!(
+                "latency:  p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms, p99.9 {:.2}ms, max {:.2}ms",
+                ms(histogram.percentile(0.5)),
+                ms(histogram.percentile(0.9)),
+                ms(histogram.percentile(0.99)),
+                ms(histogram.percentile(0.999)),
+                ms(histogram.max)
+            );
+        }
+        if self.opt.ldn_hosts > 0 {
+            println!(
+                "ldn:      {} scans answered",
+                Counters::get(&c.announcements)
+            );
+        }
+    }
+}
+
+async fn connect(server: SocketAddr, opt: &Opt) -> std::io::Result<Vec<Arc<Client>>> {
+    let mut clients = Vec::with_capacity(opt.clients);
+    for i in 0..opt.clients {
+        let client = ClientBuilder::new().pmtu(opt.pmtu).connect(server).await?;
+        // a packet to itself teaches the server the client's LAN address
+        // without flooding everyone else
+        let ip = client_ip(i);
+        client.send_ipv4(&make_udp(ip, ip, LOAD_PORT, &[])).await?;
+        clients.push(Arc::new(client));
+    }
+    Ok(clients)
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("slp_server_rust=warn")).init();
+    let opt = Opt::parse();
+
+    let server = lookup_host(&opt.server)
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Can't resolve server"))?;
+    let clients = connect(server, &opt).await?;
+    let ldn = LdnConfig::default();
+    for (i, client) in clients.iter().enumerate().take(opt.ldn_hosts) {
+        client.send_ipv4(&scan_response(client_ip(i), &ldn)).await?;
+    }
+    sleep(Duration::from_millis(500)).await;
+
+    let start = Instant::now();
+    let run = Arc::new(Run {
+        deadline: start + Duration::from_secs(opt.duration),
+        start,
+        opt,
+        counters: Counters::default(),
+        ldn,
+    });
+    let receivers: Vec<JoinHandle<Histogram>> = clients
+        .iter()
+        .enumerate()
+        .map(|(i, client)| tokio::spawn(run.clone().recv_task(client.clone(), i)))
+        .collect();
+    let senders: Vec<JoinHandle<()>> = clients
+        .iter()
+        .enumerate()
+        .map(|(i, client)| tokio::spawn(run.clone().send_task(client.clone(), i)))
+        .collect();
+
+    for sender in senders {
+        sender.await?;
+    }
+    let mut histogram = Histogram::new();
+    for receiver in receivers {
+        histogram.merge(&receiver.await?);
+    }
+    run.report(&histogram);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{broadcast_deliveries, loss, Histogram};
+
+    #[test]
+    fn test_histogram_buckets() {
+        // exact below the linear range
+        for v in 0..Histogram::LINEAR {
+            assert_eq!(Histogram::value(Histogram::index(v)), v);
+        }
+        assert_eq!(Histogram::index(63), 63);
+        assert_eq!(Histogram::index(64), 64);
+        // 32 buckets per power of two from there, 2 apart between 64 and 128
+        assert_eq!(Histogram::index(65), 64);
+        assert_eq!(Histogram::index(66), 65);
+        assert_eq!(Histogram::index(127), 95);
+        assert_eq!(Histogram::index(128), 96);
+        assert_eq!(Histogram::value(96), 128);
+
+        // every value lands in the bucket starting at or just below it
+        let mut v = 1;
+        while v < u64::MAX / 3 {
+            for v in [v, v + 1, v * 3 / 2] {
+                let i = Histogram::index(v);
+                assert!(Histogram::value(i) <= v);
+                assert!(Histogram::value(i + 1) > v);
+                assert!(v - Histogram::value(i) <= v / 32);
+            }
+            v = v * 2 + 1;
+        }
+        assert!(Histogram::index(u64::MAX) < Histogram::new().buckets.len());
+    }
+
+    #[test]
+    fn test_histogram_percentile() {
+        let mut histogram = Histogram::new();
+        assert_eq!(histogram.percentile(0.5), 0);
+        for v in 1..=100 {
+            histogram.record(v);
+        }
+        assert_eq!(histogram.percentile(0.0), 1);
+        assert_eq!(histogram.percentile(0.5), 50);
+        assert_eq!(histogram.percentile(0.9), 90);
+        // buckets are 2 wide here and reported by their lowest value
+        assert_eq!(histogram.percentile(0.99), 98);
+        assert_eq!(histogram.percentile(1.0), 100);
+        assert_eq!(histogram.max, 100);
+
+        let mut other = Histogram::new();
+        other.record(10_000);
+        histogram.merge(&other);
+        assert_eq!(histogram.count, 101);
+        assert_eq!(histogram.percentile(1.0), 9_984);
+        assert_eq!(histogram.max, 10_000);
+    }
+
+    #[test]
+    fn test_loss() {
+        assert_eq!(broadcast_deliveries(10, 5), 40);
+        assert_eq!(broadcast_deliveries(10, 1), 0);
+        assert_eq!(broadcast_deliveries(10, 0), 0);
+        assert_eq!(loss(40, 40), 0.0);
+        assert_eq!(loss(30, 40), 25.0);
+        assert_eq!(loss(0, 0), 0.0);
+    }
+}
@@ -0,0 +1,105 @@
+//! An in-memory network interface, so a client can run a TCP/IP stack
+//! without a TUN device.
+
+use crate::slp::Packet;
+use smoltcp::iface::{Config, Interface, SocketSet};
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::wire::{HardwareAddress, IpCidr, Ipv4Cidr};
+use std::collections::VecDeque;
+
+/// Packets in both directions, queued in memory
+#[derive(Debug, Default)]
+struct QueueDevice {
+    rx: VecDeque<Packet>,
+    tx: VecDeque<Packet>,
+}
+
+impl Device for QueueDevice {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        // fragmentation is up to the client, which knows the pmtu
+        caps.max_transmission_unit = crate::slp::MAX_REASSEMBLED_SIZE;
+        caps.medium = Medium::Ip;
+        caps
+    }
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let buffer = self.rx.pop_front()?;
+        Some((RxToken { buffer }, TxToken { tx: &mut self.tx }))
+    }
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxToken { tx: &mut self.tx })
+    }
+}
+
+struct RxToken {
+    buffer: Packet,
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.buffer)
+    }
+}
+
+struct TxToken<'a> {
+    tx: &'a mut VecDeque<Packet>,
+}
+
+impl<'a> phy::TxToken for TxToken<'a> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut buffer = vec![0; len];
+        let result = f(&mut buffer);
+        self.tx.push_back(buffer);
+        result
+    }
+}
+
+/// A smoltcp interface with one LAN address. Feed it the packets from
+/// [`Client::recv_ipv4`](super::Client::recv_ipv4) and send what
+/// [`poll`](Self::poll) returns, or let [`Client::pump`](super::Client::pump)
+/// do both.
+pub struct VirtualInterface {
+    device: QueueDevice,
+    iface: Interface,
+    sockets: SocketSet<'static>,
+}
+
+impl VirtualInterface {
+    pub fn new(addr: Ipv4Cidr) -> Self {
+        let mut device = QueueDevice::default();
+        let config = Config::new(HardwareAddress::Ip);
+        let mut iface = Interface::new(config, &mut device, Instant::now());
+        iface.update_ip_addrs(|addrs| {
+            // the only address, so there is room for it
+            let _ = addrs.push(IpCidr::Ipv4(addr));
+        });
+        Self {
+            device,
+            iface,
+            sockets: SocketSet::new(vec![]),
+        }
+    }
+    pub fn sockets_mut(&mut self) -> &mut SocketSet<'static> {
+        &mut self.sockets
+    }
+    /// Queues a packet from the LAN for the next `poll`
+    pub fn receive(&mut self, packet: Packet) {
+        self.device.rx.push_back(packet);
+    }
+    /// Runs the stack. Returns the IPv4 packets it sent.
+    pub fn poll(&mut self) -> Vec<Packet> {
+        self.iface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+        self.device.tx.drain(..).collect()
+    }
+}
@@ -0,0 +1,293 @@
+//! A client speaking the forwarder protocol, for tests and tools.
+
+mod iface;
+
+pub use iface::VirtualInterface;
+
+use crate::slp::{encode, ForwarderFrame, FragParser, Fragmenter, Packet, Parser};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, timeout, Duration, Instant};
+
+/// LAN packets waiting for `recv_ipv4`. Later ones are dropped.
+const LAN_QUEUE_SIZE: usize = 256;
+
+type Pings = Arc<Mutex<HashMap<u32, oneshot::Sender<Instant>>>>;
+
+pub struct ClientConfig {
+    pmtu: u16,
+    keepalive_interval: Option<Duration>,
+}
+
+pub struct ClientBuilder(ClientConfig);
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientBuilder {
+    pub fn new() -> ClientBuilder {
+        ClientBuilder(ClientConfig {
+            pmtu: 1300,
+            keepalive_interval: Some(Duration::from_secs(10)),
+        })
+    }
+    /// Largest packet sent in one frame, larger ones are fragmented
+    pub fn pmtu(mut self, v: u16) -> Self {
+        self.0.pmtu = v;
+        self
+    }
+    /// Send keepalives, so the server keeps the client while it is quiet
+    pub fn keepalive_interval(mut self, v: Option<Duration>) -> Self {
+        self.0.keepalive_interval = v;
+        self
+    }
+    pub async fn connect(self, server: SocketAddr) -> io::Result<Client> {
+        Client::connect(server, self.0).await
+    }
+}
+
+/// A connection to a server. Received frames are handled by a background
+/// task, which stops when the client is dropped.
+pub struct Client {
+    socket: Arc<UdpSocket>,
+    pmtu: u16,
+    fragmenter: Mutex<Fragmenter>,
+    pings: Pings,
+    next_ping: AtomicU32,
+    lan: tokio::sync::Mutex<mpsc::Receiver<Packet>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Client {
+    async fn connect(server: SocketAddr, config: ClientConfig) -> io::Result<Client> {
+        let local: SocketAddr = if server.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = Arc::new(UdpSocket::bind(local).await?);
+        socket.connect(server).await?;
+        // announces the client to the server
+        socket.send(&encode::keepalive()).await?;
+
+        let pings = Pings::default();
+        let (lan_send, lan_recv) = mpsc::channel(LAN_QUEUE_SIZE);
+        let mut tasks = vec![tokio::spawn(Self::recv_task(
+            socket.clone(),
+            pings.clone(),
+            lan_send,
+        ))];
+        if let Some(period) = config.keepalive_interval {
+            tasks.push(tokio::spawn(Self::keepalive_task(socket.clone(), period)));
+        }
+
+        Ok(Client {
+            socket,
+            pmtu: config.pmtu,
+            fragmenter: Mutex::new(Fragmenter::new()),
+            pings,
+            next_ping: AtomicU32::new(0),
+            lan: tokio::sync::Mutex::new(lan_recv),
+            tasks,
+        })
+    }
+    async fn recv_task(socket: Arc<UdpSocket>, pings: Pings, lan_send: mpsc::Sender<Packet>) {
+        let peer = match socket.peer_addr() {
+            Ok(peer) => peer,
+            Err(_) => return,
+        };
+        let mut frag_parser = FragParser::new();
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let size = match socket.recv(&mut buf).await {
+                Ok(size) => size,
+                // ICMP errors from a server that is not up yet
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Err(e) => {
+                    log::error!("Client recv task down: {:?}", e);
+                    return;
+                }
+            };
+            let packet = match ForwarderFrame::parse(&buf[..size]) {
+                Ok(ForwarderFrame::Ping(ping)) => {
+                    let id = u32::from_be_bytes(ping.payload());
+                    if let Some(reply) = pings.lock().remove(&id) {
+                        let _ = reply.send(Instant::now());
+                    }
+                    continue;
+                }
+                Ok(ForwarderFrame::Ipv4(ipv4)) => ipv4.data().to_vec(),
+                Ok(ForwarderFrame::Ipv4Frag(frag)) => match frag_parser.process(peer, &frag) {
+                    Some(packet) => packet,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            let _ = lan_send.try_send(packet);
+        }
+    }
+    async fn keepalive_task(socket: Arc<UdpSocket>, period: Duration) {
+        let mut timer = interval(period);
+        loop {
+            timer.tick().await;
+            if let Err(e) = socket.send(&encode::keepalive()).await {
+                log::warn!("Failed to send keepalive: {:?}", e);
+            }
+        }
+    }
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+    pub fn server_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+    pub async fn keepalive(&self) -> io::Result<()> {
+        self.socket.send(&encode::keepalive()).await.map(|_| ())
+    }
+    /// Round trip time of one ping, failing with `TimedOut` when no reply
+    /// comes in `wait`.
+    pub async fn ping(&self, wait: Duration) -> io::Result<Duration> {
+        let id = self.next_ping.fetch_add(1, Ordering::Relaxed);
+        let (reply_send, reply_recv) = oneshot::channel();
+        self.pings.lock().insert(id, reply_send);
+
+        let sent = Instant::now();
+        let result = match self.socket.send(&encode::ping(id.to_be_bytes())).await {
+            Ok(_) => match timeout(wait, reply_recv).await {
+                Ok(Ok(received)) => Ok(received - sent),
+                _ => Err(io::Error::new(io::ErrorKind::TimedOut, "ping timed out")),
+            },
+            Err(e) => Err(e),
+        };
+        self.pings.lock().remove(&id);
+        result
+    }
+    /// Sends a whole IPv4 packet to the LAN, fragmented when it is larger
+    /// than the pmtu.
+    pub async fn send_ipv4(&self, packet: &[u8]) -> io::Result<()> {
+        let frames = self
+            .fragmenter
+            .lock()
+            .fragment(packet, self.pmtu)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "packet can't be fragmented")
+            })?;
+        for frame in frames {
+            self.socket.send(&frame).await?;
+        }
+        Ok(())
+    }
+    /// The next IPv4 packet from the LAN, reassembled. `None` when the
+    /// client stopped receiving.
+    pub async fn recv_ipv4(&self) -> Option<Packet> {
+        self.lan.lock().await.recv().await
+    }
+    /// An IPv4 packet from the LAN if one is waiting
+    pub fn try_recv_ipv4(&self) -> Option<Packet> {
+        self.lan.try_lock().ok()?.try_recv().ok()
+    }
+    /// Moves packets between the server and `iface` until nothing is left
+    /// to send or receive.
+    pub async fn pump(&self, iface: &mut VirtualInterface) -> io::Result<()> {
+        loop {
+            let mut idle = true;
+            while let Some(packet) = self.try_recv_ipv4() {
+                iface.receive(packet);
+                idle = false;
+            }
+            for packet in iface.poll() {
+                self.send_ipv4(&packet).await?;
+                idle = false;
+            }
+            if idle {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClientBuilder, VirtualInterface};
+    use crate::test::make_server;
+    use smoltcp::iface::SocketHandle;
+    use smoltcp::socket::udp;
+    use smoltcp::wire::{IpEndpoint, Ipv4Address, Ipv4Cidr};
+    use tokio::time::{sleep, timeout, Duration};
+
+    fn udp_socket(iface: &mut VirtualInterface, port: u16) -> SocketHandle {
+        let buffer = || udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 4], vec![0; 8192]);
+        let mut socket = udp::Socket::new(buffer(), buffer());
+        socket.bind(port).unwrap();
+        iface.sockets_mut().add(socket)
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let (_udp_server, addr) = make_server().await;
+        let client = ClientBuilder::new().connect(addr).await.unwrap();
+        for _ in 0..3 {
+            let rtt = client.ping(Duration::from_millis(500)).await.unwrap();
+            assert!(rtt < Duration::from_millis(500));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_lan() {
+        let (_udp_server, addr) = make_server().await;
+        let client1 = ClientBuilder::new().pmtu(500).connect(addr).await.unwrap();
+        let client2 = ClientBuilder::new().connect(addr).await.unwrap();
+
+        let mut iface1 = VirtualInterface::new(Ipv4Cidr::new(Ipv4Address::new(10, 13, 37, 1), 16));
+        let mut iface2 = VirtualInterface::new(Ipv4Cidr::new(Ipv4Address::new(10, 13, 37, 2), 16));
+        let socket1 = udp_socket(&mut iface1, 1000);
+        let socket2 = udp_socket(&mut iface2, 2000);
+
+        // larger than both pmtus, so it travels as fragments. Few enough to
+        // fit in the server's peer queue.
+        let payload: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        let dst = IpEndpoint::new(Ipv4Address::new(10, 13, 37, 2).into(), 2000);
+        iface1
+            .sockets_mut()
+            .get_mut::<udp::Socket>(socket1)
+            .send_slice(&payload, dst)
+            .unwrap();
+
+        let received = timeout(Duration::from_secs(2), async {
+            loop {
+                client1.pump(&mut iface1).await.unwrap();
+                client2.pump(&mut iface2).await.unwrap();
+                let socket = iface2.sockets_mut().get_mut::<udp::Socket>(socket2);
+                if let Ok((data, meta)) = socket.recv() {
+                    return (data.to_vec(), meta.endpoint);
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(received.0, payload);
+        assert_eq!(
+            received.1,
+            IpEndpoint::new(Ipv4Address::new(10, 13, 37, 1).into(), 1000)
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! A static dashboard compiled into the binary. It talks to the GraphQL
+//! subscription endpoint at `/`.
+
+use axum::{response::Html, routing::get, Router};
+
+const INDEX: &str = include_str!("index.html");
+
+/// `/dashboard`
+pub fn router() -> Router {
+    Router::new().route("/dashboard", get(|| async { Html(INDEX) }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{router, INDEX};
+    use crate::graphql::{schema, Ctx};
+    use crate::plugin::ldn_mitm::LdnMitmPlugin;
+    use crate::plugin::traffic::TrafficPlugin;
+    use crate::test::make_server;
+    use async_graphql::{Request, Variables};
+    use axum::body::{to_bytes, Body};
+    use axum::http::{header, StatusCode};
+    use futures::StreamExt;
+    use serde_json::json;
+    use tokio::time::{timeout, Duration};
+    use tower::ServiceExt;
+
+    /// The GraphQL documents embedded in the page
+    fn queries() -> Vec<&'static str> {
+        INDEX
+            .split(r#"<script type="text/graphql""#)
+            .skip(1)
+            .map(|s| {
+                let start = s.find('>').unwrap() + 1;
+                let end = s.find("</script>").unwrap();
+                s[start..end].trim()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_serves_page() {
+        let request = axum::http::Request::get("/dashboard")
+            .body(Body::empty())
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, INDEX.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_queries_match_schema() {
+        let (udp_server, _) = make_server().await;
+        udp_server.add_plugin::<LdnMitmPlugin>().await;
+        udp_server.add_plugin::<TrafficPlugin>().await;
+        let schema = schema(&Ctx::new(udp_server, Some("token".to_string())));
+
+        let queries = queries();
+        assert_eq!(queries.len(), 3);
+        for query in queries {
+            let request =
+                Request::new(query).variables(Variables::from_json(json!({ "token": "token" })));
+            // Errors come first, a subscription with nothing to report yet stays silent
+            let mut stream = schema.execute_stream(request);
+            if let Ok(response) = timeout(Duration::from_millis(100), stream.next()).await {
+                let errors = response.unwrap().errors;
+                assert!(errors.is_empty(), "{}: {:?}", query, errors);
+            }
+        }
+    }
+}
@@ -1,18 +1,17 @@
-use crate::plugin::ldn_mitm::{LdnMitmPlugin, RoomInfo};
-use crate::plugin::traffic::{TrafficInfo, TrafficPlugin};
-use crate::slp::{ServerInfo, UDPServer};
-use async_graphql::{Context, EmptyMutation, FieldResult, Object, Schema, Subscription};
+use crate::plugin::ldn_mitm::{
+    GameStat, LdnMitmPlugin, RoomEvent, RoomInfo, SessionPage, SessionTracker,
+};
+use crate::plugin::quota::{QuotaPlugin, QuotaUsage};
+use crate::plugin::traffic::{
+    Resolution, Talker, TalkerKind, TrafficInfo, TrafficPlugin, TrafficSample,
+};
+use crate::slp::{DropInfo, FragStats, PeerEvent, PeerInfo, ServerInfo, UDPServer};
+use async_graphql::{Context, FieldResult, Object, Schema, Subscription};
 use futures::stream::BoxStream;
 use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(Debug)]
-struct StringError(&'static str);
-impl std::fmt::Display for StringError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
-    }
-}
-impl std::error::Error for StringError {}
+const MAX_PAGE_SIZE: i32 = 100;
 
 pub struct Config {
     pub admin_token: Option<String>,
@@ -47,7 +46,7 @@ impl Query {
         if Some(token) == ctx.config.admin_token {
             let r = ctx
                 .udp_server
-                .get_plugin::<TrafficPlugin, _, _>(|traffic| traffic.map(|t| t.clone()))
+                .get_plugin::<TrafficPlugin, _, _>(|traffic| traffic.cloned())
                 .await
                 .ok_or("This plugin is not available")?;
             Ok(r.traffic_info().await)
@@ -55,21 +54,182 @@ impl Query {
             Err("Permission denied".into())
         }
     }
+    /// Traffic of the last `range` seconds, oldest first. Without a
+    /// resolution, the finest one kept that long is used.
+    async fn traffic_history(
+        &self,
+        ctx: &Context<'_>,
+        token: String,
+        #[graphql(default = 600)] range: i32,
+        resolution: Option<Resolution>,
+    ) -> FieldResult<Vec<TrafficSample>> {
+        let ctx = ctx.data::<Ctx>()?;
+        if Some(token) == ctx.config.admin_token {
+            let range = Duration::from_secs(range.max(0) as u64);
+            Ok(ctx
+                .udp_server
+                .get_plugin::<TrafficPlugin, _, _>(|traffic| {
+                    traffic.map(|t| t.traffic_history(range, resolution))
+                })
+                .await
+                .ok_or("This plugin is not available")?)
+        } else {
+            Err("Permission denied".into())
+        }
+    }
+    /// The busiest clients, LAN addresses, or broadcast and unicast, by
+    /// traffic last second
+    async fn top_talkers(
+        &self,
+        ctx: &Context<'_>,
+        token: String,
+        #[graphql(default_with = "TalkerKind::Peer")] kind: TalkerKind,
+        #[graphql(default = 10)] limit: i32,
+    ) -> FieldResult<Vec<Talker>> {
+        let ctx = ctx.data::<Ctx>()?;
+        if Some(token) == ctx.config.admin_token {
+            let (_, limit) = page_args(0, limit);
+            Ok(ctx
+                .udp_server
+                .get_plugin::<TrafficPlugin, _, _>(|traffic| {
+                    traffic.map(|t| t.top_talkers(kind, limit))
+                })
+                .await
+                .ok_or("This plugin is not available")?)
+        } else {
+            Err("Permission denied".into())
+        }
+    }
+    /// Transfer of every client this month, most first
+    async fn quota_usage(&self, ctx: &Context<'_>, token: String) -> FieldResult<Vec<QuotaUsage>> {
+        let ctx = ctx.data::<Ctx>()?;
+        if Some(token) == ctx.config.admin_token {
+            Ok(ctx
+                .udp_server
+                .get_plugin::<QuotaPlugin, _, _>(|quota| quota.map(|q| q.usage()))
+                .await
+                .ok_or("This plugin is not available")?)
+        } else {
+            Err("Permission denied".into())
+        }
+    }
+    /// Packets dropped since start
+    async fn drop_info(&self, ctx: &Context<'_>, token: String) -> FieldResult<DropInfo> {
+        let ctx = ctx.data::<Ctx>()?;
+        if Some(token) == ctx.config.admin_token {
+            Ok(ctx.udp_server.drop_info())
+        } else {
+            Err("Permission denied".into())
+        }
+    }
+    /// Fragment reassembly since start
+    async fn frag_stats(&self, ctx: &Context<'_>, token: String) -> FieldResult<FragStats> {
+        let ctx = ctx.data::<Ctx>()?;
+        if Some(token) == ctx.config.admin_token {
+            Ok(ctx.udp_server.frag_stats())
+        } else {
+            Err("Permission denied".into())
+        }
+    }
+    /// Connected clients
+    async fn peers(&self, ctx: &Context<'_>, token: String) -> FieldResult<Vec<PeerInfo>> {
+        let ctx = ctx.data::<Ctx>()?;
+        if Some(token) == ctx.config.admin_token {
+            Ok(ctx.udp_server.peers())
+        } else {
+            Err("Permission denied".into())
+        }
+    }
     /// Current rooms
     async fn room(&self, ctx: &Context<'_>) -> FieldResult<Vec<RoomInfo>> {
         let ctx = ctx.data::<Ctx>()?;
-        let r = ctx
+        Ok(ctx
+            .udp_server
+            .get_plugin::<LdnMitmPlugin, _, _>(|ldn_mitm| ldn_mitm.map(|i| i.rooms()))
+            .await
+            .ok_or("This plugin is not available")?)
+    }
+    /// Current rooms grouped by game, most players first
+    async fn games(&self, ctx: &Context<'_>) -> FieldResult<Vec<GameStat>> {
+        let ctx = ctx.data::<Ctx>()?;
+        Ok(ctx
             .udp_server
-            .get_plugin::<LdnMitmPlugin, _, _>(|ldn_mitm| ldn_mitm.map(|i| i.room_info()))
+            .get_plugin::<LdnMitmPlugin, _, _>(|ldn_mitm| ldn_mitm.map(|i| i.game_stats()))
             .await
-            .ok_or("This plugin is not available")?;
-        let r = r.lock().await;
-        Ok(r.iter().map(|(_, v)| v.clone()).collect())
+            .ok_or("This plugin is not available")?)
+    }
+    /// Sessions that are still running, oldest first
+    async fn active_sessions(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] offset: i32,
+        #[graphql(default = 20)] limit: i32,
+    ) -> FieldResult<SessionPage> {
+        let (offset, limit) = page_args(offset, limit);
+        Ok(sessions(ctx).await?.lock().active_sessions(offset, limit))
+    }
+    /// Sessions that have ended, most recent first
+    async fn recent_sessions(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] offset: i32,
+        #[graphql(default = 20)] limit: i32,
+    ) -> FieldResult<SessionPage> {
+        let (offset, limit) = page_args(offset, limit);
+        Ok(sessions(ctx).await?.lock().recent_sessions(offset, limit))
+    }
+}
+
+fn page_args(offset: i32, limit: i32) -> (usize, usize) {
+    (
+        offset.max(0) as usize,
+        limit.clamp(0, MAX_PAGE_SIZE) as usize,
+    )
+}
+
+async fn sessions(ctx: &Context<'_>) -> FieldResult<Arc<parking_lot::Mutex<SessionTracker>>> {
+    let ctx = ctx.data::<Ctx>()?;
+    Ok(ctx
+        .udp_server
+        .get_plugin::<LdnMitmPlugin, _, _>(|ldn_mitm| ldn_mitm.map(|i| i.sessions()))
+        .await
+        .ok_or("This plugin is not available")?)
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    /// Clears the quota usage of one client ip, or of every client without
+    /// one. Returns how many clients were reset.
+    async fn reset_quota(
+        &self,
+        ctx: &Context<'_>,
+        token: String,
+        ip: Option<String>,
+    ) -> FieldResult<i32> {
+        let ctx = ctx.data::<Ctx>()?;
+        if Some(token) == ctx.config.admin_token {
+            let ip = ip
+                .map(|ip| ip.parse())
+                .transpose()
+                .map_err(|_| "Invalid ip")?;
+            let quota = ctx
+                .udp_server
+                .get_plugin::<QuotaPlugin, _, _>(|quota| quota.cloned())
+                .await
+                .ok_or("This plugin is not available")?;
+            Ok(quota.reset(ip).await as i32)
+        } else {
+            Err("Permission denied".into())
+        }
     }
 }
 
 type ServerInfoStream = BoxStream<'static, ServerInfo>;
 type TrafficInfoStream = BoxStream<'static, TrafficInfo>;
+type PeerEventStream = BoxStream<'static, PeerEvent>;
+type RoomEventStream = BoxStream<'static, RoomEvent>;
 
 pub struct Subscription;
 
@@ -92,7 +252,7 @@ impl Subscription {
         if Some(token) == context.config.admin_token {
             let r = context
                 .udp_server
-                .get_plugin::<TrafficPlugin, _, _>(|traffic| traffic.map(|t| t.clone()))
+                .get_plugin::<TrafficPlugin, _, _>(|traffic| traffic.cloned())
                 .await
                 .ok_or("This plugin is not available")?;
             Ok(r.traffic_info_stream().await)
@@ -100,12 +260,36 @@ impl Subscription {
             Err("Permission denied".into())
         }
     }
+    /// Clients connecting, going idle, becoming active and disconnecting
+    async fn peer_event(
+        &self,
+        context: &Context<'_>,
+        token: String,
+    ) -> FieldResult<PeerEventStream> {
+        let context = context.data::<Ctx>()?.clone();
+
+        if Some(token) == context.config.admin_token {
+            Ok(context.udp_server.peer_event_stream())
+        } else {
+            Err("Permission denied".into())
+        }
+    }
+    /// Rooms being added, updated and removed
+    async fn room(&self, context: &Context<'_>) -> FieldResult<RoomEventStream> {
+        let context = context.data::<Ctx>()?.clone();
+
+        Ok(context
+            .udp_server
+            .get_plugin::<LdnMitmPlugin, _, _>(|ldn_mitm| ldn_mitm.map(|i| i.room_event_stream()))
+            .await
+            .ok_or("This plugin is not available")?)
+    }
 }
 
-pub type SlpServerSchema = Schema<Query, EmptyMutation, Subscription>;
+pub type SlpServerSchema = Schema<Query, Mutation, Subscription>;
 
 pub fn schema(ctx: &Ctx) -> SlpServerSchema {
-    Schema::build(Query, EmptyMutation, Subscription)
+    Schema::build(Query, Mutation, Subscription)
         .data(ctx.clone())
         .finish()
 }
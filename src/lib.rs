@@ -1,6 +1,10 @@
+pub mod client;
+pub mod dashboard;
 pub mod graphql;
 pub mod panic;
 pub mod plugin;
+#[cfg(feature = "ldn_mitm")]
+pub mod rest;
 pub mod slp;
 pub mod test;
 pub mod util;
@@ -10,14 +10,24 @@ use axum::{
 use clap::Parser;
 use env_logger::Env;
 use graphql::{schema, Ctx, SlpServerSchema};
-use serde::Serialize;
-use slp::{ServerInfo, UDPServerBuilder};
+use slp::{DropPolicy, ServerInfo, UDPServerBuilder};
+#[cfg(feature = "ldn_mitm")]
+use slp_server_rust::rest;
 use slp_server_rust::{
-    graphql, panic,
-    plugin::{self, blocker::Rule},
+    dashboard, graphql, panic,
+    plugin::{
+        self,
+        blocker::Rule,
+        quota::{parse_size, QuotaAction, QuotaConfig, QuotaPlugin},
+    },
     slp,
 };
-use std::{net::SocketAddr, str::FromStr};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
 use tower_http::{
     cors::{Any, CorsLayer},
     trace::TraceLayer,
@@ -46,15 +56,87 @@ struct Opt {
     /// Don't send broadcast to idle clients
     #[arg(short, long)]
     ignore_idle: bool,
+    /// Seconds without any packet before a client is disconnected
+    #[arg(long, default_value_t = 30)]
+    timeout: u64,
+    /// Seconds without LAN packets before a client is idle
+    #[arg(long, default_value_t = 300)]
+    idle_timeout: u64,
+    /// Send a keepalive to every client every N seconds, so NAT mappings stay open
+    #[arg(long)]
+    keepalive_interval: Option<u64>,
+    /// Number of UDP sockets sharing the port with SO_REUSEPORT, each with its own receive loop
+    #[arg(long, default_value_t = 1)]
+    shards: usize,
+    /// Packets queued per client before the drop policy applies
+    #[arg(long, default_value_t = 10)]
+    peer_queue_size: usize,
+    /// Events queued for the event loop
+    #[arg(long, default_value_t = 100)]
+    event_queue_size: usize,
+    /// What to do when a client's queue is full: drop-oldest, drop-newest or disconnect
+    #[arg(long, default_value_t = DropPolicy::DropNewest)]
+    drop_policy: DropPolicy,
+    /// Largest packet the server sends to a client before it fragments, until the client reports its own. Clamped to 531..=2031
+    #[arg(long, default_value_t = 1300)]
+    pmtu: u16,
     /// Block rules
     #[arg(short, long, default_values_t = [Rule::from_str("tcp:5000").unwrap(), Rule::from_str("tcp:21").unwrap()])]
     block_rules: Vec<Rule>,
-}
-
-#[derive(Serialize)]
-struct Info {
-    online: i32,
-    version: String,
+    /// Bytes a client may transfer per UTC day, with an optional K, M, G or T suffix
+    #[arg(long, value_parser = parse_size)]
+    daily_quota: Option<u64>,
+    /// Bytes a client may transfer per UTC month, with an optional K, M, G or T suffix
+    #[arg(long, value_parser = parse_size)]
+    monthly_quota: Option<u64>,
+    /// What to do with a client over its quota: throttle or refuse
+    #[arg(long, default_value_t = QuotaAction::Throttle)]
+    quota_action: QuotaAction,
+    /// Bytes per second a throttled client may still transfer
+    #[arg(long, value_parser = parse_size, default_value = "16K")]
+    quota_throttle_rate: u64,
+    /// File to keep quota usage in across restarts
+    #[arg(long)]
+    quota_file: Option<PathBuf>,
+    /// File to keep traffic history in across restarts
+    #[arg(long)]
+    traffic_history: Option<PathBuf>,
+    /// JSON file of `{ "id", "name", "icon" }` objects, replacing the bundled game list. The bundled list has no icons, so this is the only way to set them
+    #[cfg(feature = "ldn_mitm")]
+    #[arg(long)]
+    game_db: Option<PathBuf>,
+    /// File to keep ended LDN sessions in across restarts
+    #[cfg(feature = "ldn_mitm")]
+    #[arg(long)]
+    session_history: Option<PathBuf>,
+    /// Show UTF-16 text found in the advertise data of games without their own decoder
+    #[cfg(feature = "ldn_mitm")]
+    #[arg(long)]
+    ldn_decode_text: bool,
+    /// Answer LDN scans with cached responses of known rooms, so rooms of hosts with poor connections stay visible
+    #[cfg(feature = "ldn_mitm")]
+    #[arg(long)]
+    ldn_proxy: bool,
+    /// Seconds between LDN scans while rooms are changing
+    #[cfg(feature = "ldn_mitm")]
+    #[arg(long, default_value_t = 5)]
+    ldn_min_scan_interval: u64,
+    /// Seconds between LDN scans once rooms stop changing. Rooms and sessions end after three of these without a reply
+    #[cfg(feature = "ldn_mitm")]
+    #[arg(long, default_value_t = 10)]
+    ldn_max_scan_interval: u64,
+    /// ldn_mitm UDP port
+    #[cfg(feature = "ldn_mitm")]
+    #[arg(long, default_value_t = 11452)]
+    ldn_port: u16,
+    /// Address LDN scans are sent from
+    #[cfg(feature = "ldn_mitm")]
+    #[arg(long, default_value = "10.13.37.0")]
+    ldn_server_addr: Ipv4Addr,
+    /// Address LDN scans are sent to
+    #[cfg(feature = "ldn_mitm")]
+    #[arg(long, default_value = "10.13.255.255")]
+    ldn_broadcast_addr: Ipv4Addr,
 }
 
 async fn server_info(Extension(context): Extension<Ctx>) -> Json<ServerInfo> {
@@ -107,15 +189,19 @@ async fn main() -> std::io::Result<()> {
 
     let opt = Opt::parse();
 
-    if opt.ignore_idle {
-        log::info!("--ignore-idle is not tested, bugs are expected");
-    }
-
     let bind_address = format!("{}:{}", "0.0.0.0", opt.port);
     let socket_addr: &SocketAddr = &bind_address.parse().unwrap();
 
     let udp_server = UDPServerBuilder::new()
         .ignore_idle(opt.ignore_idle)
+        .timeout(Duration::from_secs(opt.timeout))
+        .idle_timeout(Duration::from_secs(opt.idle_timeout))
+        .keepalive_interval(opt.keepalive_interval.map(Duration::from_secs))
+        .shards(opt.shards)
+        .peer_queue_size(opt.peer_queue_size)
+        .event_queue_size(opt.event_queue_size)
+        .drop_policy(opt.drop_policy)
+        .pmtu(opt.pmtu)
         .build(socket_addr)
         .await?;
     plugin::register_plugins(&udp_server).await;
@@ -128,6 +214,87 @@ async fn main() -> std::io::Result<()> {
             })
             .await;
     }
+    {
+        let config = QuotaConfig {
+            daily: opt.daily_quota,
+            monthly: opt.monthly_quota,
+            action: opt.quota_action,
+            throttle_rate: opt.quota_throttle_rate,
+        };
+        udp_server
+            .get_plugin::<QuotaPlugin, _, _>(|q| {
+                q.map(|q| {
+                    q.set_config(config.clone());
+                    opt.quota_file
+                        .as_ref()
+                        .map(|path| q.set_file(path.clone()))
+                        .transpose()
+                })
+            })
+            .await
+            .transpose()?;
+    }
+    if let Some(path) = &opt.traffic_history {
+        udp_server
+            .get_plugin::<plugin::traffic::TrafficPlugin, _, _>(|t| {
+                t.map(|t| t.set_history_file(path.clone()))
+            })
+            .await
+            .transpose()?;
+    }
+    #[cfg(feature = "ldn_mitm")]
+    if let Some(path) = &opt.game_db {
+        let game_db = plugin::ldn_mitm::GameDb::load(path)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        log::info!("Loaded {} games from {}", game_db.len(), path.display());
+        udp_server
+            .get_plugin::<plugin::ldn_mitm::LdnMitmPlugin, _, _>(|p| {
+                p.map(|p| p.set_game_db(game_db.clone()))
+            })
+            .await;
+    }
+    #[cfg(feature = "ldn_mitm")]
+    if let Some(path) = &opt.session_history {
+        udp_server
+            .get_plugin::<plugin::ldn_mitm::LdnMitmPlugin, _, _>(|p| {
+                p.map(|p| p.set_session_file(path.clone()))
+            })
+            .await
+            .transpose()?;
+    }
+    #[cfg(feature = "ldn_mitm")]
+    {
+        let config = plugin::ldn_mitm::LdnConfig {
+            min_scan_interval: Duration::from_secs(opt.ldn_min_scan_interval),
+            max_scan_interval: Duration::from_secs(opt.ldn_max_scan_interval),
+            port: opt.ldn_port,
+            server_addr: opt.ldn_server_addr,
+            broadcast_addr: opt.ldn_broadcast_addr,
+        };
+        udp_server
+            .get_plugin::<plugin::ldn_mitm::LdnMitmPlugin, _, _>(|p| {
+                p.map(|p| p.set_config(config.clone()))
+            })
+            .await
+            .transpose()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    }
+    #[cfg(feature = "ldn_mitm")]
+    if opt.ldn_decode_text {
+        let decoder = std::sync::Arc::new(plugin::ldn_mitm::TextDecoder::default());
+        udp_server
+            .get_plugin::<plugin::ldn_mitm::LdnMitmPlugin, _, _>(|p| {
+                p.map(|p| p.set_fallback_decoder(Some(decoder.clone())))
+            })
+            .await;
+    }
+    #[cfg(feature = "ldn_mitm")]
+    if opt.ldn_proxy {
+        log::info!("Answering LDN scans for known rooms");
+        udp_server
+            .get_plugin::<plugin::ldn_mitm::LdnMitmPlugin, _, _>(|p| p.map(|p| p.set_proxy(true)))
+            .await;
+    }
 
     let context = Ctx::new(udp_server, opt.admin_token);
 
@@ -138,7 +305,11 @@ async fn main() -> std::io::Result<()> {
 
     let app = Router::new()
         .route("/", post_service(graphql_service).get(index_get))
-        .route("/info", get(server_info))
+        .route("/info", get(server_info));
+    #[cfg(feature = "ldn_mitm")]
+    let app = app.merge(rest::router());
+    let app = app
+        .merge(dashboard::router())
         .layer(Extension(executor))
         .layer(Extension(context))
         .layer(TraceLayer::new_for_http())
@@ -148,7 +319,7 @@ async fn main() -> std::io::Result<()> {
                     http::header::CONTENT_TYPE,
                     http::header::HeaderName::from_static("x-apollo-tracing"),
                 ])
-                .allow_methods([http::Method::POST])
+                .allow_methods([http::Method::GET, http::Method::POST])
                 .allow_origin(Any),
         );
 
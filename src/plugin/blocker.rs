@@ -1,5 +1,5 @@
 use crate::slp::plugin::*;
-use crate::slp::{ForwarderFrame, FragParser, Parser};
+use parking_lot::RwLock;
 use smoltcp::wire::{IpProtocol, Ipv4Packet, TcpPacket, UdpPacket};
 use std::str::FromStr;
 
@@ -60,19 +60,17 @@ impl FromStr for Rule {
 }
 
 pub struct BlockerPlugin {
-    frag_parser: FragParser,
-    block_rules: Vec<Rule>,
+    block_rules: RwLock<Vec<Rule>>,
 }
 
 impl BlockerPlugin {
     fn new() -> Self {
         BlockerPlugin {
-            frag_parser: FragParser::new(),
-            block_rules: vec![],
+            block_rules: RwLock::new(vec![]),
         }
     }
-    pub fn set_block_rules(&mut self, block_rules: Vec<Rule>) {
-        self.block_rules = block_rules;
+    pub fn set_block_rules(&self, block_rules: Vec<Rule>) {
+        *self.block_rules.write() = block_rules;
     }
 }
 
@@ -86,7 +84,7 @@ impl Protocol {
 }
 
 impl Rule {
-    fn hit<P: AsRef<[u8]>>(&self, packet: &Ipv4Packet<&P>) -> bool {
+    fn hit<P: AsRef<[u8]> + ?Sized>(&self, packet: &Ipv4Packet<&P>) -> bool {
         if !self.protocol.hit(packet) {
             return false;
         }
@@ -108,37 +106,19 @@ impl Rule {
 
 #[async_trait]
 impl Plugin for BlockerPlugin {
-    async fn in_packet(&mut self, packet: &InPacket) -> Result<(), ()> {
-        let packet = match ForwarderFrame::parse(packet.as_ref()) {
-            Ok(ForwarderFrame::Ipv4(ipv4)) => {
-                let src_ip = ipv4.src_ip();
-                let dst_ip = ipv4.dst_ip();
-                let p = Vec::from(ipv4.data());
-                Some((src_ip, dst_ip, p))
-            }
-            Ok(ForwarderFrame::Ipv4Frag(frag)) => {
-                let src_ip = frag.src_ip();
-                let dst_ip = frag.dst_ip();
-                self.frag_parser.process(frag).map(|p| (src_ip, dst_ip, p))
-            }
-            _ => None,
-        };
-        let (_, _, packet) = match packet {
-            Some(p) => p,
-            None => return Ok(()),
-        };
-        let packet = match Ipv4Packet::new_checked(&packet) {
-            Ok(p) => p,
+    async fn in_packet(&self, frame: &InFrame<'_>) -> Result<(), ()> {
+        let packet = match frame.lan().map(|lan| Ipv4Packet::new_checked(lan.data())) {
+            Some(Ok(p)) => p,
             _ => return Ok(()),
         };
-        for r in &self.block_rules {
+        for r in self.block_rules.read().iter() {
             if r.hit(&packet) {
                 return Err(());
             }
         }
         Ok(())
     }
-    async fn out_packet(&mut self, _packet: &Packet, _addrs: &[SocketAddr]) -> Result<(), ()> {
+    async fn out_packet(&self, _packet: &Packet, _addrs: &mut Vec<SocketAddr>) -> Result<(), ()> {
         Ok(())
     }
 }
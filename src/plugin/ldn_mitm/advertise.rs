@@ -0,0 +1,192 @@
+//! Decoders that turn a game's advertise data into key/value fields.
+//!
+//! The advertise blob is opaque to LDN and every game lays it out
+//! differently, so decoders are registered per content id.
+
+use async_graphql::SimpleObject;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A decoded advertise data field
+#[derive(SimpleObject, Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct AdvertiseField {
+    pub key: String,
+    pub value: String,
+}
+
+impl AdvertiseField {
+    pub fn new(key: impl Into<String>, value: impl ToString) -> Self {
+        Self {
+            key: key.into(),
+            value: value.to_string(),
+        }
+    }
+}
+
+pub trait AdvertiseDecoder: Send + Sync {
+    fn decode(&self, data: &[u8]) -> Vec<AdvertiseField>;
+}
+
+pub type SharedDecoder = Arc<dyn AdvertiseDecoder>;
+
+/// Decoders keyed by content id, with an optional fallback for unknown games.
+#[derive(Clone)]
+pub struct DecoderRegistry {
+    decoders: HashMap<String, SharedDecoder>,
+    fallback: Option<SharedDecoder>,
+}
+
+impl DecoderRegistry {
+    pub fn new() -> Self {
+        Self {
+            decoders: HashMap::new(),
+            fallback: None,
+        }
+    }
+    pub fn register(&mut self, content_id: &str, decoder: SharedDecoder) {
+        self.decoders
+            .insert(content_id.to_ascii_lowercase(), decoder);
+    }
+    pub fn set_fallback(&mut self, decoder: Option<SharedDecoder>) {
+        self.fallback = decoder;
+    }
+    pub fn decode(&self, content_id: &str, data: &[u8]) -> Vec<AdvertiseField> {
+        self.decoders
+            .get(&content_id.to_ascii_lowercase())
+            .or(self.fallback.as_ref())
+            .map(|decoder| decoder.decode(data))
+            .unwrap_or_default()
+    }
+}
+
+impl Default for DecoderRegistry {
+    /// The bundled game decoders, without a fallback
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register(SMASH_ULTIMATE, Arc::new(smash_ultimate()));
+        registry
+    }
+}
+
+pub const SMASH_ULTIMATE: &str = "01006a800016e000";
+
+/// Super Smash Bros. Ultimate. Only the player names are known, from a
+/// captured two player room.
+pub fn smash_ultimate() -> LayoutDecoder {
+    LayoutDecoder::new()
+        .field("host", 0x36, FieldKind::Utf16Le(16))
+        .field("guest", 0x98, FieldKind::Utf16Le(16))
+}
+
+/// Finds UTF-16LE text, which is how most games store room and player names.
+///
+/// Only ASCII characters are recognized: wider ranges match too much random
+/// binary data.
+pub struct TextDecoder {
+    pub min_len: usize,
+}
+
+impl Default for TextDecoder {
+    fn default() -> Self {
+        Self { min_len: 3 }
+    }
+}
+
+impl AdvertiseDecoder for TextDecoder {
+    fn decode(&self, data: &[u8]) -> Vec<AdvertiseField> {
+        let is_text = |i: usize| data[i + 1] == 0 && (0x20..0x7f).contains(&data[i]);
+        let mut fields = vec![];
+        let mut i = 0;
+        while i + 1 < data.len() {
+            let mut end = i;
+            while end + 1 < data.len() && is_text(end) {
+                end += 2;
+            }
+            if (end - i) / 2 >= self.min_len {
+                let text: String = data[i..end].iter().step_by(2).map(|c| *c as char).collect();
+                fields.push(AdvertiseField::new("text", text));
+                i = end;
+            } else {
+                i += 1;
+            }
+        }
+        fields
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    U8,
+    U16Le,
+    U32Le,
+    Bool,
+    /// NUL terminated UTF-16LE string of at most this many characters
+    Utf16Le(usize),
+    /// Hex encoded bytes
+    Hex(usize),
+}
+
+impl FieldKind {
+    fn size(&self) -> usize {
+        match self {
+            FieldKind::U8 | FieldKind::Bool => 1,
+            FieldKind::U16Le => 2,
+            FieldKind::U32Le => 4,
+            FieldKind::Utf16Le(len) => len * 2,
+            FieldKind::Hex(len) => *len,
+        }
+    }
+    fn read(&self, data: &[u8]) -> String {
+        match self {
+            FieldKind::U8 => data[0].to_string(),
+            FieldKind::U16Le => u16::from_le_bytes([data[0], data[1]]).to_string(),
+            FieldKind::U32Le => {
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]]).to_string()
+            }
+            FieldKind::Bool => (data[0] != 0).to_string(),
+            FieldKind::Utf16Le(_) => {
+                let units: Vec<u16> = data
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .take_while(|c| *c != 0)
+                    .collect();
+                String::from_utf16_lossy(&units)
+            }
+            FieldKind::Hex(_) => hex::encode(data),
+        }
+    }
+}
+
+/// Reads fields at fixed offsets. Fields past the end of the data are skipped.
+pub struct LayoutDecoder {
+    fields: Vec<(String, usize, FieldKind)>,
+}
+
+impl LayoutDecoder {
+    pub fn new() -> Self {
+        Self { fields: vec![] }
+    }
+    pub fn field(mut self, key: &str, offset: usize, kind: FieldKind) -> Self {
+        self.fields.push((key.to_string(), offset, kind));
+        self
+    }
+}
+
+impl Default for LayoutDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AdvertiseDecoder for LayoutDecoder {
+    fn decode(&self, data: &[u8]) -> Vec<AdvertiseField> {
+        self.fields
+            .iter()
+            .filter_map(|(key, offset, kind)| {
+                let bytes = data.get(*offset..*offset + kind.size())?;
+                Some(AdvertiseField::new(key.as_str(), kind.read(bytes)))
+            })
+            .collect()
+    }
+}
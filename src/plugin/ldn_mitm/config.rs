@@ -0,0 +1,157 @@
+use super::constants::*;
+use super::util::make_udp;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub struct LdnConfigError(&'static str);
+impl std::fmt::Display for LdnConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid LDN config: {}", self.0)
+    }
+}
+impl std::error::Error for LdnConfigError {}
+
+/// Addressing and scan settings of `LdnMitmPlugin`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LdnConfig {
+    /// Scan interval while rooms are changing
+    pub min_scan_interval: Duration,
+    /// Scan interval once rooms have stopped changing
+    pub max_scan_interval: Duration,
+    /// ldn_mitm UDP port
+    pub port: u16,
+    /// Address the server scans from and hosts reply to
+    pub server_addr: Ipv4Addr,
+    /// Address scans are sent to
+    pub broadcast_addr: Ipv4Addr,
+}
+
+impl Default for LdnConfig {
+    fn default() -> Self {
+        Self {
+            min_scan_interval: Duration::from_secs(5),
+            max_scan_interval: Duration::from_secs(10),
+            port: LDN_MITM_PORT,
+            server_addr: SERVER_ADDR,
+            broadcast_addr: BROADCAST_ADDR,
+        }
+    }
+}
+
+impl LdnConfig {
+    pub fn validate(&self) -> Result<(), LdnConfigError> {
+        if self.min_scan_interval.is_zero() {
+            return Err(LdnConfigError("the minimum scan interval must not be zero"));
+        }
+        if self.min_scan_interval > self.max_scan_interval {
+            return Err(LdnConfigError(
+                "the minimum scan interval must not exceed the maximum",
+            ));
+        }
+        Ok(())
+    }
+    /// A scan from the server, as an IPv4 packet
+    pub fn scan_packet(&self) -> Vec<u8> {
+        make_udp(
+            self.server_addr,
+            self.broadcast_addr,
+            self.port,
+            SCAN_PACKET,
+        )
+    }
+    /// How long a room stays listed without replying: three scans at the
+    /// slowest rate, so one lost reply does not remove it. With the default
+    /// intervals that is 30 seconds.
+    pub fn room_ttl(&self) -> Duration {
+        self.max_scan_interval * 3
+    }
+}
+
+/// Picks the delay before the next scan: the minimum interval while the
+/// room list changes, doubling up to the maximum while it does not.
+pub struct ScanScheduler {
+    interval: Option<Duration>,
+}
+
+impl ScanScheduler {
+    pub fn new() -> Self {
+        Self { interval: None }
+    }
+    pub fn next(&mut self, config: &LdnConfig, changed: bool) -> Duration {
+        let min = config.min_scan_interval;
+        let max = config.max_scan_interval;
+        let interval = match self.interval {
+            Some(interval) if !changed => (interval * 2).clamp(min, max),
+            _ => min,
+        };
+        self.interval = Some(interval);
+        interval
+    }
+}
+
+impl Default for ScanScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LdnConfig, ScanScheduler};
+    use crate::plugin::ldn_mitm::lan_protocol::LanMessage;
+    use crate::plugin::ldn_mitm::util::parse_udp;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    #[test]
+    fn test_scan_scheduler() {
+        let config = LdnConfig::default();
+        let mut scheduler = ScanScheduler::new();
+        let secs = |d: Duration| d.as_secs();
+
+        assert_eq!(secs(scheduler.next(&config, false)), 5);
+        assert_eq!(secs(scheduler.next(&config, false)), 10);
+        assert_eq!(secs(scheduler.next(&config, false)), 10);
+        assert_eq!(secs(scheduler.next(&config, true)), 5);
+        assert_eq!(secs(scheduler.next(&config, false)), 10);
+        assert_eq!(config.room_ttl(), Duration::from_secs(30));
+
+        let fixed = LdnConfig {
+            min_scan_interval: Duration::from_secs(5),
+            max_scan_interval: Duration::from_secs(5),
+            ..Default::default()
+        };
+        assert_eq!(secs(scheduler.next(&fixed, false)), 5);
+        assert_eq!(secs(scheduler.next(&fixed, true)), 5);
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = |min, max| LdnConfig {
+            min_scan_interval: Duration::from_secs(min),
+            max_scan_interval: Duration::from_secs(max),
+            ..Default::default()
+        };
+        assert!(LdnConfig::default().validate().is_ok());
+        assert!(config(5, 5).validate().is_ok());
+        assert!(config(0, 5).validate().is_err());
+        assert!(config(0, 0).validate().is_err());
+        assert!(config(10, 5).validate().is_err());
+    }
+
+    #[test]
+    fn test_scan_packet() {
+        let config = LdnConfig {
+            port: 12000,
+            server_addr: Ipv4Addr::new(10, 14, 0, 0),
+            broadcast_addr: Ipv4Addr::new(10, 14, 255, 255),
+            ..Default::default()
+        };
+        let packet = config.scan_packet();
+        assert_eq!(&packet[12..16], &[10, 14, 0, 0]);
+        assert_eq!(&packet[16..20], &[10, 14, 255, 255]);
+        let payload = parse_udp(&packet, 12000).unwrap();
+        assert!(matches!(LanMessage::parse(payload), Ok(LanMessage::Scan)));
+    }
+}
@@ -0,0 +1,181 @@
+use super::plugin::RoomInfo;
+use async_graphql::SimpleObject;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Game list shipped with the server, names only. `--game-db` replaces it
+/// at startup and is the only way to give games icons.
+const BUNDLED: &str = include_str!("games.json");
+
+/// Game infomation
+#[derive(SimpleObject, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GameInfo {
+    /// title id in hex, as in `RoomInfo.content_id`
+    #[serde(rename = "id")]
+    pub content_id: String,
+    /// game name
+    pub name: String,
+    /// url of the game icon. The bundled list has none, only a `--game-db`
+    /// file can set them.
+    #[serde(default)]
+    pub icon: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum GameDbError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+impl std::fmt::Display for GameDbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GameDbError::Io(e) => write!(f, "Failed to read game db: {}", e),
+            GameDbError::Json(e) => write!(f, "Failed to parse game db: {}", e),
+        }
+    }
+}
+impl std::error::Error for GameDbError {}
+
+/// Maps content ids to games.
+#[derive(Debug, Clone, Default)]
+pub struct GameDb(HashMap<String, GameInfo>);
+
+impl GameDb {
+    /// The bundled list of popular games, without icons
+    pub fn bundled() -> Self {
+        Self::from_json(BUNDLED).expect("bundled games.json is valid")
+    }
+    /// Parses a JSON array of `{ "id", "name", "icon" }` objects.
+    pub fn from_json(json: &str) -> Result<Self, GameDbError> {
+        let games: Vec<GameInfo> = serde_json::from_str(json).map_err(GameDbError::Json)?;
+        Ok(GameDb(
+            games
+                .into_iter()
+                .map(|mut game| {
+                    game.content_id = game.content_id.to_ascii_lowercase();
+                    (game.content_id.clone(), game)
+                })
+                .collect(),
+        ))
+    }
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, GameDbError> {
+        let json = std::fs::read_to_string(path).map_err(GameDbError::Io)?;
+        Self::from_json(&json)
+    }
+    /// Looks up a content id, case insensitive
+    pub fn get(&self, content_id: &str) -> Option<&GameInfo> {
+        self.0.get(&content_id.to_ascii_lowercase())
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Active rooms of one game
+#[derive(SimpleObject, Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct GameStat {
+    /// the content id of the game
+    pub content_id: String,
+    /// the game, if the content id is known
+    pub game: Option<GameInfo>,
+    /// number of rooms
+    pub room_count: i32,
+    /// number of players in those rooms
+    pub player_count: i32,
+}
+
+/// Groups rooms by game, most players first.
+pub fn aggregate(rooms: &[RoomInfo]) -> Vec<GameStat> {
+    let mut stats: HashMap<&str, GameStat> = HashMap::new();
+    for room in rooms {
+        let stat = stats
+            .entry(room.content_id.as_str())
+            .or_insert_with(|| GameStat {
+                content_id: room.content_id.clone(),
+                game: room.game.clone(),
+                room_count: 0,
+                player_count: 0,
+            });
+        stat.room_count += 1;
+        stat.player_count += room.node_count;
+    }
+    let mut stats: Vec<_> = stats.into_values().collect();
+    stats.sort_by(|a, b| {
+        (b.player_count, b.room_count, &a.content_id).cmp(&(
+            a.player_count,
+            a.room_count,
+            &b.content_id,
+        ))
+    });
+    stats
+}
+
+#[cfg(test)]
+mod test {
+    use super::{aggregate, GameDb};
+    use crate::plugin::ldn_mitm::plugin::RoomInfo;
+
+    #[test]
+    fn test_bundled() {
+        let db = GameDb::bundled();
+        assert!(!db.is_empty());
+        // RoomInfo.content_id is lowercase hex
+        let game = db.get("0100152000022000").unwrap();
+        assert_eq!(game.name, "Mario Kart 8 Deluxe");
+        assert_eq!(
+            db.get("01006a800016e000").unwrap().content_id,
+            "01006a800016e000"
+        );
+    }
+
+    #[test]
+    fn test_from_json() {
+        let db = GameDb::from_json(r#"[{"id": "0100AAAA00000000", "name": "Test", "icon": "https://example.com/icon.png"}]"#).unwrap();
+        let game = db.get("0100aaaa00000000").unwrap();
+        assert_eq!(game.icon.as_deref(), Some("https://example.com/icon.png"));
+        assert!(GameDb::from_json("{}").is_err());
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let db = GameDb::bundled();
+        let room = |content_id: &str, node_count| RoomInfo {
+            ip: "10.13.37.1".to_string(),
+            content_id: content_id.to_string(),
+            game: db.get(content_id).cloned(),
+            host_player_name: "host".to_string(),
+            session_id: "".to_string(),
+            node_count_max: 8,
+            node_count,
+            nodes: vec![],
+            advertise_data_len: 0,
+            advertise_data: "".to_string(),
+            advertise: vec![],
+        };
+        let stats = aggregate(&[
+            room("0100152000022000", 2),
+            room("01006a800016e000", 4),
+            room("0100152000022000", 3),
+            room("0100ffff00000000", 1),
+        ]);
+        let stats: Vec<_> = stats
+            .iter()
+            .map(|s| {
+                let name = s.game.as_ref().map(|g| g.name.as_str());
+                (name, s.room_count, s.player_count)
+            })
+            .collect();
+        assert_eq!(
+            stats,
+            vec![
+                (Some("Mario Kart 8 Deluxe"), 2, 5),
+                (Some("Super Smash Bros. Ultimate"), 1, 4),
+                (None, 1, 1),
+            ]
+        );
+    }
+}
@@ -9,6 +9,11 @@ pub enum Error {
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
+pub const MAGIC: u32 = 0x11451400;
+pub const HEADER_SIZE: usize = 12;
+pub const NETWORK_INFO_SIZE: usize = 0x480;
+pub const NODE_INFO_SIZE: usize = 0x40;
+
 mod field {
     pub type Field = ::core::ops::Range<usize>;
     pub type FieldFrom = ::core::ops::RangeFrom<usize>;
@@ -60,7 +65,9 @@ impl<T: AsRef<[u8]>> LdnHeader<T> {
     pub fn compressed(&self) -> u8 {
         self.buffer.as_ref()[field::COMPRESSED]
     }
+    /// payload length, not the length of the header
     #[inline]
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> u16 {
         let mut data = &self.buffer.as_ref()[field::LEN];
         data.get_u16_le()
@@ -76,10 +83,10 @@ impl<T: AsRef<[u8]>> LdnHeader<T> {
     pub fn check(&self) -> Result<()> {
         let data = self.buffer.as_ref();
         let len = data.len();
-        if len < field::REVERSED.end {
+        if len < HEADER_SIZE {
             return Err(Error::Truncated);
         }
-        if self.magic() != 0x11451400 {
+        if self.magic() != MAGIC {
             return Err(Error::Truncated);
         }
         if len < self.len() as usize + field::REVERSED.end {
@@ -95,7 +102,7 @@ pub struct NetworkInfo<T: AsRef<[u8]>> {
 }
 impl<T: AsRef<[u8]>> NetworkInfo<T> {
     pub fn new(buffer: T) -> Result<NetworkInfo<T>> {
-        if buffer.as_ref().len() < 0x480 {
+        if buffer.as_ref().len() < NETWORK_INFO_SIZE {
             Err(Error::Invalid)
         } else {
             Ok(NetworkInfo { buffer })
@@ -140,12 +147,14 @@ impl<T: AsRef<[u8]>> NetworkInfo<T> {
         let mut data = &self.buffer.as_ref()[0x26A..0x26A + 2];
         std::cmp::min(data.get_u16_le(), 384)
     }
-    #[allow(dead_code)]
     pub fn advertise_data(&self) -> &[u8] {
         let len = self.advertise_data_len() as usize;
         let start = 0x26C;
         &self.buffer.as_ref()[start..start + len]
     }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer.as_ref()[..NETWORK_INFO_SIZE]
+    }
 }
 
 pub struct NodeInfo<T: AsRef<[u8]>> {
@@ -153,7 +162,7 @@ pub struct NodeInfo<T: AsRef<[u8]>> {
 }
 impl<T: AsRef<[u8]>> NodeInfo<T> {
     pub fn new(buffer: T) -> Result<NodeInfo<T>> {
-        if buffer.as_ref().len() < 0x40 {
+        if buffer.as_ref().len() < NODE_INFO_SIZE {
             Err(Error::Invalid)
         } else {
             Ok(NodeInfo { buffer })
@@ -174,6 +183,32 @@ impl<T: AsRef<[u8]>> NodeInfo<T> {
         let data = data.iter().copied().take_while(|i| *i != 0).collect();
         String::from_utf8(data).unwrap_or("".to_string())
     }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer.as_ref()[..NODE_INFO_SIZE]
+    }
+}
+
+impl NodeInfo<Vec<u8>> {
+    pub fn build(ip: Ipv4Addr, node_id: u8, is_connected: bool, player_name: &str) -> Self {
+        let mut buffer = vec![0u8; NODE_INFO_SIZE];
+        buffer[0..4].copy_from_slice(&u32::from(ip).to_le_bytes());
+        buffer[0xA] = node_id;
+        buffer[0xB] = is_connected as u8;
+        // 32 bytes, the last one stays NUL
+        let name = player_name.as_bytes();
+        let len = name.len().min(0x1F);
+        buffer[0xC..0xC + len].copy_from_slice(&name[..len]);
+        NodeInfo { buffer }
+    }
+}
+
+impl<T: AsRef<[u8]>> std::fmt::Debug for NodeInfo<T> {
+    fn fmt(&self, w: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        w.debug_struct("NodeInfo")
+            .field("ip", &self.ip())
+            .field("node_id", &self.node_id())
+            .finish()
+    }
 }
 
 impl<T: AsRef<[u8]>> std::fmt::Debug for NetworkInfo<T> {
@@ -218,6 +253,119 @@ impl LdnPacket {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LanPacketType {
+    Scan = 0,
+    ScanResponse = 1,
+    Connect = 2,
+    SyncNetwork = 3,
+}
+
+impl LanPacketType {
+    pub fn from_u8(typ: u8) -> Option<Self> {
+        match typ {
+            0 => Some(LanPacketType::Scan),
+            1 => Some(LanPacketType::ScanResponse),
+            2 => Some(LanPacketType::Connect),
+            3 => Some(LanPacketType::SyncNetwork),
+            _ => None,
+        }
+    }
+}
+
+/// A ldn_mitm LAN message.
+///
+/// There is no disconnect message: a station leaves by closing its TCP
+/// connection to the access point, which then sends `SyncNetwork` to the
+/// remaining stations.
+#[derive(Debug)]
+pub enum LanMessage {
+    /// Broadcast by a station looking for networks
+    Scan,
+    /// An access point describing its network
+    ScanResponse(NetworkInfo<Vec<u8>>),
+    /// A station joining, sent over TCP to the access point
+    Connect(NodeInfo<Vec<u8>>),
+    /// The access point's current network, sent over TCP to every station
+    SyncNetwork(NetworkInfo<Vec<u8>>),
+}
+
+impl LanMessage {
+    pub fn parse(data: &[u8]) -> Result<LanMessage> {
+        let packet = LdnPacket::new(data)?;
+        let typ = LanPacketType::from_u8(packet.typ()).ok_or(Error::Invalid)?;
+        let payload = packet.payload;
+        Ok(match typ {
+            LanPacketType::Scan => LanMessage::Scan,
+            LanPacketType::ScanResponse => LanMessage::ScanResponse(NetworkInfo::new(payload)?),
+            LanPacketType::Connect => LanMessage::Connect(NodeInfo::new(payload)?),
+            LanPacketType::SyncNetwork => LanMessage::SyncNetwork(NetworkInfo::new(payload)?),
+        })
+    }
+    pub fn typ(&self) -> LanPacketType {
+        match self {
+            LanMessage::Scan => LanPacketType::Scan,
+            LanMessage::ScanResponse(_) => LanPacketType::ScanResponse,
+            LanMessage::Connect(_) => LanPacketType::Connect,
+            LanMessage::SyncNetwork(_) => LanPacketType::SyncNetwork,
+        }
+    }
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            LanMessage::Scan => &[],
+            LanMessage::ScanResponse(info) | LanMessage::SyncNetwork(info) => info.as_bytes(),
+            LanMessage::Connect(node) => node.as_bytes(),
+        }
+    }
+    /// Header and payload. With `compress` the payload is compressed when
+    /// that makes it smaller, as ldn_mitm does.
+    pub fn emit(&self, compress: bool) -> Vec<u8> {
+        let payload = self.payload();
+        let compressed = if compress {
+            self::compress(payload).filter(|c| c.len() < payload.len())
+        } else {
+            None
+        };
+        let body = compressed.as_deref().unwrap_or(payload);
+
+        let mut out = Vec::with_capacity(HEADER_SIZE + body.len());
+        out.extend_from_slice(&MAGIC.to_le_bytes());
+        out.push(self.typ() as u8);
+        out.push(compressed.is_some() as u8);
+        out.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(body);
+        out
+    }
+}
+
+/// Inverse of [`decompress`]: every zero byte is followed by the number of
+/// zeros after it, up to 255. Returns `None` if the input does not fit in a
+/// packet.
+pub fn compress(input: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        let c = input[i];
+        i += 1;
+        out.push(c);
+        if c == 0 {
+            let mut count = 0u8;
+            while i < input.len() && input[i] == 0 && count < 0xFF {
+                count += 1;
+                i += 1;
+            }
+            out.push(count);
+        }
+    }
+    if out.len() > u16::MAX as usize {
+        None
+    } else {
+        Some(out)
+    }
+}
+
 pub fn decompress(input: &[u8], output: &mut [u8]) -> Option<usize> {
     let mut lead_zero = false;
     let mut pos = 0;
@@ -250,12 +398,21 @@ pub fn decompress(input: &[u8], output: &mut [u8]) -> Option<usize> {
 
 #[cfg(test)]
 mod test {
+    use super::super::advertise::{
+        AdvertiseDecoder, AdvertiseField, DecoderRegistry, FieldKind, LayoutDecoder, TextDecoder,
+    };
     use super::*;
     use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    /// Scan response of a Super Smash Bros. Ultimate room with two players
+    fn network_info_fixture() -> Vec<u8> {
+        hex::decode("00e01600806a000100000100000000000000000000000000000000000000000002007a3a0d0a2031323334353637383132333435363738313233343536373831323334353637380006000302000000000000000000000000000000000000000001000000000008027a3a0d0a02007a3a0d0a0001436f6c796f000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000024070d0a020024070d0a01017368616e610000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000200000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000003000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000040000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000500000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000006000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000070000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000070012e4cb28f00000000041800009fe9344a000000000000000004000000000000000000000044010000000000000000000000000000000043006f006c0079006f000000610000000000000012005f010000000096000305000200000a000058000000000000000002ffffffffffffffffffffffffffffffffffffffffffffffffffffff04ffff00000000000000003143010b633bc6c76fb1f87300680061006e00610000001200000000561b0012001d0100000000000000000000000000000000c59d1c8100000000000000000000ffff00561b001200000000000000000000000000000000000000000000000000ffff000000001100000000002800120000000000000000000001700328001200ffff10a61c0c0300000000000000100000001000000030000000400000000100ffffa0a61c0c0100000000006505120000000000000001000000d00128001200ffff70f2ab42120000000000ab421200000068f2ab4212000000c80128001200ffff000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000").unwrap()
+    }
 
     #[test]
     fn test_network_info() {
-        let data = hex::decode("00e01600806a000100000100000000000000000000000000000000000000000002007a3a0d0a2031323334353637383132333435363738313233343536373831323334353637380006000302000000000000000000000000000000000000000001000000000008027a3a0d0a02007a3a0d0a0001436f6c796f000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000024070d0a020024070d0a01017368616e610000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000200000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000003000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000040000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000500000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000006000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000070000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000070012e4cb28f00000000041800009fe9344a000000000000000004000000000000000000000044010000000000000000000000000000000043006f006c0079006f000000610000000000000012005f010000000096000305000200000a000058000000000000000002ffffffffffffffffffffffffffffffffffffffffffffffffffffff04ffff00000000000000003143010b633bc6c76fb1f87300680061006e00610000001200000000561b0012001d0100000000000000000000000000000000c59d1c8100000000000000000000ffff00561b001200000000000000000000000000000000000000000000000000ffff000000001100000000002800120000000000000000000001700328001200ffff10a61c0c0300000000000000100000001000000030000000400000000100ffffa0a61c0c0100000000006505120000000000000001000000d00128001200ffff70f2ab42120000000000ab421200000068f2ab4212000000c80128001200ffff000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000").unwrap();
+        let data = network_info_fixture();
         let advertise_data = Vec::from(&data[0x26C..0x26C + 368]);
         let info = NetworkInfo::new(data).unwrap();
         assert_eq!(info.content_id(), 0x01006a800016e000);
@@ -288,6 +445,74 @@ mod test {
         assert_eq!(info.advertise_data(), &advertise_data[..]);
     }
 
+    #[test]
+    fn test_compress() {
+        let mut output = [0u8; 1024];
+        for input in [
+            vec![],
+            vec![1, 2, 3],
+            vec![0],
+            vec![1, 0, 0, 0, 0, 3, 0],
+            vec![0u8; 256],
+            vec![0u8; 257],
+            vec![0u8; 600],
+        ] {
+            let compressed = compress(&input).unwrap();
+            let size = decompress(&compressed, &mut output).unwrap();
+            assert_eq!(&output[..size], &input[..]);
+        }
+        assert_eq!(
+            compress(&[1, 0, 0, 0, 0, 3, 0]).unwrap(),
+            vec![1, 0, 3, 3, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let scan = LanMessage::Scan.emit(true);
+        assert_eq!(&scan[..], crate::plugin::ldn_mitm::constants::SCAN_PACKET);
+        assert!(matches!(LanMessage::parse(&scan), Ok(LanMessage::Scan)));
+
+        let mut data = network_info_fixture();
+        data.truncate(NETWORK_INFO_SIZE);
+        for compress in [false, true] {
+            let info = NetworkInfo::new(data.clone()).unwrap();
+            let bytes = LanMessage::ScanResponse(info).emit(compress);
+            assert_eq!(bytes[5], compress as u8);
+            assert_eq!(bytes.len() < HEADER_SIZE + NETWORK_INFO_SIZE, compress);
+            match LanMessage::parse(&bytes).unwrap() {
+                LanMessage::ScanResponse(info) => {
+                    assert_eq!(info.as_bytes(), &data[..]);
+                    assert_eq!(info.host_player_name(), "Colyo");
+                }
+                m => panic!("unexpected {:?}", m),
+            }
+
+            let info = NetworkInfo::new(data.clone()).unwrap();
+            let bytes = LanMessage::SyncNetwork(info).emit(compress);
+            match LanMessage::parse(&bytes).unwrap() {
+                LanMessage::SyncNetwork(info) => assert_eq!(info.as_bytes(), &data[..]),
+                m => panic!("unexpected {:?}", m),
+            }
+
+            let node = NodeInfo::build(Ipv4Addr::new(10, 13, 7, 36), 1, true, "shana");
+            let bytes = LanMessage::Connect(node).emit(compress);
+            match LanMessage::parse(&bytes).unwrap() {
+                LanMessage::Connect(node) => {
+                    assert_eq!(node.ip(), Ipv4Addr::new(10, 13, 7, 36));
+                    assert_eq!(node.node_id(), 1);
+                    assert!(node.is_connected());
+                    assert_eq!(node.player_name(), "shana");
+                }
+                m => panic!("unexpected {:?}", m),
+            }
+        }
+
+        let mut bad = LanMessage::Scan.emit(false);
+        bad[4] = 4;
+        assert!(LanMessage::parse(&bad).is_err());
+    }
+
     #[test]
     fn test_decompress() {
         let mut output = [0u8; 512];
@@ -320,4 +545,69 @@ mod test {
 
         assert_eq!(decompress(&[0, 255, 0, 254, 1, 1], &mut output), None);
     }
+
+    #[test]
+    fn test_smash_ultimate_decoder() {
+        let data = network_info_fixture();
+        let info = NetworkInfo::new(&data[..]).unwrap();
+        let registry = DecoderRegistry::default();
+        assert_eq!(
+            registry.decode("01006A800016E000", info.advertise_data()),
+            vec![
+                AdvertiseField::new("host", "Colyo"),
+                AdvertiseField::new("guest", "shana"),
+            ]
+        );
+        // no fallback by default
+        assert_eq!(
+            registry.decode("0100152000022000", info.advertise_data()),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_text_decoder() {
+        let data = network_info_fixture();
+        let info = NetworkInfo::new(&data[..]).unwrap();
+        let mut registry = DecoderRegistry::new();
+        registry.set_fallback(Some(Arc::new(TextDecoder::default())));
+        assert_eq!(
+            registry.decode("0100152000022000", info.advertise_data()),
+            vec![
+                AdvertiseField::new("text", "Colyo"),
+                AdvertiseField::new("text", "shana"),
+            ]
+        );
+        registry.set_fallback(Some(Arc::new(TextDecoder { min_len: 6 })));
+        assert_eq!(
+            registry.decode("0100152000022000", info.advertise_data()),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_layout_decoder() {
+        let mut data = vec![1, 0x18, 0x04, 0x78, 0x56, 0x34, 0x12];
+        data.extend(b"h\0i\0\0\0");
+        data.extend([0xff, 0xfe]);
+        let decoder = LayoutDecoder::new()
+            .field("flag", 0, FieldKind::Bool)
+            .field("u8", 1, FieldKind::U8)
+            .field("u16", 1, FieldKind::U16Le)
+            .field("u32", 3, FieldKind::U32Le)
+            .field("name", 7, FieldKind::Utf16Le(3))
+            .field("tail", 13, FieldKind::Hex(2))
+            .field("past_end", 14, FieldKind::U32Le);
+        assert_eq!(
+            decoder.decode(&data),
+            vec![
+                AdvertiseField::new("flag", "true"),
+                AdvertiseField::new("u8", "24"),
+                AdvertiseField::new("u16", "1048"),
+                AdvertiseField::new("u32", "305419896"),
+                AdvertiseField::new("name", "hi"),
+                AdvertiseField::new("tail", "fffe"),
+            ]
+        );
+    }
 }
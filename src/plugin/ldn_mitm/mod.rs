@@ -1,13 +1,24 @@
-mod lan_protocol;
+mod advertise;
+mod config;
+mod game_db;
+pub mod lan_protocol;
 mod plugin;
+mod room;
+mod session;
 mod util;
 
+pub use advertise::{
+    AdvertiseDecoder, AdvertiseField, DecoderRegistry, FieldKind, LayoutDecoder, SharedDecoder,
+    TextDecoder,
+};
+pub use config::{LdnConfig, LdnConfigError};
+pub use game_db::{GameDb, GameDbError, GameInfo, GameStat};
 pub use plugin::*;
+pub use room::{RoomEvent, RoomEventKind};
+pub use session::{SessionInfo, SessionPage, SessionTracker};
+pub use util::{make_udp, parse_udp};
 
 mod constants {
-    use once_cell::sync::OnceCell;
-
-    use super::util::*;
     use std::net::Ipv4Addr;
 
     pub const LDN_MITM_PORT: u16 = 11452;
@@ -21,12 +32,4 @@ mod constants {
         0, 0, // decompressed len
         0, 0, // reversed
     ];
-    pub fn slp_scan_packet() -> &'static [u8] {
-        static SLP_SCAN_PACKET: OnceCell<Vec<u8>> = OnceCell::new();
-        SLP_SCAN_PACKET.get_or_init(|| {
-            let mut bytes = make_udp(SCAN_PACKET);
-            bytes.insert(0, 1);
-            bytes
-        })
-    }
 }
@@ -1,161 +1,532 @@
-use super::constants::*;
-use super::lan_protocol::{LdnPacket, NetworkInfo};
-use crate::slp::frame::{ForwarderFrame, FragParser, Parser};
+use super::advertise::{AdvertiseField, DecoderRegistry, SharedDecoder};
+use super::config::{LdnConfig, LdnConfigError, ScanScheduler};
+use super::game_db::{aggregate, GameDb, GameInfo, GameStat};
+use super::lan_protocol::{LanMessage, NetworkInfo};
+use super::room::{diff, RoomEvent, RoomList};
+use super::session::SessionTracker;
+use super::util::{make_udp, parse_udp};
 use crate::slp::plugin::*;
+use crate::slp::stream::spawn_stream;
+use crate::util::{save_blocking, FilterSameExt};
 use async_graphql::SimpleObject;
 use futures::prelude::*;
+use futures::stream::BoxStream;
+use parking_lot::{Mutex, RwLock};
 use serde::Serialize;
-use smoltcp::wire::{IpProtocol, Ipv4Packet, UdpPacket};
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::{interval, Duration};
-use tokio_stream::wrappers::IntervalStream;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::time::sleep;
+use tokio_stream::wrappers::BroadcastStream;
+
+type RoomEventStream = BoxStream<'static, RoomEvent>;
+
+/// A peer's scans are answered at most once per this interval
+const SCAN_ANSWER_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Node infomation
 #[derive(SimpleObject, Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct NodeInfo {
-    ip: String,
-    node_id: i32,
-    is_connected: bool,
-    player_name: String,
+    pub(super) ip: String,
+    pub(super) node_id: i32,
+    pub(super) is_connected: bool,
+    pub(super) player_name: String,
 }
 /// Room infomation
 #[derive(SimpleObject, Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct RoomInfo {
     /// the ip of room
-    ip: String,
+    pub(super) ip: String,
     /// the content id of the game
-    content_id: String,
+    pub(super) content_id: String,
+    /// the game, if the content id is known
+    pub(super) game: Option<GameInfo>,
     /// host player name
-    host_player_name: String,
+    pub(super) host_player_name: String,
     /// session id
-    session_id: String,
+    pub(super) session_id: String,
     /// node count max
-    node_count_max: i32,
+    pub(super) node_count_max: i32,
     /// node count
-    node_count: i32,
+    pub(super) node_count: i32,
     /// nodes
-    nodes: Vec<NodeInfo>,
+    pub(super) nodes: Vec<NodeInfo>,
     /// advertise data length
-    advertise_data_len: i32,
+    pub(super) advertise_data_len: i32,
     /// advertise data in hex
-    advertise_data: String,
+    pub(super) advertise_data: String,
+    /// advertise data decoded into fields, empty if no decoder knows the game
+    pub(super) advertise: Vec<AdvertiseField>,
 }
 
 pub struct LdnMitmPlugin {
-    frag_parser: FragParser,
-    room_info: Arc<Mutex<HashMap<Ipv4Addr, RoomInfo>>>,
+    config: Arc<RwLock<LdnConfig>>,
+    config_changed: Arc<Notify>,
+    rooms: Arc<Mutex<RoomList>>,
+    room_sender: broadcast::Sender<Vec<RoomInfo>>,
+    sessions: Arc<Mutex<SessionTracker>>,
+    session_file: Arc<Mutex<Option<PathBuf>>>,
+    game_db: RwLock<Arc<GameDb>>,
+    decoders: RwLock<Arc<DecoderRegistry>>,
+    proxy: AtomicBool,
+    scan_answers: Mutex<HashMap<SocketAddr, Instant>>,
+    peer_manager: PeerManager,
+    event_send: mpsc::Sender<Event>,
 }
 
 impl LdnMitmPlugin {
-    fn new(peer_manager: PeerManager) -> LdnMitmPlugin {
-        let room_info = Arc::new(Mutex::new(HashMap::new()));
-        let ri = room_info.clone();
-        tokio::spawn(
-            IntervalStream::new(interval(Duration::from_secs(5))).for_each(move |_| {
-                let pm = peer_manager.clone();
-                let ri = ri.clone();
-                async move {
-                    ri.lock().await.clear();
-                    let _ = pm.send_broadcast(slp_scan_packet()).await;
-                }
-            }),
-        );
+    fn new(peer_manager: PeerManager, event_send: mpsc::Sender<Event>) -> LdnMitmPlugin {
+        let config = Arc::new(RwLock::new(LdnConfig::default()));
+        let ttl = config.read().room_ttl();
+        let rooms = Arc::new(Mutex::new(RoomList::with_ttl(ttl)));
+        let sessions = Arc::new(Mutex::new(SessionTracker::with_timeout(ttl)));
+        let session_file = Arc::new(Mutex::new(None));
+        let config_changed = Arc::new(Notify::new());
+        tokio::spawn(scan_loop(
+            peer_manager.clone(),
+            config.clone(),
+            config_changed.clone(),
+            rooms.clone(),
+            sessions.clone(),
+            session_file.clone(),
+        ));
+        let room_sender = spawn_stream(&rooms, |rooms| async move {
+            let now = Instant::now();
+            let mut rooms = rooms.lock();
+            rooms.expire(now);
+            rooms.snapshot(now)
+        });
         LdnMitmPlugin {
-            frag_parser: FragParser::new(),
-            room_info,
+            config,
+            config_changed,
+            rooms,
+            room_sender,
+            sessions,
+            session_file,
+            game_db: RwLock::new(Arc::new(GameDb::bundled())),
+            decoders: RwLock::new(Arc::new(DecoderRegistry::default())),
+            proxy: AtomicBool::new(false),
+            scan_answers: Mutex::new(HashMap::new()),
+            peer_manager,
+            event_send,
+        }
+    }
+}
+
+/// Scans at the pace of a [`ScanScheduler`], skipping scans while no one is
+/// connected. A new config takes effect immediately. Session history is
+/// saved whenever it changes.
+async fn scan_loop(
+    peer_manager: PeerManager,
+    config: Arc<RwLock<LdnConfig>>,
+    config_changed: Arc<Notify>,
+    rooms: Arc<Mutex<RoomList>>,
+    sessions: Arc<Mutex<SessionTracker>>,
+    session_file: Arc<Mutex<Option<PathBuf>>>,
+) {
+    let mut scheduler = ScanScheduler::new();
+    let mut last_rooms = vec![];
+    loop {
+        let config = config.read().clone();
+        let ended = sessions.lock().expire(SystemTime::now());
+        let path = session_file.lock().clone();
+        if let Some(path) = path.filter(|_| ended) {
+            let sessions = sessions.clone();
+            save_blocking(path, "session history", move |path| {
+                sessions.lock().save(path)
+            })
+            .await;
+        }
+        let current = rooms.lock().snapshot(Instant::now());
+        let changed = current != last_rooms;
+        last_rooms = current;
+
+        if peer_manager.server_info().await.online > 0 {
+            let _ = peer_manager.broadcast_ipv4(&config.scan_packet()).await;
+        }
+        let delay = scheduler.next(&config, changed);
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = config_changed.notified() => {}
         }
     }
 }
 
 impl LdnMitmPlugin {
-    pub fn room_info(&self) -> Arc<Mutex<HashMap<Ipv4Addr, RoomInfo>>> {
-        self.room_info.clone()
+    pub fn config(&self) -> LdnConfig {
+        self.config.read().clone()
+    }
+    pub fn set_config(&self, config: LdnConfig) -> Result<(), LdnConfigError> {
+        config.validate()?;
+        let ttl = config.room_ttl();
+        *self.config.write() = config;
+        self.rooms.lock().set_ttl(ttl);
+        self.sessions.lock().set_timeout(ttl);
+        self.config_changed.notify_one();
+        Ok(())
+    }
+    /// Rooms whose host answered a recent scan
+    pub fn rooms(&self) -> Vec<RoomInfo> {
+        self.rooms.lock().snapshot(Instant::now())
+    }
+    /// Rooms of one game, matched case-insensitively
+    pub fn rooms_of(&self, content_id: &str) -> Vec<RoomInfo> {
+        let mut rooms = self.rooms();
+        rooms.retain(|room| room.content_id.eq_ignore_ascii_case(content_id));
+        rooms
+    }
+    pub fn room(&self, ip: &str) -> Option<RoomInfo> {
+        self.rooms().into_iter().find(|room| room.ip == ip)
+    }
+    /// Every room is first sent as `Add`, followed by changes as they happen
+    pub fn room_event_stream(&self) -> RoomEventStream {
+        let stream = BroadcastStream::new(self.room_sender.subscribe())
+            .take_while(|rooms| future::ready(rooms.is_ok()))
+            .map(|rooms| rooms.unwrap());
+
+        stream::once(future::ready(self.rooms()))
+            .chain(stream)
+            .filter_same()
+            .scan(vec![], |prev, next| {
+                let events = diff(prev, &next);
+                *prev = next;
+                future::ready(Some(stream::iter(events)))
+            })
+            .flatten()
+            .boxed()
+    }
+    /// Active rooms grouped by game
+    pub fn game_stats(&self) -> Vec<GameStat> {
+        aggregate(&self.rooms())
+    }
+    /// Replaces the bundled game list
+    pub fn set_game_db(&self, game_db: GameDb) {
+        *self.game_db.write() = Arc::new(game_db);
+    }
+    /// Decodes the advertise data of one game
+    pub fn register_decoder(&self, content_id: &str, decoder: SharedDecoder) {
+        let mut decoders = self.decoders.write();
+        let mut registry = DecoderRegistry::clone(&decoders);
+        registry.register(content_id, decoder);
+        *decoders = Arc::new(registry);
+    }
+    /// Decodes the advertise data of games without their own decoder. There
+    /// is none by default.
+    pub fn set_fallback_decoder(&self, decoder: Option<SharedDecoder>) {
+        let mut decoders = self.decoders.write();
+        let mut registry = DecoderRegistry::clone(&decoders);
+        registry.set_fallback(decoder);
+        *decoders = Arc::new(registry);
+    }
+    pub fn sessions(&self) -> Arc<Mutex<SessionTracker>> {
+        self.sessions.clone()
+    }
+    /// Loads ended sessions from `path` if it exists, and saves to it
+    /// whenever a session ends
+    pub fn set_session_file(&self, path: PathBuf) -> std::io::Result<()> {
+        match self.sessions.lock().load(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        *self.session_file.lock() = Some(path);
+        Ok(())
+    }
+    /// Answers scans from clients with the cached responses of known rooms,
+    /// so rooms of hosts that miss a scan stay visible
+    pub fn set_proxy(&self, enabled: bool) {
+        self.proxy.store(enabled, Ordering::Relaxed);
+    }
+    fn on_scan_response(&self, src_ip: Ipv4Addr, info: NetworkInfo<Vec<u8>>) {
+        let nodes: Vec<_> = info
+            .nodes()
+            .into_iter()
+            .map(|node| NodeInfo {
+                ip: node.ip().to_string(),
+                node_id: node.node_id() as i32,
+                is_connected: node.is_connected(),
+                player_name: node.player_name(),
+            })
+            .collect();
+        let content_id = hex::encode(info.content_id_bytes());
+        let game = self.game_db.read().get(&content_id).cloned();
+        let advertise = self
+            .decoders
+            .read()
+            .decode(&content_id, info.advertise_data());
+        let room = RoomInfo {
+            ip: src_ip.to_string(),
+            content_id,
+            game,
+            host_player_name: info.host_player_name(),
+            session_id: hex::encode(info.session_id()),
+            node_count_max: info.node_count_max() as i32,
+            node_count: info.node_count() as i32,
+            nodes,
+            advertise_data_len: info.advertise_data_len() as i32,
+            advertise_data: hex::encode(info.advertise_data()),
+            advertise,
+        };
+        self.sessions.lock().update(&room, SystemTime::now());
+        self.rooms
+            .lock()
+            .insert(src_ip, room, Vec::from(info.as_bytes()), Instant::now());
+    }
+    /// Only connected peers are answered, at most once per
+    /// [`SCAN_ANSWER_INTERVAL`], so spoofed scans can't turn the server into
+    /// an amplifier. Answers go through the `out_packet` hooks like any other
+    /// packet.
+    fn answer_scan(&self, src_ip: Ipv4Addr, from: SocketAddr, port: u16) {
+        if !self.peer_manager.contains(&from) {
+            return;
+        }
+        let now = Instant::now();
+        {
+            let mut answers = self.scan_answers.lock();
+            answers.retain(|_, at| now.duration_since(*at) < SCAN_ANSWER_INTERVAL);
+            if answers.contains_key(&from) {
+                return;
+            }
+            answers.insert(from, now);
+        }
+        let network_infos = self.rooms.lock().network_infos(Instant::now());
+        for (host_ip, info) in network_infos {
+            if host_ip == src_ip {
+                continue;
+            }
+            let info = match NetworkInfo::new(info) {
+                Ok(info) => info,
+                _ => continue,
+            };
+            let payload = LanMessage::ScanResponse(info).emit(true);
+            let packet = make_udp(host_ip, src_ip, port, &payload);
+            // a full event queue drops the answer like any other packet
+            let _ = self
+                .event_send
+                .try_send(Event::SendIpv4(packet, vec![from]));
+        }
     }
 }
 
 #[async_trait]
 impl Plugin for LdnMitmPlugin {
-    async fn in_packet(&mut self, packet: &InPacket) -> Result<(), ()> {
-        let packet = match ForwarderFrame::parse(packet.as_ref()) {
-            Ok(ForwarderFrame::Ipv4(ipv4)) => {
-                let src_ip = ipv4.src_ip();
-                let dst_ip = ipv4.dst_ip();
-                let p = Vec::from(ipv4.data());
-                Some((src_ip, dst_ip, p))
-            }
-            Ok(ForwarderFrame::Ipv4Frag(frag)) => {
-                let src_ip = frag.src_ip();
-                let dst_ip = frag.dst_ip();
-                self.frag_parser.process(frag).map(|p| (src_ip, dst_ip, p))
-            }
-            _ => None,
+    async fn in_packet(&self, frame: &InFrame<'_>) -> Result<(), ()> {
+        let from = *frame.addr();
+        let lan = match frame.lan() {
+            Some(lan) => lan,
+            None => return Ok(()),
+        };
+        let (src_ip, dst_ip) = (lan.src_ip(), lan.dst_ip());
+        let config = self.config();
+        let message = match parse_udp(lan.data(), config.port).map(LanMessage::parse) {
+            Some(Ok(message)) => message,
+            _ => return Ok(()),
         };
-        match packet {
-            Some((src_ip, dst_ip, packet)) if dst_ip == SERVER_ADDR => {
-                let mut packet = match Ipv4Packet::new_checked(packet) {
-                    Ok(p) => p,
-                    _ => return Ok(()),
-                };
-                if packet.next_header() != IpProtocol::Udp {
-                    return Ok(());
-                }
-                let payload = packet.payload_mut();
-                let mut packet = match UdpPacket::new_checked(payload) {
-                    Ok(p) => p,
-                    _ => return Ok(()),
-                };
-                let payload = packet.payload_mut();
-
-                let packet = match LdnPacket::new(payload) {
-                    Ok(p) => p,
-                    _ => return Ok(()),
-                };
-                if packet.typ() != 1 {
-                    return Ok(());
-                }
-                let info = match NetworkInfo::new(packet.payload()) {
-                    Ok(info) => info,
-                    _ => return Ok(()),
-                };
-                let nodes: Vec<_> = info
-                    .nodes()
-                    .into_iter()
-                    .map(|node| NodeInfo {
-                        ip: node.ip().to_string(),
-                        node_id: node.node_id() as i32,
-                        is_connected: node.is_connected(),
-                        player_name: node.player_name(),
-                    })
-                    .collect();
-                self.room_info.lock().await.insert(
-                    src_ip,
-                    RoomInfo {
-                        ip: src_ip.to_string(),
-                        content_id: hex::encode(info.content_id_bytes()),
-                        host_player_name: info.host_player_name(),
-                        session_id: hex::encode(info.session_id()),
-                        node_count_max: info.node_count_max() as i32,
-                        node_count: info.node_count() as i32,
-                        nodes,
-                        advertise_data_len: info.advertise_data_len() as i32,
-                        advertise_data: hex::encode(info.advertise_data()),
-                    },
-                );
+        match message {
+            LanMessage::ScanResponse(info) if dst_ip == config.server_addr => {
+                self.on_scan_response(src_ip, info)
+            }
+            LanMessage::Scan
+                if dst_ip == config.broadcast_addr && self.proxy.load(Ordering::Relaxed) =>
+            {
+                self.answer_scan(src_ip, from, config.port)
             }
             _ => (),
         };
         Ok(())
     }
-    async fn out_packet(&mut self, _packet: &Packet, _addrs: &[SocketAddr]) -> Result<(), ()> {
+    async fn out_packet(&self, _packet: &Packet, _addrs: &mut Vec<SocketAddr>) -> Result<(), ()> {
         Ok(())
     }
 }
 
 impl PluginType for LdnMitmPlugin {
     fn create(context: Context) -> BoxPlugin {
-        Box::new(LdnMitmPlugin::new(context.peer_manager.clone()))
+        Box::new(LdnMitmPlugin::new(
+            context.peer_manager.clone(),
+            context.event_send.clone(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::constants::*;
+    use super::super::lan_protocol::{LanMessage, NetworkInfo, NETWORK_INFO_SIZE};
+    use super::super::util::{make_udp, parse_udp};
+    use super::{LdnConfig, LdnMitmPlugin};
+    use crate::plugin::traffic::{TalkerKind, TrafficPlugin};
+    use crate::slp::frame::{encode, ForwarderFrame, Parser};
+    use crate::slp::UDPServer;
+    use crate::test::{client_connect, drain, make_server, recv_nothing, recv_packet};
+    use std::net::Ipv4Addr;
+    use tokio::net::UdpSocket;
+    use tokio::time::{sleep, Duration};
+
+    const HOST_IP: Ipv4Addr = Ipv4Addr::new(10, 13, 37, 1);
+    const GUEST_IP: Ipv4Addr = Ipv4Addr::new(10, 13, 37, 2);
+    const KEEPALIVE: [u8; 1] = [0];
+
+    fn frame(src: Ipv4Addr, dst: Ipv4Addr, message: LanMessage) -> Vec<u8> {
+        encode::ipv4(&make_udp(src, dst, LDN_MITM_PORT, &message.emit(true)))
+    }
+
+    fn network_info() -> NetworkInfo<Vec<u8>> {
+        let mut buffer = vec![0u8; NETWORK_INFO_SIZE];
+        buffer[0..8].copy_from_slice(&0x0100152000022000u64.to_le_bytes());
+        buffer[0x74..0x78].copy_from_slice(b"host");
+        NetworkInfo::new(buffer).unwrap()
+    }
+
+    /// A host that has advertised its room and a connected guest that has
+    /// not scanned yet
+    async fn host_and_guest(proxy: bool) -> (UDPServer, UdpSocket, UdpSocket) {
+        let (udp_server, addr) = make_server().await;
+        udp_server.add_plugin::<LdnMitmPlugin>().await;
+        udp_server.add_plugin::<TrafficPlugin>().await;
+        udp_server
+            .get_plugin::<LdnMitmPlugin, _, _>(|p| p.map(|p| p.set_proxy(proxy)))
+            .await;
+
+        let host = client_connect(addr).await;
+        let mut guest = client_connect(addr).await;
+        guest.send(&KEEPALIVE).await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+        host.send(&frame(
+            HOST_IP,
+            SERVER_ADDR,
+            LanMessage::ScanResponse(network_info()),
+        ))
+        .await
+        .unwrap();
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            udp_server
+                .get_plugin::<LdnMitmPlugin, _, _>(|p| p.map(|p| p.rooms().len()))
+                .await,
+            Some(1)
+        );
+        drain(&mut guest).await;
+
+        (udp_server, host, guest)
+    }
+
+    #[tokio::test]
+    async fn test_proxy_answers_scan() {
+        let (_, _host, mut guest) = host_and_guest(true).await;
+        guest
+            .send(&frame(GUEST_IP, BROADCAST_ADDR, LanMessage::Scan))
+            .await
+            .unwrap();
+
+        let reply = recv_packet(&mut guest).await;
+        let ipv4 = match ForwarderFrame::parse(&reply) {
+            Ok(ForwarderFrame::Ipv4(ipv4)) => ipv4,
+            _ => panic!("not an ipv4 frame"),
+        };
+        assert_eq!(ipv4.src_ip(), HOST_IP);
+        assert_eq!(ipv4.dst_ip(), GUEST_IP);
+        match LanMessage::parse(parse_udp(ipv4.data(), LDN_MITM_PORT).unwrap()).unwrap() {
+            LanMessage::ScanResponse(info) => {
+                assert_eq!(info.as_bytes(), network_info().as_bytes());
+                assert_eq!(info.host_player_name(), "host");
+            }
+            m => panic!("unexpected {:?}", m),
+        }
+    }
+
+    /// Packets the server sent on behalf of the host
+    async fn host_upload_packets(udp_server: &UDPServer) -> u64 {
+        udp_server
+            .get_plugin::<TrafficPlugin, _, _>(|t| {
+                t.map(|t| t.top_talkers(TalkerKind::VirtualIp, 10))
+            })
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|t| t.key == HOST_IP.to_string())
+            .map(|t| t.total.upload_packet.0)
+            .unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn test_proxy_answers_go_through_plugins() {
+        let (udp_server, _host, mut guest) = host_and_guest(true).await;
+        let before = host_upload_packets(&udp_server).await;
+        guest
+            .send(&frame(GUEST_IP, BROADCAST_ADDR, LanMessage::Scan))
+            .await
+            .unwrap();
+        recv_packet(&mut guest).await;
+        assert_eq!(host_upload_packets(&udp_server).await, before + 1);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_rate_limit() {
+        let (_, _host, mut guest) = host_and_guest(true).await;
+        let scan = frame(GUEST_IP, BROADCAST_ADDR, LanMessage::Scan);
+        guest.send(&scan).await.unwrap();
+        recv_packet(&mut guest).await;
+        guest.send(&scan).await.unwrap();
+        recv_nothing(&mut guest).await;
+    }
+
+    #[tokio::test]
+    async fn test_proxy_ignores_unknown_peer() {
+        let (udp_server, _host, _guest) = host_and_guest(true).await;
+        let mut stranger = client_connect(*udp_server.local_addr()).await;
+        stranger
+            .send(&frame(GUEST_IP, BROADCAST_ADDR, LanMessage::Scan))
+            .await
+            .unwrap();
+        recv_nothing(&mut stranger).await;
+    }
+
+    #[tokio::test]
+    async fn test_proxy_is_opt_in() {
+        let (_, _host, mut guest) = host_and_guest(false).await;
+        guest
+            .send(&frame(GUEST_IP, BROADCAST_ADDR, LanMessage::Scan))
+            .await
+            .unwrap();
+        recv_nothing(&mut guest).await;
+    }
+
+    #[tokio::test]
+    async fn test_proxy_skips_own_room() {
+        let (_, mut host, _guest) = host_and_guest(true).await;
+        drain(&mut host).await;
+        host.send(&frame(HOST_IP, BROADCAST_ADDR, LanMessage::Scan))
+            .await
+            .unwrap();
+        recv_nothing(&mut host).await;
+    }
+
+    #[tokio::test]
+    async fn test_scan_starts_with_first_peer() {
+        let (udp_server, addr) = make_server().await;
+        udp_server.add_plugin::<LdnMitmPlugin>().await;
+        let config = LdnConfig {
+            min_scan_interval: Duration::from_millis(100),
+            max_scan_interval: Duration::from_millis(200),
+            ..Default::default()
+        };
+        udp_server
+            .get_plugin::<LdnMitmPlugin, _, _>(|p| p.map(|p| p.set_config(config.clone())))
+            .await
+            .unwrap()
+            .unwrap();
+        sleep(Duration::from_millis(300)).await;
+
+        let mut client = client_connect(addr).await;
+        client.send(&[0]).await.unwrap();
+        assert_eq!(
+            recv_packet(&mut client).await,
+            encode::ipv4(&config.scan_packet())
+        );
     }
 }
@@ -0,0 +1,175 @@
+use super::plugin::RoomInfo;
+use async_graphql::{Enum, SimpleObject};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+#[derive(Enum, Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+pub enum RoomEventKind {
+    Add,
+    Update,
+    Remove,
+}
+
+/// A change in the room list
+#[derive(SimpleObject, Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct RoomEvent {
+    pub kind: RoomEventKind,
+    /// the room after the change, or the last known state when removed
+    pub room: RoomInfo,
+}
+
+struct Room {
+    info: RoomInfo,
+    /// the NetworkInfo the room was parsed from
+    network_info: Vec<u8>,
+    seen: Instant,
+}
+
+/// Rooms by host ip, each kept until its TTL runs out.
+pub struct RoomList {
+    rooms: BTreeMap<Ipv4Addr, Room>,
+    ttl: Duration,
+}
+
+impl RoomList {
+    /// Rooms are dropped when their host has not answered a scan for `ttl`
+    pub fn with_ttl(ttl: Duration) -> Self {
+        Self {
+            rooms: BTreeMap::new(),
+            ttl,
+        }
+    }
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+    /// Adds a room or refreshes it.
+    pub fn insert(&mut self, ip: Ipv4Addr, info: RoomInfo, network_info: Vec<u8>, now: Instant) {
+        self.rooms.insert(
+            ip,
+            Room {
+                info,
+                network_info,
+                seen: now,
+            },
+        );
+    }
+    pub fn expire(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.rooms
+            .retain(|_, room| now.saturating_duration_since(room.seen) <= ttl);
+    }
+    fn live(&self, now: Instant) -> impl Iterator<Item = (&Ipv4Addr, &Room)> {
+        self.rooms
+            .iter()
+            .filter(move |(_, room)| now.saturating_duration_since(room.seen) <= self.ttl)
+    }
+    /// Live rooms, ordered by ip
+    pub fn snapshot(&self, now: Instant) -> Vec<RoomInfo> {
+        self.live(now).map(|(_, room)| room.info.clone()).collect()
+    }
+    /// The raw NetworkInfo of live rooms by host ip
+    pub fn network_infos(&self, now: Instant) -> Vec<(Ipv4Addr, Vec<u8>)> {
+        self.live(now)
+            .map(|(ip, room)| (*ip, room.network_info.clone()))
+            .collect()
+    }
+}
+
+/// Events that turn the `prev` room list into `next`.
+pub fn diff(prev: &[RoomInfo], next: &[RoomInfo]) -> Vec<RoomEvent> {
+    let mut events = vec![];
+    for room in next {
+        match prev.iter().find(|r| r.ip == room.ip) {
+            None => events.push(RoomEvent {
+                kind: RoomEventKind::Add,
+                room: room.clone(),
+            }),
+            Some(old) if old != room => events.push(RoomEvent {
+                kind: RoomEventKind::Update,
+                room: room.clone(),
+            }),
+            _ => {}
+        }
+    }
+    for room in prev {
+        if !next.iter().any(|r| r.ip == room.ip) {
+            events.push(RoomEvent {
+                kind: RoomEventKind::Remove,
+                room: room.clone(),
+            });
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff, RoomEventKind, RoomList};
+    use crate::plugin::ldn_mitm::plugin::RoomInfo;
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    fn room(i: u8, node_count: i32) -> RoomInfo {
+        RoomInfo {
+            ip: Ipv4Addr::new(10, 13, 37, i).to_string(),
+            content_id: "0100152000022000".to_string(),
+            game: None,
+            host_player_name: "host".to_string(),
+            session_id: i.to_string(),
+            node_count_max: 8,
+            node_count,
+            nodes: vec![],
+            advertise_data_len: 0,
+            advertise_data: "".to_string(),
+            advertise: vec![],
+        }
+    }
+
+    #[test]
+    fn test_room_ttl() {
+        let start = Instant::now();
+        let mut rooms = RoomList::with_ttl(Duration::from_secs(15));
+        rooms.insert(Ipv4Addr::new(10, 13, 37, 1), room(1, 1), vec![1], start);
+        rooms.insert(
+            Ipv4Addr::new(10, 13, 37, 2),
+            room(2, 1),
+            vec![2],
+            start + Duration::from_secs(10),
+        );
+
+        // a scan tick does not empty the list
+        let now = start + Duration::from_secs(5);
+        rooms.expire(now);
+        assert_eq!(rooms.snapshot(now).len(), 2);
+
+        let now = start + Duration::from_secs(16);
+        assert_eq!(rooms.snapshot(now), vec![room(2, 1)]);
+        assert_eq!(
+            rooms.network_infos(now),
+            vec![(Ipv4Addr::new(10, 13, 37, 2), vec![2])]
+        );
+        rooms.expire(now);
+        assert_eq!(rooms.snapshot(now), vec![room(2, 1)]);
+    }
+
+    #[test]
+    fn test_diff() {
+        let prev = vec![room(1, 1), room(2, 1)];
+        let next = vec![room(2, 2), room(3, 1)];
+        let events: Vec<_> = diff(&prev, &next)
+            .into_iter()
+            .map(|e| (e.kind, e.room.session_id))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (RoomEventKind::Update, "2".to_string()),
+                (RoomEventKind::Add, "3".to_string()),
+                (RoomEventKind::Remove, "1".to_string()),
+            ]
+        );
+        assert!(diff(&next, &next).is_empty());
+    }
+}
@@ -0,0 +1,320 @@
+use super::plugin::RoomInfo;
+use crate::util::{write_atomic, BigInt};
+use async_graphql::SimpleObject;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long ended sessions are kept
+pub const HISTORY_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Sessions tracked at once. Beyond this, the one seen least recently is
+/// ended early, so made-up session ids can't grow the map without bound.
+pub const MAX_ACTIVE: usize = 1000;
+
+/// A game session, tracked from creation to end
+#[derive(SimpleObject, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    /// session id
+    session_id: String,
+    /// the content id of the game
+    content_id: String,
+    /// the ip of the host
+    ip: String,
+    /// host player name
+    host_player_name: String,
+    /// unix time in seconds when the session was first seen
+    created_at: BigInt,
+    /// unix time in seconds when the session was last seen
+    last_seen: BigInt,
+    /// unix time in seconds when the session ended, null while it is active
+    ended_at: Option<BigInt>,
+    /// node count max
+    node_count_max: i32,
+    /// highest node count seen
+    peak_node_count: i32,
+    /// every player that joined the session
+    players: Vec<String>,
+}
+
+/// A page of sessions
+#[derive(SimpleObject, Clone, Debug, Serialize)]
+pub struct SessionPage {
+    /// number of sessions across all pages
+    total: i32,
+    /// sessions in this page
+    sessions: Vec<SessionInfo>,
+}
+
+fn unix_time(time: SystemTime) -> BigInt {
+    BigInt(
+        time.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    )
+}
+
+fn page<'a>(
+    iter: impl Iterator<Item = &'a SessionInfo>,
+    total: usize,
+    offset: usize,
+    limit: usize,
+) -> SessionPage {
+    SessionPage {
+        total: total as i32,
+        sessions: iter.skip(offset).take(limit).cloned().collect(),
+    }
+}
+
+struct ActiveSession {
+    info: SessionInfo,
+    last_seen: SystemTime,
+}
+
+/// Turns periodic scan results into session history.
+pub struct SessionTracker {
+    active: HashMap<String, ActiveSession>,
+    ended: VecDeque<SessionInfo>,
+    timeout: Duration,
+}
+
+impl SessionTracker {
+    /// A session ends when its room has not answered a scan for `timeout`
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            active: HashMap::new(),
+            ended: VecDeque::new(),
+            timeout,
+        }
+    }
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+    /// Records a scan response.
+    pub fn update(&mut self, room: &RoomInfo, now: SystemTime) {
+        if !self.active.contains_key(&room.session_id) && self.active.len() >= MAX_ACTIVE {
+            let oldest = self
+                .active
+                .iter()
+                .min_by_key(|(_, s)| s.last_seen)
+                .map(|(id, _)| id.clone());
+            if let Some(id) = oldest {
+                self.end(&id);
+            }
+        }
+        let session = self
+            .active
+            .entry(room.session_id.clone())
+            .or_insert_with(|| ActiveSession {
+                info: SessionInfo {
+                    session_id: room.session_id.clone(),
+                    content_id: room.content_id.clone(),
+                    ip: room.ip.clone(),
+                    host_player_name: room.host_player_name.clone(),
+                    created_at: unix_time(now),
+                    last_seen: unix_time(now),
+                    ended_at: None,
+                    node_count_max: room.node_count_max,
+                    peak_node_count: 0,
+                    players: vec![],
+                },
+                last_seen: now,
+            });
+        session.last_seen = now;
+        let info = &mut session.info;
+        info.last_seen = unix_time(now);
+        info.peak_node_count = info.peak_node_count.max(room.node_count);
+        for node in room.nodes.iter().filter(|n| n.is_connected) {
+            if !info.players.contains(&node.player_name) {
+                info.players.push(node.player_name.clone());
+            }
+        }
+    }
+    fn end(&mut self, id: &str) {
+        if let Some(session) = self.active.remove(id) {
+            let mut info = session.info;
+            info.ended_at = Some(info.last_seen);
+            self.ended.push_front(info);
+        }
+    }
+    /// Ends the sessions that have not been seen within the timeout, and
+    /// forgets those that ended before the retention. Returns true if the
+    /// history changed.
+    pub fn expire(&mut self, now: SystemTime) -> bool {
+        let timeout = self.timeout;
+        let expired: Vec<String> = self
+            .active
+            .iter()
+            .filter(|(_, s)| {
+                now.duration_since(s.last_seen)
+                    .map(|d| d > timeout)
+                    .unwrap_or(false)
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        let mut changed = !expired.is_empty();
+        for id in expired {
+            self.end(&id);
+        }
+        let cutoff = unix_time(now - HISTORY_RETENTION);
+        while self
+            .ended
+            .back()
+            .map(|s| s.ended_at < Some(cutoff))
+            .unwrap_or(false)
+        {
+            self.ended.pop_back();
+            changed = true;
+        }
+        changed
+    }
+    /// Active sessions, oldest first
+    pub fn active_sessions(&self, offset: usize, limit: usize) -> SessionPage {
+        let mut sessions: Vec<_> = self.active.values().map(|s| &s.info).collect();
+        sessions.sort_by(|a, b| (a.created_at, &a.session_id).cmp(&(b.created_at, &b.session_id)));
+        page(sessions.into_iter(), self.active.len(), offset, limit)
+    }
+    /// Ended sessions, most recently ended first
+    pub fn recent_sessions(&self, offset: usize, limit: usize) -> SessionPage {
+        page(self.ended.iter(), self.ended.len(), offset, limit)
+    }
+    /// Loads ended sessions saved by [`save`](Self::save)
+    pub fn load(&mut self, path: &Path) -> std::io::Result<()> {
+        let json = std::fs::read(path)?;
+        self.ended = serde_json::from_slice(&json)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(())
+    }
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        write_atomic(path, &serde_json::to_vec(&self.ended)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SessionTracker, HISTORY_RETENTION, MAX_ACTIVE};
+    use crate::plugin::ldn_mitm::plugin::{NodeInfo, RoomInfo};
+    use crate::util::BigInt;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn room(session_id: &str, players: &[&str]) -> RoomInfo {
+        let nodes: Vec<_> = players
+            .iter()
+            .enumerate()
+            .map(|(i, name)| NodeInfo {
+                ip: format!("169.254.1.{}", i + 1),
+                node_id: i as i32,
+                is_connected: true,
+                player_name: name.to_string(),
+            })
+            .collect();
+        RoomInfo {
+            ip: "10.13.37.1".to_string(),
+            content_id: "0100152000022000".to_string(),
+            game: None,
+            host_player_name: players[0].to_string(),
+            session_id: session_id.to_string(),
+            node_count_max: 8,
+            node_count: nodes.len() as i32,
+            nodes,
+            advertise_data_len: 0,
+            advertise_data: "".to_string(),
+            advertise: vec![],
+        }
+    }
+
+    #[test]
+    fn test_session_lifecycle() {
+        let t = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut tracker = SessionTracker::with_timeout(Duration::from_secs(15));
+
+        tracker.update(&room("a", &["host"]), t(100));
+        tracker.update(&room("a", &["host", "p1", "p2"]), t(105));
+        tracker.update(&room("a", &["host", "p2"]), t(110));
+        tracker.update(&room("b", &["other"]), t(110));
+        tracker.expire(t(120));
+
+        let active = tracker.active_sessions(0, 10);
+        assert_eq!(active.total, 2);
+        assert_eq!(active.sessions[0].session_id, "a");
+        assert_eq!(active.sessions[0].peak_node_count, 3);
+        assert_eq!(active.sessions[0].players, vec!["host", "p1", "p2"]);
+        assert_eq!(active.sessions[0].ended_at, None);
+
+        tracker.update(&room("b", &["other"]), t(125));
+        tracker.expire(t(126));
+        let recent = tracker.recent_sessions(0, 10);
+        assert_eq!(recent.total, 1);
+        assert_eq!(recent.sessions[0].session_id, "a");
+        assert_eq!(recent.sessions[0].created_at, BigInt(100));
+        assert_eq!(recent.sessions[0].ended_at, Some(BigInt(110)));
+        assert_eq!(tracker.active_sessions(0, 10).total, 1);
+    }
+
+    #[test]
+    fn test_pagination() {
+        let mut tracker = SessionTracker::with_timeout(Duration::from_secs(1));
+        for i in 0..5u64 {
+            let id = i.to_string();
+            tracker.update(&room(&id, &["host"]), UNIX_EPOCH + Duration::from_secs(i));
+            tracker.expire(UNIX_EPOCH + Duration::from_secs(i + 2));
+        }
+        let recent = tracker.recent_sessions(1, 2);
+        assert_eq!(recent.total, 5);
+        let ids: Vec<_> = recent
+            .sessions
+            .iter()
+            .map(|s| s.session_id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["3", "2"]);
+        assert_eq!(tracker.recent_sessions(10, 2).sessions, vec![]);
+    }
+
+    #[test]
+    fn test_retention() {
+        let t = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut tracker = SessionTracker::with_timeout(Duration::from_secs(15));
+        tracker.update(&room("old", &["host"]), t(100));
+        assert!(tracker.expire(t(200)));
+        tracker.update(&room("new", &["host"]), t(1000));
+        assert!(tracker.expire(t(1100)));
+        assert!(!tracker.expire(t(1101)));
+
+        let retention = HISTORY_RETENTION.as_secs();
+        assert!(tracker.expire(t(100 + retention + 1)));
+        let recent = tracker.recent_sessions(0, 10);
+        assert_eq!(recent.total, 1);
+        assert_eq!(recent.sessions[0].session_id, "new");
+    }
+
+    #[test]
+    fn test_active_bound() {
+        let t = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut tracker = SessionTracker::with_timeout(Duration::from_secs(15));
+        for i in 0..MAX_ACTIVE as u64 + 1 {
+            tracker.update(&room(&i.to_string(), &["host"]), t(i));
+        }
+        assert_eq!(tracker.active_sessions(0, 1).total as usize, MAX_ACTIVE);
+        let recent = tracker.recent_sessions(0, 10);
+        assert_eq!(recent.total, 1);
+        assert_eq!(recent.sessions[0].session_id, "0");
+    }
+
+    #[test]
+    fn test_save_load() {
+        let path = std::env::temp_dir().join(format!("slp-sessions-{}.json", std::process::id()));
+        let t = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut tracker = SessionTracker::with_timeout(Duration::from_secs(15));
+        tracker.update(&room("a", &["host", "p1"]), t(100));
+        tracker.expire(t(200));
+        tracker.save(&path).unwrap();
+
+        let mut loaded = SessionTracker::with_timeout(Duration::from_secs(15));
+        loaded.load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            loaded.recent_sessions(0, 10).sessions,
+            tracker.recent_sessions(0, 10).sessions
+        );
+    }
+}
@@ -1,16 +1,17 @@
-use super::constants::*;
 use smoltcp::phy::ChecksumCapabilities;
 use smoltcp::wire::{IpProtocol, Ipv4Packet, Ipv4Repr, UdpPacket, UdpRepr};
+use std::net::Ipv4Addr;
 
-pub fn make_udp(payload: &[u8]) -> Vec<u8> {
+/// An IPv4 packet carrying an ldn_mitm UDP datagram from `src` to `dst`
+pub fn make_udp(src: Ipv4Addr, dst: Ipv4Addr, port: u16, payload: &[u8]) -> Vec<u8> {
     let checksum = ChecksumCapabilities::default();
     let udp_repr = UdpRepr {
-        src_port: LDN_MITM_PORT,
-        dst_port: LDN_MITM_PORT,
+        src_port: port,
+        dst_port: port,
     };
     let ip_repr = Ipv4Repr {
-        src_addr: SERVER_ADDR.into(),
-        dst_addr: BROADCAST_ADDR.into(),
+        src_addr: src.into(),
+        dst_addr: dst.into(),
         next_header: IpProtocol::Udp,
         payload_len: udp_repr.header_len() + payload.len(),
         hop_limit: 64,
@@ -19,8 +20,8 @@ pub fn make_udp(payload: &[u8]) -> Vec<u8> {
     let mut udp_packet = UdpPacket::new_unchecked(&mut bytes[ip_repr.buffer_len()..]);
     udp_repr.emit(
         &mut udp_packet,
-        &SERVER_ADDR.into(),
-        &BROADCAST_ADDR.into(),
+        &src.into(),
+        &dst.into(),
         payload.len(),
         |e: &mut [u8]| e.copy_from_slice(payload),
         &checksum,
@@ -31,13 +32,27 @@ pub fn make_udp(payload: &[u8]) -> Vec<u8> {
     bytes
 }
 
+/// The payload of an ldn_mitm UDP datagram inside an IPv4 packet
+pub fn parse_udp(packet: &[u8], port: u16) -> Option<&[u8]> {
+    let packet = Ipv4Packet::new_checked(packet).ok()?;
+    if packet.next_header() != IpProtocol::Udp {
+        return None;
+    }
+    let udp = UdpPacket::new_checked(packet.payload()).ok()?;
+    if udp.dst_port() != port {
+        return None;
+    }
+    Some(udp.payload())
+}
+
 #[cfg(test)]
 mod test {
-    use super::make_udp;
+    use super::super::constants::*;
+    use super::{make_udp, parse_udp};
 
     #[test]
     fn test_make_udp() {
-        let bytes = make_udp(&[0, 1, 2, 3]);
+        let bytes = make_udp(SERVER_ADDR, BROADCAST_ADDR, LDN_MITM_PORT, &[0, 1, 2, 3]);
         assert_eq!(
             &bytes,
             &[
@@ -49,4 +64,12 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn test_parse_udp() {
+        let bytes = make_udp(SERVER_ADDR, BROADCAST_ADDR, LDN_MITM_PORT, &[0, 1, 2, 3]);
+        assert_eq!(parse_udp(&bytes, LDN_MITM_PORT), Some(&[0u8, 1, 2, 3][..]));
+        assert_eq!(parse_udp(&bytes, LDN_MITM_PORT + 1), None);
+        assert_eq!(parse_udp(&bytes[..20], LDN_MITM_PORT), None);
+    }
 }
@@ -1,14 +1,18 @@
 pub mod blocker;
 #[cfg(feature = "ldn_mitm")]
 pub mod ldn_mitm;
+pub mod quota;
 pub mod traffic;
 
 use crate::slp::UDPServer;
 
+/// Filters come first, so a packet they drop is never seen by the plugins
+/// that account for traffic.
 pub async fn register_plugins(server: &UDPServer) {
+    server.add_plugin::<blocker::BlockerPlugin>().await;
+    server.add_plugin::<quota::QuotaPlugin>().await;
     if cfg!(feature = "ldn_mitm") {
         server.add_plugin::<ldn_mitm::LdnMitmPlugin>().await;
     }
     server.add_plugin::<traffic::TrafficPlugin>().await;
-    server.add_plugin::<blocker::BlockerPlugin>().await;
 }
@@ -0,0 +1,704 @@
+//! Daily and monthly transfer caps per client IP.
+
+use crate::slp::plugin::*;
+use crate::util::{save_blocking, write_atomic, BigInt, BoundedMap};
+use async_graphql::SimpleObject;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::time::interval;
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Clients whose usage is kept for the month
+const MAX_CLIENTS: usize = 4096;
+/// New clients seen while the table is full, waiting to replace one that
+/// used less
+const OVERFLOW: usize = 256;
+
+#[derive(Debug)]
+pub struct QuotaParseError(String);
+impl std::fmt::Display for QuotaParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to parse quota: {}", self.0)
+    }
+}
+impl std::error::Error for QuotaParseError {}
+
+/// What happens to a client over its cap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaAction {
+    /// Drop its packets beyond the throttle rate
+    Throttle,
+    /// Drop all its packets, so it times out
+    Refuse,
+}
+
+impl std::fmt::Display for QuotaAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaAction::Throttle => write!(f, "throttle"),
+            QuotaAction::Refuse => write!(f, "refuse"),
+        }
+    }
+}
+
+impl FromStr for QuotaAction {
+    type Err = QuotaParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "throttle" => Ok(QuotaAction::Throttle),
+            "refuse" => Ok(QuotaAction::Refuse),
+            _ => Err(QuotaParseError(s.to_string())),
+        }
+    }
+}
+
+/// Parses a byte count with an optional binary `K`, `M`, `G` or `T` suffix
+pub fn parse_size(s: &str) -> Result<u64, QuotaParseError> {
+    let err = || QuotaParseError(s.to_string());
+    let s = s.trim();
+    let (number, shift) = match s.char_indices().last().ok_or_else(err)? {
+        (i, 'k') | (i, 'K') => (&s[..i], 10),
+        (i, 'm') | (i, 'M') => (&s[..i], 20),
+        (i, 'g') | (i, 'G') => (&s[..i], 30),
+        (i, 't') | (i, 'T') => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    let number: u64 = number.trim().parse().map_err(|_| err())?;
+    number.checked_mul(1 << shift).ok_or_else(err)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaConfig {
+    /// Bytes per client per UTC day
+    pub daily: Option<u64>,
+    /// Bytes per client per UTC month
+    pub monthly: Option<u64>,
+    pub action: QuotaAction,
+    /// Bytes per second a throttled client may still transfer
+    pub throttle_rate: u64,
+}
+
+impl QuotaConfig {
+    /// Without a cap there is nothing to count
+    pub fn is_enabled(&self) -> bool {
+        self.daily.is_some() || self.monthly.is_some()
+    }
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            daily: None,
+            monthly: None,
+            action: QuotaAction::Throttle,
+            throttle_rate: 16 * 1024,
+        }
+    }
+}
+
+/// A UTC day and the month it falls in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Date {
+    /// days since the unix epoch
+    day: i64,
+    /// `year * 12 + month`, with months counted from 0
+    month: i64,
+}
+
+impl Date {
+    pub fn of(time: SystemTime) -> Self {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        let day = secs.div_euclid(86400);
+        // civil_from_days by Howard Hinnant
+        let z = day + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let month = if mp < 10 { mp + 2 } else { mp - 10 };
+        let year = yoe + era * 400 + if month < 2 { 1 } else { 0 };
+        Date {
+            day,
+            month: year * 12 + month,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Usage {
+    day: i64,
+    day_bytes: u64,
+    month: i64,
+    month_bytes: u64,
+}
+
+impl Usage {
+    fn roll(&mut self, date: Date) {
+        if self.day != date.day {
+            self.day = date.day;
+            self.day_bytes = 0;
+        }
+        if self.month != date.month {
+            self.month = date.month;
+            self.month_bytes = 0;
+        }
+    }
+    fn is_over(&self, config: &QuotaConfig) -> bool {
+        config
+            .daily
+            .map(|cap| self.day_bytes >= cap)
+            .unwrap_or(false)
+            || config
+                .monthly
+                .map(|cap| self.month_bytes >= cap)
+                .unwrap_or(false)
+    }
+}
+
+/// Quota usage of a client
+#[derive(SimpleObject, Clone, Debug, Eq, PartialEq)]
+pub struct QuotaUsage {
+    /// client ip
+    pub ip: String,
+    /// bytes today, UTC
+    pub day_bytes: BigInt,
+    /// bytes this month, UTC
+    pub month_bytes: BigInt,
+    /// whether the client is throttled or refused
+    pub over_quota: bool,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    /// One second worth of burst. A frame larger than that still goes
+    /// through once the bucket is full and is paid back before the next,
+    /// so a rate below the frame size slows a client down instead of
+    /// cutting it off.
+    fn take(&mut self, rate: u64, bytes: usize, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        if self.tokens >= bytes as f64 || self.tokens >= rate as f64 {
+            self.tokens -= bytes as f64;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Usage of every client, without any I/O
+pub struct QuotaTable {
+    usage: BoundedMap<IpAddr, Usage>,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl Default for QuotaTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl QuotaTable {
+    pub fn new() -> Self {
+        Self {
+            usage: BoundedMap::new(MAX_CLIENTS, OVERFLOW),
+            buckets: HashMap::new(),
+        }
+    }
+    /// Counts bytes without applying the cap
+    pub fn add(&mut self, ip: IpAddr, bytes: usize, date: Date) {
+        let usage = self.usage.get_or_default(ip);
+        usage.roll(date);
+        usage.day_bytes += bytes as u64;
+        usage.month_bytes += bytes as u64;
+    }
+    /// Counts bytes to or from a client. Returns false if they should be
+    /// dropped.
+    pub fn allow(
+        &mut self,
+        config: &QuotaConfig,
+        ip: IpAddr,
+        bytes: usize,
+        date: Date,
+        instant: Instant,
+    ) -> bool {
+        self.add(ip, bytes, date);
+        if !self
+            .usage
+            .get(&ip)
+            .is_some_and(|usage| usage.is_over(config))
+        {
+            self.buckets.remove(&ip);
+            return true;
+        }
+        match config.action {
+            QuotaAction::Refuse => false,
+            QuotaAction::Throttle => self
+                .buckets
+                .entry(ip)
+                .or_insert(Bucket {
+                    tokens: config.throttle_rate as f64,
+                    last: instant,
+                })
+                .take(config.throttle_rate, bytes, instant),
+        }
+    }
+    /// Most used first
+    pub fn usage(&self, config: &QuotaConfig, date: Date) -> Vec<QuotaUsage> {
+        let mut list: Vec<_> = self
+            .usage
+            .iter()
+            .map(|(ip, usage)| {
+                let mut usage = usage.clone();
+                usage.roll(date);
+                QuotaUsage {
+                    ip: ip.to_string(),
+                    day_bytes: usage.day_bytes.into(),
+                    month_bytes: usage.month_bytes.into(),
+                    over_quota: usage.is_over(config),
+                }
+            })
+            .filter(|u| u.month_bytes.0 > 0)
+            .collect();
+        list.sort_by_key(|u| std::cmp::Reverse(u.month_bytes));
+        list
+    }
+    /// Forgets clients with no usage this month, and lets new clients that
+    /// used more replace established ones
+    pub fn prune(&mut self, date: Date) {
+        self.usage.retain(|_, usage| usage.month == date.month);
+        self.usage.promote(|usage| usage.month_bytes);
+        let usage = &self.usage;
+        self.buckets.retain(|ip, _| usage.contains_key(ip));
+    }
+    /// Forgets the usage of one client, or of all. Returns how many were reset.
+    pub fn reset(&mut self, ip: Option<IpAddr>) -> usize {
+        match ip {
+            Some(ip) => {
+                self.buckets.remove(&ip);
+                self.usage.remove(&ip).map(|_| 1).unwrap_or(0)
+            }
+            None => {
+                let count = self.usage.len();
+                self.buckets.clear();
+                self.usage.clear();
+                count
+            }
+        }
+    }
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let json = std::fs::read(path)?;
+        let usage: HashMap<IpAddr, Usage> = serde_json::from_slice(&json)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let mut table = Self::new();
+        for (ip, usage) in usage {
+            *table.usage.get_or_default(ip) = usage;
+        }
+        Ok(table)
+    }
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let usage: HashMap<_, _> = self.usage.iter().collect();
+        write_atomic(path, &serde_json::to_vec(&usage)?)
+    }
+}
+
+#[derive(Clone)]
+struct Inner {
+    config: Arc<RwLock<QuotaConfig>>,
+    table: Arc<Mutex<QuotaTable>>,
+    file: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl Inner {
+    async fn save(&self) {
+        let path = match self.file.lock().clone() {
+            Some(path) => path,
+            None => return,
+        };
+        let table = self.table.clone();
+        save_blocking(path, "quota usage", move |path| table.lock().save(path)).await;
+    }
+}
+
+#[derive(Clone)]
+pub struct QuotaPlugin(Inner);
+
+impl QuotaPlugin {
+    fn new() -> Self {
+        let inner = Inner {
+            config: Arc::new(RwLock::new(QuotaConfig::default())),
+            table: Arc::new(Mutex::new(QuotaTable::new())),
+            file: Arc::new(Mutex::new(None)),
+        };
+        let saver = inner.clone();
+        tokio::spawn(async move {
+            let mut interval = interval(SAVE_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                saver.table.lock().prune(Date::of(SystemTime::now()));
+                saver.save().await;
+            }
+        });
+        Self(inner)
+    }
+    pub fn config(&self) -> QuotaConfig {
+        self.0.config.read().clone()
+    }
+    pub fn set_config(&self, config: QuotaConfig) {
+        *self.0.config.write() = config;
+    }
+    /// Loads usage from `path` if it exists, and saves to it every minute
+    pub fn set_file(&self, path: PathBuf) -> std::io::Result<()> {
+        match QuotaTable::load(&path) {
+            Ok(table) => *self.0.table.lock() = table,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        *self.0.file.lock() = Some(path);
+        Ok(())
+    }
+    pub fn usage(&self) -> Vec<QuotaUsage> {
+        let config = self.config();
+        self.0
+            .table
+            .lock()
+            .usage(&config, Date::of(SystemTime::now()))
+    }
+    pub async fn reset(&self, ip: Option<IpAddr>) -> usize {
+        let count = self.0.table.lock().reset(ip);
+        self.0.save().await;
+        count
+    }
+}
+
+#[async_trait]
+impl Plugin for QuotaPlugin {
+    /// Every forwarder frame is counted, a client's first included. The
+    /// table keeps at most `MAX_CLIENTS` clients: while it is full, new
+    /// senders are counted in a small overflow that they only leave by
+    /// using more than an established client. Spoofed source addresses
+    /// can't grow the table or push out established clients, but they can
+    /// push out each other and other new clients.
+    async fn in_packet(&self, frame: &InFrame<'_>) -> Result<(), ()> {
+        let config = self.config();
+        if !config.is_enabled() || frame.frame().is_none() {
+            return Ok(());
+        }
+        let ip = frame.addr().ip();
+        let mut table = self.0.table.lock();
+        let size = frame.packet().as_ref().len();
+        let date = Date::of(SystemTime::now());
+        if table.allow(&config, ip, size, date, Instant::now()) {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+    /// Clients over their quota are skipped, or throttled like their uploads
+    async fn out_packet(&self, packet: &Packet, addrs: &mut Vec<SocketAddr>) -> Result<(), ()> {
+        let config = self.config();
+        if !config.is_enabled() {
+            return Ok(());
+        }
+        let (date, instant) = (Date::of(SystemTime::now()), Instant::now());
+        let mut table = self.0.table.lock();
+        addrs.retain(|addr| table.allow(&config, addr.ip(), packet.len(), date, instant));
+        Ok(())
+    }
+}
+
+impl PluginType for QuotaPlugin {
+    fn create(_: Context) -> BoxPlugin {
+        Box::new(QuotaPlugin::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{client_connect, make_packet, make_server, recv_nothing, recv_packet};
+    use smoltcp::wire::Ipv4Address;
+    use tokio::time::sleep;
+
+    const MB: u64 = 1024 * 1024;
+
+    fn at(secs: u64) -> Date {
+        Date::of(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1234").unwrap(), 1234);
+        assert_eq!(parse_size("10K").unwrap(), 10 * 1024);
+        assert_eq!(parse_size("500m").unwrap(), 500 * MB);
+        assert_eq!(parse_size("2 G").unwrap(), 2048 * MB);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("99999999T").is_err());
+    }
+
+    #[test]
+    fn test_date() {
+        let date = |day, month| Date { day, month };
+        assert_eq!(at(0), date(0, 1970 * 12));
+        // 1970-01-31 23:59:59
+        assert_eq!(at(31 * 86400 - 1), date(30, 1970 * 12));
+        // 1970-02-01
+        assert_eq!(at(31 * 86400), date(31, 1970 * 12 + 1));
+        // 2024-02-29 and 2024-03-01
+        assert_eq!(at(1709164800).month, 2024 * 12 + 1);
+        assert_eq!(at(1709251200).month, 2024 * 12 + 2);
+        // 2023-12-31 and 2024-01-01
+        assert_eq!(at(1704067199).month, 2023 * 12 + 11);
+        assert_eq!(at(1704067200).month, 2024 * 12);
+    }
+
+    #[test]
+    fn test_daily_cap_refuses_until_next_day() {
+        let config = QuotaConfig {
+            daily: Some(1000),
+            action: QuotaAction::Refuse,
+            ..Default::default()
+        };
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let other: IpAddr = "1.2.3.5".parse().unwrap();
+        let instant = Instant::now();
+        let mut table = QuotaTable::new();
+        let day = 1_700_006_400;
+
+        assert!(table.allow(&config, ip, 600, at(day), instant));
+        table.add(ip, 300, at(day));
+        // this one crosses the cap
+        assert!(!table.allow(&config, ip, 200, at(day + 10), instant));
+        assert!(!table.allow(&config, ip, 1, at(day + 20), instant));
+        assert!(table.allow(&config, other, 100, at(day + 20), instant));
+
+        let usage = table.usage(&config, at(day + 30));
+        assert_eq!(usage[0].ip, "1.2.3.4");
+        assert_eq!(usage[0].day_bytes, BigInt(1101));
+        assert!(usage[0].over_quota);
+        assert!(!usage[1].over_quota);
+
+        assert!(table.allow(&config, ip, 1, at(day + 86400), instant));
+        let usage = table.usage(&config, at(day + 86400));
+        assert_eq!(usage[0].day_bytes, BigInt(1));
+        assert_eq!(usage[0].month_bytes, BigInt(1102));
+    }
+
+    #[test]
+    fn test_throttle() {
+        let config = QuotaConfig {
+            monthly: Some(100),
+            action: QuotaAction::Throttle,
+            throttle_rate: 1000,
+            ..Default::default()
+        };
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let now = at(1_700_006_400);
+        let start = Instant::now();
+        let mut table = QuotaTable::new();
+
+        table.add(ip, 100, now);
+        assert!(table.allow(&config, ip, 600, now, start));
+        assert!(!table.allow(&config, ip, 600, now, start));
+        assert!(table.allow(&config, ip, 400, now, start));
+        assert!(!table.allow(&config, ip, 100, now, start));
+        assert!(table.allow(&config, ip, 500, now, start + Duration::from_millis(500)));
+        // the burst is capped at one second
+        let later = start + Duration::from_secs(10);
+        assert!(table.allow(&config, ip, 1000, now, later));
+        assert!(!table.allow(&config, ip, 1, now, later));
+
+        assert_eq!(table.reset(Some(ip)), 1);
+        assert!(table.allow(&config, ip, 50, now, later));
+        assert_eq!(table.reset(None), 1);
+        assert!(table.usage(&config, now).is_empty());
+    }
+
+    #[test]
+    fn test_throttle_below_frame_size() {
+        let config = QuotaConfig {
+            monthly: Some(100),
+            action: QuotaAction::Throttle,
+            throttle_rate: 100,
+            ..Default::default()
+        };
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let now = at(1_700_006_400);
+        let start = Instant::now();
+        let mut table = QuotaTable::new();
+        table.add(ip, 100, now);
+
+        // a full bucket lets one frame through, which takes 13s to pay back
+        assert!(table.allow(&config, ip, 1300, now, start));
+        assert!(!table.allow(&config, ip, 1300, now, start));
+        let later = start + Duration::from_secs(12);
+        assert!(!table.allow(&config, ip, 1300, now, later));
+        let later = start + Duration::from_secs(14);
+        assert!(table.allow(&config, ip, 1300, now, later));
+    }
+
+    #[test]
+    fn test_spoofed_flood() {
+        let config = QuotaConfig {
+            daily: Some(MB),
+            ..Default::default()
+        };
+        let now = at(1_700_006_400);
+        let mut table = QuotaTable::new();
+        let client = |i: u32| IpAddr::from((i + 1).to_be_bytes());
+        for i in 0..MAX_CLIENTS as u32 {
+            table.add(client(i), 1000, now);
+        }
+        for i in 0..100_000 {
+            table.add(client(MAX_CLIENTS as u32 + i), 100, now);
+        }
+        table.prune(now);
+
+        assert_eq!(table.usage.len(), MAX_CLIENTS + OVERFLOW);
+        let usage = table.usage(&config, now);
+        assert!(usage[..MAX_CLIENTS]
+            .iter()
+            .all(|u| u.month_bytes == BigInt(1000)));
+
+        // a new client that used more takes an established one's place
+        let heavy = client(1_000_000);
+        table.add(heavy, 5000, now);
+        table.prune(now);
+        assert!(table.usage.main().contains_key(&heavy));
+    }
+
+    #[test]
+    fn test_save_load() {
+        let path = std::env::temp_dir().join(format!("slp-quota-{}.json", std::process::id()));
+        let config = QuotaConfig::default();
+        let now = at(1_700_006_400);
+        let mut table = QuotaTable::new();
+        table.add("1.2.3.4".parse().unwrap(), 1234, now);
+        table.add("::1".parse().unwrap(), 5678, now);
+        table.save(&path).unwrap();
+        let loaded = QuotaTable::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.usage(&config, now), table.usage(&config, now));
+    }
+
+    #[tokio::test]
+    async fn test_refused_client_is_dropped() {
+        let (udp_server, addr) = make_server().await;
+        udp_server.add_plugin::<QuotaPlugin>().await;
+        let ip = |i| Ipv4Address::new(10, 13, 37, i);
+
+        let quota = udp_server
+            .get_plugin::<QuotaPlugin, _, _>(|q| q.cloned())
+            .await
+            .unwrap();
+        let refuse_over = |daily| QuotaConfig {
+            daily: Some(daily),
+            action: QuotaAction::Refuse,
+            ..Default::default()
+        };
+        quota.set_config(refuse_over(MB));
+
+        let mut client1 = client_connect(addr).await;
+        let client2 = client_connect(addr).await;
+        client1.send(&make_packet(ip(1), ip(2))).await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+        client2.send(&make_packet(ip(2), ip(1))).await.unwrap();
+        recv_packet(&mut client1).await;
+
+        let used = quota.usage();
+        assert_eq!(used[0].ip, "127.0.0.1");
+        quota.set_config(refuse_over(1));
+        client2.send(&make_packet(ip(2), ip(1))).await.unwrap();
+        recv_nothing(&mut client1).await;
+    }
+
+    #[tokio::test]
+    async fn test_junk_not_counted() {
+        let (udp_server, addr) = make_server().await;
+        udp_server.add_plugin::<QuotaPlugin>().await;
+        let quota = udp_server
+            .get_plugin::<QuotaPlugin, _, _>(|q| q.cloned())
+            .await
+            .unwrap();
+        quota.set_config(QuotaConfig {
+            daily: Some(MB),
+            ..Default::default()
+        });
+
+        // not a forwarder frame, so it never becomes a peer
+        let client = client_connect(addr).await;
+        client.send(&[0xff; 100]).await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+        assert!(quota.usage().is_empty());
+
+        // a client's first frame counts
+        client.send(&[0]).await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+        assert_eq!(quota.usage()[0].day_bytes, BigInt(1));
+    }
+
+    #[tokio::test]
+    async fn test_throttled_client_receives_less() {
+        let (udp_server, _) = make_server().await;
+        udp_server.add_plugin::<QuotaPlugin>().await;
+        let quota = udp_server
+            .get_plugin::<QuotaPlugin, _, _>(|q| q.cloned())
+            .await
+            .unwrap();
+        quota.set_config(QuotaConfig {
+            daily: Some(1000),
+            action: QuotaAction::Throttle,
+            throttle_rate: 1000,
+            ..Default::default()
+        });
+        let throttled: SocketAddr = "10.0.0.1:11451".parse().unwrap();
+        let other: SocketAddr = "10.0.0.2:11451".parse().unwrap();
+        let packet = vec![0u8; 400];
+
+        let mut sent = 0;
+        for _ in 0..10 {
+            let mut addrs = vec![throttled];
+            quota.out_packet(&packet, &mut addrs).await.unwrap();
+            sent += addrs.len();
+        }
+        // 1000 bytes under the cap, then one second worth of the rate
+        assert_eq!(sent, 4);
+
+        let mut addrs = vec![throttled, other];
+        quota.out_packet(&packet, &mut addrs).await.unwrap();
+        assert_eq!(addrs, vec![other]);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_without_cap() {
+        let (udp_server, _) = make_server().await;
+        udp_server.add_plugin::<QuotaPlugin>().await;
+        let quota = udp_server
+            .get_plugin::<QuotaPlugin, _, _>(|q| q.cloned())
+            .await
+            .unwrap();
+        let mut addrs = vec!["10.0.0.1:11451".parse().unwrap()];
+        quota.out_packet(&vec![0u8; 400], &mut addrs).await.unwrap();
+        assert_eq!(addrs.len(), 1);
+        assert!(quota.usage().is_empty());
+    }
+}
@@ -0,0 +1,244 @@
+//! Traffic history, downsampled into rings of fixed resolution.
+
+use super::Tally;
+use crate::util::{write_atomic, BigInt};
+use async_graphql::{Enum, SimpleObject};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Enum, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Resolution {
+    /// kept for 10 minutes
+    Second,
+    /// kept for 24 hours
+    Minute,
+    /// kept for 30 days
+    Hour,
+}
+
+impl Resolution {
+    const ALL: [Resolution; 3] = [Resolution::Second, Resolution::Minute, Resolution::Hour];
+
+    fn secs(self) -> i64 {
+        match self {
+            Resolution::Second => 1,
+            Resolution::Minute => 60,
+            Resolution::Hour => 60 * 60,
+        }
+    }
+    fn retention(self) -> i64 {
+        match self {
+            Resolution::Second => 10 * 60,
+            Resolution::Minute => 24 * 60 * 60,
+            Resolution::Hour => 30 * 24 * 60 * 60,
+        }
+    }
+    /// The finest resolution still kept for `range`
+    pub fn covering(range: Duration) -> Resolution {
+        let range = range.as_secs() as i64;
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|r| r.retention() >= range)
+            .unwrap_or(Resolution::Hour)
+    }
+}
+
+/// Traffic in one period
+#[derive(SimpleObject, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TrafficSample {
+    /// start of the period, in seconds since the unix epoch
+    pub time: i64,
+    /// upload bytes
+    pub upload: BigInt,
+    /// download bytes
+    pub download: BigInt,
+    /// upload packets
+    pub upload_packet: BigInt,
+    /// download packets
+    pub download_packet: BigInt,
+}
+
+impl TrafficSample {
+    fn add(&mut self, tally: &Tally) {
+        self.upload.0 += tally.upload;
+        self.download.0 += tally.download;
+        self.upload_packet.0 += tally.upload_packet;
+        self.download_packet.0 += tally.download_packet;
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TrafficHistory {
+    seconds: VecDeque<TrafficSample>,
+    minutes: VecDeque<TrafficSample>,
+    hours: VecDeque<TrafficSample>,
+}
+
+impl TrafficHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn ring(&self, resolution: Resolution) -> &VecDeque<TrafficSample> {
+        match resolution {
+            Resolution::Second => &self.seconds,
+            Resolution::Minute => &self.minutes,
+            Resolution::Hour => &self.hours,
+        }
+    }
+    fn ring_mut(&mut self, resolution: Resolution) -> &mut VecDeque<TrafficSample> {
+        match resolution {
+            Resolution::Second => &mut self.seconds,
+            Resolution::Minute => &mut self.minutes,
+            Resolution::Hour => &mut self.hours,
+        }
+    }
+    /// Adds the traffic of the second starting at `time`. Returns true when
+    /// this starts a new minute.
+    pub(super) fn record(&mut self, time: i64, tally: &Tally) -> bool {
+        let mut new_minute = false;
+        for &resolution in Resolution::ALL.iter() {
+            let start = time - time.rem_euclid(resolution.secs());
+            let ring = self.ring_mut(resolution);
+            match ring.back_mut() {
+                Some(last) if last.time == start => last.add(tally),
+                _ => {
+                    let mut sample = TrafficSample {
+                        time: start,
+                        ..Default::default()
+                    };
+                    sample.add(tally);
+                    ring.push_back(sample);
+                    new_minute |= resolution == Resolution::Minute;
+                }
+            }
+            while ring
+                .front()
+                .map(|s| s.time <= time - resolution.retention())
+                .unwrap_or(false)
+            {
+                ring.pop_front();
+            }
+        }
+        new_minute
+    }
+    /// Samples starting at or after `since`, oldest first. The last one may
+    /// still be filling up.
+    pub fn samples(&self, resolution: Resolution, since: i64) -> Vec<TrafficSample> {
+        let ring = self.ring(resolution);
+        let first = ring.partition_point(|s| s.time < since);
+        ring.range(first..).cloned().collect()
+    }
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let json = std::fs::read(path)?;
+        serde_json::from_slice(&json)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        write_atomic(path, &serde_json::to_vec(self)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Resolution, TrafficHistory};
+    use crate::plugin::traffic::Tally;
+    use crate::util::BigInt;
+    use std::time::Duration;
+
+    fn info(upload: u64) -> Tally {
+        Tally {
+            upload,
+            download: upload * 2,
+            upload_packet: 1,
+            download_packet: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_downsample() {
+        let mut history = TrafficHistory::new();
+        let start = 1_700_000_000 - 1_700_000_000 % 3600;
+        assert!(history.record(start, &info(100)));
+        for t in 1..120 {
+            assert_eq!(history.record(start + t, &info(100)), t % 60 == 0);
+        }
+
+        let seconds = history.samples(Resolution::Second, 0);
+        assert_eq!(seconds.len(), 120);
+        assert_eq!(seconds[0].time, start);
+        assert_eq!(seconds[0].upload, BigInt(100));
+
+        let minutes = history.samples(Resolution::Minute, 0);
+        assert_eq!(minutes.len(), 2);
+        assert_eq!(minutes[1].time, start + 60);
+        assert_eq!(minutes[1].upload, BigInt(6000));
+        assert_eq!(minutes[1].download, BigInt(12000));
+        assert_eq!(minutes[1].upload_packet, BigInt(60));
+
+        let hours = history.samples(Resolution::Hour, 0);
+        assert_eq!(hours.len(), 1);
+        assert_eq!(hours[0].upload, BigInt(12000));
+
+        assert_eq!(history.samples(Resolution::Second, start + 100).len(), 20);
+    }
+
+    #[test]
+    fn test_retention() {
+        let mut history = TrafficHistory::new();
+        let start = 1_700_000_000;
+        for t in 0..700 {
+            history.record(start + t, &info(1));
+        }
+        let seconds = history.samples(Resolution::Second, 0);
+        assert_eq!(seconds.len(), 600);
+        assert_eq!(seconds[0].time, start + 100);
+
+        // a gap longer than the retention empties the ring
+        history.record(start + 10_000, &info(1));
+        assert_eq!(history.samples(Resolution::Second, 0).len(), 1);
+        assert_eq!(history.samples(Resolution::Minute, 0).len(), 13);
+    }
+
+    #[test]
+    fn test_covering() {
+        assert_eq!(
+            Resolution::covering(Duration::from_secs(600)),
+            Resolution::Second
+        );
+        assert_eq!(
+            Resolution::covering(Duration::from_secs(3600)),
+            Resolution::Minute
+        );
+        assert_eq!(
+            Resolution::covering(Duration::from_secs(7 * 24 * 3600)),
+            Resolution::Hour
+        );
+        assert_eq!(
+            Resolution::covering(Duration::from_secs(365 * 24 * 3600)),
+            Resolution::Hour
+        );
+    }
+
+    #[test]
+    fn test_save_load() {
+        let path = std::env::temp_dir().join(format!("slp-traffic-{}.json", std::process::id()));
+        let mut history = TrafficHistory::new();
+        for t in 0..90 {
+            history.record(1_700_000_000 + t, &info(5));
+        }
+        history.save(&path).unwrap();
+        let loaded = TrafficHistory::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        for resolution in [Resolution::Second, Resolution::Minute, Resolution::Hour] {
+            assert_eq!(
+                loaded.samples(resolution, 0),
+                history.samples(resolution, 0)
+            );
+        }
+    }
+}
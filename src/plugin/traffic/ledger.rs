@@ -0,0 +1,261 @@
+//! Traffic broken down by client, virtual IP and broadcast or unicast.
+
+use crate::util::{BigInt, BoundedMap};
+use async_graphql::{Enum, SimpleObject};
+use std::fmt::Display;
+use std::hash::Hash;
+use std::net::{Ipv4Addr, SocketAddr};
+
+/// Seconds without traffic before an entry is forgotten
+const FORGET_AFTER: u32 = 5 * 60;
+/// Entries kept per ledger. Virtual IPs are whatever clients put in their
+/// packets, so without a bound a client could grow the ledger at will.
+const MAX_ENTRIES: usize = 1024;
+/// New keys seen while the ledger is full, waiting to replace an entry
+const OVERFLOW: usize = 64;
+
+#[derive(SimpleObject, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Counters {
+    /// bytes sent by the server
+    pub upload: BigInt,
+    /// bytes received by the server
+    pub download: BigInt,
+    /// packets sent by the server
+    pub upload_packet: BigInt,
+    /// packets received by the server
+    pub download_packet: BigInt,
+}
+
+impl Counters {
+    fn bytes(&self) -> u64 {
+        self.upload.0 + self.download.0
+    }
+    fn is_empty(&self) -> bool {
+        self.upload_packet.0 == 0 && self.download_packet.0 == 0
+    }
+    fn on_upload(&mut self, bytes: usize, packets: usize) {
+        self.upload.0 += bytes as u64;
+        self.upload_packet.0 += packets as u64;
+    }
+    fn on_download(&mut self, bytes: usize) {
+        self.download.0 += bytes as u64;
+        self.download_packet.0 += 1;
+    }
+}
+
+/// Traffic of one client, virtual IP or cast kind
+#[derive(SimpleObject, Clone, Debug, Eq, PartialEq)]
+pub struct Talker {
+    /// client address, virtual IP, or `broadcast`/`unicast`
+    pub key: String,
+    /// traffic last second
+    pub last_second: Counters,
+    /// traffic since first seen
+    pub total: Counters,
+}
+
+#[derive(Enum, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TalkerKind {
+    /// by client address
+    Peer,
+    /// by LAN address. Upload is what the server sent on behalf of the
+    /// address, so a client flooding broadcasts shows up here.
+    VirtualIp,
+    /// broadcast or unicast
+    Cast,
+}
+
+#[derive(Debug, Default)]
+struct Entry {
+    current: Counters,
+    last_second: Counters,
+    total: Counters,
+    idle: u32,
+}
+
+#[derive(Debug)]
+pub struct Ledger<K: Hash + Eq> {
+    entries: BoundedMap<K, Entry>,
+}
+
+impl<K: Hash + Eq + Clone + Display> Ledger<K> {
+    pub fn new() -> Self {
+        Self::with_capacity(MAX_ENTRIES)
+    }
+    /// Once full, new keys only replace the entries with the least total
+    /// traffic when the ledger ticks.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: BoundedMap::new(capacity, OVERFLOW),
+        }
+    }
+    pub fn upload(&mut self, key: K, bytes: usize, packets: usize) {
+        let entry = self.entries.get_or_default(key);
+        entry.current.on_upload(bytes, packets);
+        entry.total.on_upload(bytes, packets);
+    }
+    pub fn download(&mut self, key: K, bytes: usize) {
+        let entry = self.entries.get_or_default(key);
+        entry.current.on_download(bytes);
+        entry.total.on_download(bytes);
+    }
+    /// Ends the current second
+    pub fn tick(&mut self) {
+        self.entries.retain(|_, entry| {
+            entry.last_second = std::mem::take(&mut entry.current);
+            if entry.last_second.is_empty() {
+                entry.idle += 1;
+            } else {
+                entry.idle = 0;
+            }
+            entry.idle < FORGET_AFTER
+        });
+        self.entries.promote(|entry| entry.total.bytes());
+    }
+    /// The busiest entries last second, ties broken by total traffic
+    pub fn top(&self, limit: usize) -> Vec<Talker> {
+        let mut talkers: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(key, entry)| Talker {
+                key: key.to_string(),
+                last_second: entry.last_second.clone(),
+                total: entry.total.clone(),
+            })
+            .collect();
+        talkers.sort_by(|a, b| {
+            (b.last_second.bytes(), b.total.bytes()).cmp(&(a.last_second.bytes(), a.total.bytes()))
+        });
+        talkers.truncate(limit);
+        talkers
+    }
+}
+
+impl<K: Hash + Eq + Clone + Display> Default for Ledger<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Broadcast and unicast LAN packets, telling them apart by the destination
+/// the way `PeerManager` routes them.
+pub fn cast_of(dst_ip: Ipv4Addr, addrs: usize) -> &'static str {
+    let octets = dst_ip.octets();
+    let broadcast = dst_ip.is_broadcast()
+        || dst_ip.is_multicast()
+        || octets[2..] == [255, 255]
+        // unknown destinations are sent to everyone
+        || addrs > 1;
+    if broadcast {
+        "broadcast"
+    } else {
+        "unicast"
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Talkers {
+    pub peers: Ledger<SocketAddr>,
+    pub virtual_ips: Ledger<Ipv4Addr>,
+    pub cast: Ledger<&'static str>,
+}
+
+impl Talkers {
+    pub fn tick(&mut self) {
+        self.peers.tick();
+        self.virtual_ips.tick();
+        self.cast.tick();
+    }
+    pub fn top(&self, kind: TalkerKind, limit: usize) -> Vec<Talker> {
+        match kind {
+            TalkerKind::Peer => self.peers.top(limit),
+            TalkerKind::VirtualIp => self.virtual_ips.top(limit),
+            TalkerKind::Cast => self.cast.top(limit),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cast_of, Ledger, FORGET_AFTER, OVERFLOW};
+    use crate::util::BigInt;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_top() {
+        let mut ledger = Ledger::new();
+        ledger.download("a", 100);
+        ledger.upload("b", 300, 3);
+        ledger.download("c", 10);
+        ledger.tick();
+        ledger.download("a", 1000);
+
+        let top = ledger.top(2);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].key, "b");
+        assert_eq!(top[0].last_second.upload, BigInt(300));
+        assert_eq!(top[0].last_second.upload_packet, BigInt(3));
+        assert_eq!(top[1].key, "a");
+        assert_eq!(top[1].last_second.download, BigInt(100));
+        assert_eq!(top[1].total.download, BigInt(1100));
+
+        ledger.tick();
+        assert_eq!(ledger.top(1)[0].key, "a");
+        ledger.tick();
+        // nothing last second, ordered by total
+        let keys: Vec<_> = ledger.top(3).into_iter().map(|t| t.key).collect();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_forget_idle() {
+        let mut ledger = Ledger::new();
+        ledger.download("a", 1);
+        ledger.download("b", 1);
+        for _ in 0..=FORGET_AFTER {
+            ledger.download("b", 1);
+            ledger.tick();
+        }
+        let keys: Vec<_> = ledger.top(10).into_iter().map(|t| t.key).collect();
+        assert_eq!(keys, vec!["b"]);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut ledger = Ledger::with_capacity(10);
+        for i in 0..10 {
+            ledger.download(i.to_string(), 1000);
+        }
+        ledger.tick();
+        // a flood of new keys, each seen once
+        for i in 10..10_000 {
+            ledger.download(i.to_string(), 1);
+            if i % 100 == 0 {
+                ledger.tick();
+            }
+        }
+        ledger.tick();
+        let mut keys: Vec<_> = ledger.entries.main().keys().cloned().collect();
+        keys.sort();
+        let established: Vec<_> = (0..10).map(|i| i.to_string()).collect();
+        assert_eq!(keys, established);
+        assert_eq!(ledger.entries.len(), 10 + OVERFLOW);
+
+        // a new talker busier than an established one takes its place
+        ledger.upload("new".to_string(), 2000, 1);
+        ledger.tick();
+        assert!(ledger.entries.main().contains_key("new"));
+        assert_eq!(ledger.entries.main().len(), 10);
+    }
+
+    #[test]
+    fn test_cast() {
+        let ip = Ipv4Addr::new(10, 13, 37, 100);
+        assert_eq!(cast_of(ip, 1), "unicast");
+        assert_eq!(cast_of(ip, 0), "unicast");
+        assert_eq!(cast_of(ip, 2), "broadcast");
+        assert_eq!(cast_of(Ipv4Addr::new(10, 13, 255, 255), 1), "broadcast");
+        assert_eq!(cast_of(Ipv4Addr::BROADCAST, 1), "broadcast");
+        assert_eq!(cast_of(Ipv4Addr::new(224, 0, 0, 1), 1), "broadcast");
+    }
+}
@@ -0,0 +1,455 @@
+mod history;
+mod ledger;
+
+pub use history::{Resolution, TrafficHistory, TrafficSample};
+pub use ledger::{Counters, Talker, TalkerKind};
+
+use ledger::{cast_of, Talkers};
+
+use crate::slp::frame::{ForwarderFrame, Parser};
+use crate::slp::plugin::*;
+use crate::slp::stream::spawn_stream;
+use crate::util::{saturate, save_blocking, BigInt, FilterSameExt};
+use async_graphql::SimpleObject;
+use futures::prelude::*;
+use futures::{future, stream::BoxStream};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+
+/// Traffic infomation
+#[derive(SimpleObject, Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct TrafficInfo {
+    /// upload bytes last second, at most 2^31 - 1
+    #[graphql(deprecation = "Use uploadBytes")]
+    upload: i32,
+    /// download bytes last second, at most 2^31 - 1
+    #[graphql(deprecation = "Use downloadBytes")]
+    download: i32,
+    /// upload packets last second, at most 2^31 - 1. A packet sent to
+    /// several clients counts once.
+    #[graphql(deprecation = "Use uploadPackets")]
+    upload_packet: i32,
+    /// download packets last second, at most 2^31 - 1
+    #[graphql(deprecation = "Use downloadPackets")]
+    download_packet: i32,
+    /// upload bytes last second
+    upload_bytes: BigInt,
+    /// download bytes last second
+    download_bytes: BigInt,
+    /// upload packets last second
+    upload_packets: BigInt,
+    /// download packets last second
+    download_packets: BigInt,
+    /// upload bytes since start
+    total_upload_bytes: BigInt,
+    /// download bytes since start
+    total_download_bytes: BigInt,
+    /// upload packets since start
+    total_upload_packets: BigInt,
+    /// download packets since start
+    total_download_packets: BigInt,
+    /// seconds since start
+    uptime: BigInt,
+}
+type TrafficInfoStream = BoxStream<'static, TrafficInfo>;
+
+impl TrafficInfo {
+    fn new(second: &Tally, total: &Tally, uptime: Duration) -> Self {
+        Self {
+            upload: saturate(second.upload),
+            download: saturate(second.download),
+            upload_packet: saturate(second.upload_send),
+            download_packet: saturate(second.download_packet),
+            upload_bytes: second.upload.into(),
+            download_bytes: second.download.into(),
+            upload_packets: second.upload_packet.into(),
+            download_packets: second.download_packet.into(),
+            total_upload_bytes: total.upload.into(),
+            total_download_bytes: total.download.into(),
+            total_upload_packets: total.upload_packet.into(),
+            total_download_packets: total.download_packet.into(),
+            uptime: uptime.as_secs().into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Tally {
+    upload: u64,
+    download: u64,
+    /// one per destination
+    upload_packet: u64,
+    download_packet: u64,
+    /// one per packet however many peers it went to, as the deprecated
+    /// `upload_packet` always counted
+    upload_send: u64,
+}
+
+impl Tally {
+    fn on_upload(&mut self, size: usize, packets: usize) {
+        self.upload += size as u64;
+        self.upload_packet += packets as u64;
+        self.upload_send += 1;
+    }
+    fn on_download(&mut self, size: usize) {
+        self.download += size as u64;
+        self.download_packet += 1;
+    }
+}
+
+#[derive(Debug)]
+struct Traffic {
+    /// this second
+    current: Tally,
+    /// since start
+    total: Tally,
+    started: Instant,
+}
+
+impl Traffic {
+    fn new() -> Self {
+        Self {
+            current: Tally::default(),
+            total: Tally::default(),
+            started: Instant::now(),
+        }
+    }
+    fn on_upload(&mut self, size: usize, packets: usize) {
+        self.current.on_upload(size, packets);
+        self.total.on_upload(size, packets);
+    }
+    fn on_download(&mut self, size: usize) {
+        self.current.on_download(size);
+        self.total.on_download(size);
+    }
+    fn info(&self, second: &Tally) -> TrafficInfo {
+        TrafficInfo::new(second, &self.total, self.started.elapsed())
+    }
+}
+
+fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Debug)]
+struct Inner {
+    traffic: Arc<Mutex<Traffic>>,
+    history: Arc<Mutex<TrafficHistory>>,
+    history_file: Arc<Mutex<Option<PathBuf>>>,
+    talkers: Arc<Mutex<Talkers>>,
+}
+
+impl Inner {
+    fn new() -> Inner {
+        Inner {
+            traffic: Arc::new(Mutex::new(Traffic::new())),
+            history: Arc::new(Mutex::new(TrafficHistory::new())),
+            history_file: Arc::new(Mutex::new(None)),
+            talkers: Arc::new(Mutex::new(Talkers::default())),
+        }
+    }
+    async fn clear_traffic(&self) -> TrafficInfo {
+        let (second, info) = {
+            let mut traffic = self.traffic.lock();
+            let second = std::mem::take(&mut traffic.current);
+            let info = traffic.info(&second);
+            (second, info)
+        };
+        self.talkers.lock().tick();
+        // the second that just ended
+        let new_minute = self.history.lock().record(unix_time() - 1, &second);
+        if new_minute {
+            self.save_history().await;
+        }
+        info
+    }
+    async fn save_history(&self) {
+        let path = match self.history_file.lock().clone() {
+            Some(path) => path,
+            None => return,
+        };
+        let history = self.history.clone();
+        save_blocking(path, "traffic history", move |path| {
+            history.lock().save(path)
+        })
+        .await;
+    }
+    async fn in_packet(&self, frame: &InFrame<'_>) {
+        let size = frame.packet().as_ref().len();
+        self.traffic.lock().on_download(size);
+
+        let mut talkers = self.talkers.lock();
+        talkers.peers.download(*frame.addr(), size);
+        if let Some((src_ip, dst_ip)) = frame.frame().and_then(lan_addrs) {
+            talkers.virtual_ips.download(src_ip, size);
+            talkers.cast.download(cast_of(dst_ip, 0), size);
+        }
+    }
+    async fn out_packet(&self, packet: &Packet, addrs: &[SocketAddr]) {
+        let size = packet.len();
+        self.traffic
+            .lock()
+            .on_upload(size * addrs.len(), addrs.len());
+
+        let mut talkers = self.talkers.lock();
+        for addr in addrs {
+            talkers.peers.upload(*addr, size, 1);
+        }
+        let frame = ForwarderFrame::parse(packet).ok();
+        if let Some((src_ip, dst_ip)) = frame.as_ref().and_then(lan_addrs) {
+            let (bytes, packets) = (size * addrs.len(), addrs.len());
+            talkers.virtual_ips.upload(src_ip, bytes, packets);
+            talkers
+                .cast
+                .upload(cast_of(dst_ip, addrs.len()), bytes, packets);
+        }
+    }
+    async fn traffic_info(&self) -> TrafficInfo {
+        let traffic = self.traffic.lock();
+        traffic.info(&traffic.current)
+    }
+}
+
+/// Source and destination of a LAN frame
+fn lan_addrs(frame: &ForwarderFrame<'_>) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    match frame {
+        ForwarderFrame::Ipv4(ipv4) => Some((ipv4.src_ip(), ipv4.dst_ip())),
+        ForwarderFrame::Ipv4Frag(frag) => Some((frag.src_ip(), frag.dst_ip())),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TrafficPlugin(Inner, broadcast::Sender<TrafficInfo>);
+
+impl TrafficPlugin {
+    fn new() -> Self {
+        let inner = Inner::new();
+
+        let traffic_sender =
+            spawn_stream(&inner, |inner| async move { inner.clear_traffic().await });
+
+        Self(inner, traffic_sender)
+    }
+    pub async fn traffic_info(&self) -> TrafficInfo {
+        self.0.traffic_info().await
+    }
+    /// Samples of the last `range`, oldest first. Without a resolution, the
+    /// finest one kept for that long is used.
+    pub fn traffic_history(
+        &self,
+        range: Duration,
+        resolution: Option<Resolution>,
+    ) -> Vec<TrafficSample> {
+        let resolution = resolution.unwrap_or_else(|| Resolution::covering(range));
+        let since = unix_time() - range.as_secs() as i64;
+        self.0.history.lock().samples(resolution, since)
+    }
+    /// Loads history from `path` if it exists, and saves to it every minute
+    pub fn set_history_file(&self, path: PathBuf) -> std::io::Result<()> {
+        match TrafficHistory::load(&path) {
+            Ok(history) => *self.0.history.lock() = history,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        *self.0.history_file.lock() = Some(path);
+        Ok(())
+    }
+    /// The busiest clients, virtual IPs or cast kinds
+    pub fn top_talkers(&self, kind: TalkerKind, limit: usize) -> Vec<Talker> {
+        self.0.talkers.lock().top(kind, limit)
+    }
+    pub async fn traffic_info_stream(&self) -> TrafficInfoStream {
+        let stream = BroadcastStream::new(self.1.subscribe())
+            .take_while(|info| future::ready(info.is_ok()))
+            .map(|info| info.unwrap());
+
+        stream::once(future::ready(self.traffic_info().await))
+            .chain(stream)
+            .filter_same()
+            .boxed()
+    }
+}
+
+#[async_trait]
+impl Plugin for TrafficPlugin {
+    async fn in_packet(&self, frame: &InFrame<'_>) -> Result<(), ()> {
+        // traffic is what went over the wire, so every fragment counts
+        self.0.in_packet(frame).await;
+        Ok(())
+    }
+    async fn out_packet(&self, packet: &Packet, addrs: &mut Vec<SocketAddr>) -> Result<(), ()> {
+        self.0.out_packet(packet, addrs).await;
+        Ok(())
+    }
+}
+
+impl PluginType for TrafficPlugin {
+    fn create(_: Context) -> BoxPlugin {
+        Box::new(TrafficPlugin::new())
+    }
+}
+
+#[tokio::test]
+async fn get_traffic_from_box() {
+    let p: BoxPlugin = Box::new(TrafficPlugin::new());
+    let t = p.as_any().downcast_ref::<TrafficPlugin>();
+    assert!(t.is_some(), "Traffic should be Some");
+}
+
+#[test]
+fn test_traffic_info() {
+    let mut traffic = Traffic::new();
+    traffic.on_download(100);
+    traffic.on_upload(3 << 30, 3);
+    traffic.on_upload(0, 1);
+    let second = std::mem::take(&mut traffic.current);
+    traffic.on_download(50);
+
+    let info = traffic.info(&second);
+    assert_eq!(info.upload, i32::MAX);
+    assert_eq!(info.upload_bytes, BigInt(3 << 30));
+    assert_eq!(info.upload_packet, 2);
+    assert_eq!(info.upload_packets, BigInt(4));
+    assert_eq!(info.download, 100);
+    assert_eq!(info.total_download_bytes, BigInt(150));
+    assert_eq!(info.total_download_packets, BigInt(2));
+    assert_eq!(info.total_upload_bytes, BigInt(3 << 30));
+}
+
+#[tokio::test]
+async fn test_broadcast_legacy_upload_packet() {
+    use crate::test::make_packet;
+    use smoltcp::wire::Ipv4Address;
+
+    let inner = Inner::new();
+    let packet = make_packet(
+        Ipv4Address::new(10, 13, 37, 100),
+        Ipv4Address::new(10, 13, 255, 255),
+    );
+    let addrs: Vec<SocketAddr> = (0..3).map(|i| ([127, 0, 0, 1], 1000 + i).into()).collect();
+    inner.out_packet(&packet, &addrs).await;
+
+    let traffic = inner.traffic.lock();
+    let info = traffic.info(&traffic.current);
+    // the deprecated field counts the packet once, as it always has
+    assert_eq!(info.upload_packet, 1);
+    assert_eq!(info.upload, 3 * packet.len() as i32);
+    assert_eq!(info.upload_packets, BigInt(3));
+    assert_eq!(info.total_upload_packets, BigInt(3));
+}
+
+#[tokio::test]
+async fn test_top_talkers() {
+    use crate::test::{client_connect, make_packet, make_server};
+    use smoltcp::wire::Ipv4Address;
+    use tokio::time::{sleep, Duration};
+
+    let (udp_server, addr) = make_server().await;
+    udp_server.add_plugin::<TrafficPlugin>().await;
+    let ip = |i| Ipv4Address::new(10, 13, 37, i);
+    let broadcast = Ipv4Address::new(10, 13, 255, 255);
+
+    let clients = [
+        client_connect(addr).await,
+        client_connect(addr).await,
+        client_connect(addr).await,
+    ];
+    for (i, client) in clients.iter().enumerate() {
+        let packet = make_packet(ip(100 + i as u8), broadcast);
+        client.send(&packet).await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+    }
+    // client 0 floods
+    for _ in 0..5 {
+        clients[0]
+            .send(&make_packet(ip(100), broadcast))
+            .await
+            .unwrap();
+    }
+    clients[1]
+        .send(&make_packet(ip(101), ip(102)))
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    let top = |kind| {
+        let udp_server = udp_server.clone();
+        async move {
+            udp_server
+                .get_plugin::<TrafficPlugin, _, _>(|t| t.map(|t| t.top_talkers(kind, 10)))
+                .await
+                .unwrap()
+        }
+    };
+    let size = make_packet(ip(100), broadcast).len() as u64;
+
+    let ips = top(TalkerKind::VirtualIp).await;
+    assert_eq!(ips[0].key, "10.13.37.100");
+    assert_eq!(ips[0].total.download_packet.0, 6);
+    // the first broadcast had nobody to go to, the rest reached 2 clients
+    assert_eq!(ips[0].total.upload_packet.0, 5 * 2);
+    assert_eq!(ips[0].total.upload.0, 5 * 2 * size);
+
+    let cast = top(TalkerKind::Cast).await;
+    let unicast = cast.iter().find(|t| t.key == "unicast").unwrap();
+    assert_eq!(unicast.total.download_packet.0, 1);
+    assert_eq!(unicast.total.upload_packet.0, 1);
+    let broadcast = cast.iter().find(|t| t.key == "broadcast").unwrap();
+    assert_eq!(broadcast.total.download_packet.0, 8);
+
+    let peers = top(TalkerKind::Peer).await;
+    assert_eq!(peers.len(), 3);
+    let local = clients[2].local_addr().unwrap().port();
+    let peer = peers
+        .iter()
+        .find(|t| t.key.ends_with(&format!(":{}", local)))
+        .unwrap();
+    assert_eq!(peer.total.download_packet.0, 1);
+    assert_eq!(peer.total.upload_packet.0, 6);
+}
+
+#[tokio::test]
+async fn test_blocked_not_counted() {
+    use crate::plugin::{blocker::BlockerPlugin, register_plugins};
+    use crate::test::{client_connect, make_packet, make_server, make_tcp_packet};
+    use smoltcp::wire::Ipv4Address;
+    use tokio::time::{sleep, Duration};
+
+    let (udp_server, addr) = make_server().await;
+    register_plugins(&udp_server).await;
+    udp_server
+        .get_plugin::<BlockerPlugin, _, _>(|b| {
+            b.map(|b| b.set_block_rules(vec!["tcp:5000".parse().unwrap()]))
+        })
+        .await;
+
+    let client = client_connect(addr).await;
+    let (ip1, ip2) = (
+        Ipv4Address::new(10, 13, 37, 1),
+        Ipv4Address::new(10, 13, 37, 2),
+    );
+    for _ in 0..3 {
+        client.send(&make_tcp_packet(ip1, ip2, 5000)).await.unwrap();
+    }
+    client.send(&make_packet(ip1, ip2)).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    let info = udp_server
+        .get_plugin::<TrafficPlugin, _, _>(|t| t.cloned())
+        .await
+        .unwrap()
+        .traffic_info()
+        .await;
+    // the blocker runs first, so traffic only sees the allowed packet
+    assert_eq!(info.total_download_packets, BigInt(1));
+}
@@ -0,0 +1,197 @@
+//! Plain JSON endpoints for server lists and status pages.
+
+use crate::graphql::Ctx;
+use crate::plugin::ldn_mitm::LdnMitmPlugin;
+use axum::{
+    extract::{Path, Query},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Extension, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Rooms change at most once per scan, so clients may reuse a response this long
+const MAX_AGE: u32 = 5;
+
+#[derive(Debug, Deserialize)]
+pub struct RoomFilter {
+    content_id: Option<String>,
+}
+
+/// `/rooms` and `/rooms/:ip`. Expects a [`Ctx`] extension.
+pub fn router() -> Router {
+    Router::new()
+        .route("/rooms", get(rooms))
+        .route("/rooms/:ip", get(room))
+}
+
+async fn rooms(
+    Extension(ctx): Extension<Ctx>,
+    Query(filter): Query<RoomFilter>,
+    headers: HeaderMap,
+) -> Response {
+    let rooms = ctx
+        .udp_server
+        .get_plugin::<LdnMitmPlugin, _, _>(|ldn_mitm| {
+            ldn_mitm.map(|i| match &filter.content_id {
+                Some(content_id) => i.rooms_of(content_id),
+                None => i.rooms(),
+            })
+        })
+        .await;
+    match rooms {
+        Some(rooms) => cached_json(&rooms, &headers),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn room(
+    Extension(ctx): Extension<Ctx>,
+    Path(ip): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let room = ctx
+        .udp_server
+        .get_plugin::<LdnMitmPlugin, _, _>(|ldn_mitm| ldn_mitm.and_then(|i| i.room(&ip)))
+        .await;
+    match room {
+        Some(room) => cached_json(&room, &headers),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// JSON with an ETag of its body. Answers `304 Not Modified` when the
+/// client already has it.
+fn cached_json<T: Serialize>(value: &T, headers: &HeaderMap) -> Response {
+    let body = match serde_json::to_vec(value) {
+        Ok(body) => body,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    let etag = format!("\"{:016x}\"", hasher.finish());
+    let etag = HeaderValue::from_str(&etag).expect("etag is a valid header");
+    let cache_control = HeaderValue::from_str(&format!("public, max-age={}", MAX_AGE))
+        .expect("cache-control is a valid header");
+
+    let not_modified = headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|tag| tag.trim() == etag || tag.trim() == "*");
+    let caching = [(header::ETAG, etag), (header::CACHE_CONTROL, cache_control)];
+    if not_modified {
+        (StatusCode::NOT_MODIFIED, caching).into_response()
+    } else {
+        (
+            caching,
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            )],
+            body,
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::router;
+    use crate::graphql::Ctx;
+    use crate::plugin::ldn_mitm::lan_protocol::{LanMessage, NetworkInfo, NETWORK_INFO_SIZE};
+    use crate::plugin::ldn_mitm::{make_udp, LdnConfig, LdnMitmPlugin};
+    use crate::slp::encode;
+    use crate::test::{client_connect, make_server};
+    use axum::body::{to_bytes, Body};
+    use axum::http::{header, Request, StatusCode};
+    use axum::{Extension, Router};
+    use std::net::Ipv4Addr;
+    use tokio::time::{sleep, Duration};
+    use tower::ServiceExt;
+
+    async fn app_with_room() -> Router {
+        let (udp_server, addr) = make_server().await;
+        udp_server.add_plugin::<LdnMitmPlugin>().await;
+
+        let config = LdnConfig::default();
+        let mut info = vec![0u8; NETWORK_INFO_SIZE];
+        info[0..8].copy_from_slice(&0x0100152000022000u64.to_le_bytes());
+        let message = LanMessage::ScanResponse(NetworkInfo::new(info).unwrap());
+        let frame = encode::ipv4(&make_udp(
+            Ipv4Addr::new(10, 13, 37, 1),
+            config.server_addr,
+            config.port,
+            &message.emit(true),
+        ));
+        let host = client_connect(addr).await;
+        host.send(&frame).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+
+        router().layer(Extension(Ctx::new(udp_server, None)))
+    }
+
+    async fn get(
+        app: &Router,
+        uri: &str,
+        etag: Option<&str>,
+    ) -> (StatusCode, Option<String>, Vec<u8>) {
+        let mut request = Request::get(uri);
+        if let Some(etag) = etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        let response = app
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .map(|v| v.to_str().unwrap().to_string());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, etag, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_rooms() {
+        let app = app_with_room().await;
+
+        let (status, etag, body) = get(&app, "/rooms", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let rooms: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(rooms.as_array().unwrap().len(), 1);
+        assert_eq!(rooms[0]["ip"], "10.13.37.1");
+        assert_eq!(rooms[0]["content_id"], "0100152000022000");
+
+        let etag = etag.unwrap();
+        let (status, _, body) = get(&app, "/rooms", Some(&etag)).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+
+        let (_, _, body) = get(&app, "/rooms?content_id=0100152000022000", None).await;
+        let rooms: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(rooms.as_array().unwrap().len(), 1);
+        let (_, other_etag, body) = get(&app, "/rooms?content_id=01006a800016e000", None).await;
+        assert_eq!(body, b"[]");
+        assert_ne!(other_etag.unwrap(), etag);
+    }
+
+    #[tokio::test]
+    async fn test_room_by_ip() {
+        let app = app_with_room().await;
+
+        let (status, _, body) = get(&app, "/rooms/10.13.37.1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let room: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(room["ip"], "10.13.37.1");
+
+        let (status, _, _) = get(&app, "/rooms/10.13.37.2", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
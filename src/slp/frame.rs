@@ -1,8 +1,12 @@
 #![allow(dead_code)]
 use super::packet::{OutAddr, Packet};
+use crate::util::BigInt;
+use async_graphql::SimpleObject;
 use bytes::Buf;
 use lru::LruCache;
-use std::{net::Ipv4Addr, num::NonZeroUsize};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
 
 mod forwarder_type {
     pub const KEEPALIVE: u8 = 0;
@@ -27,6 +31,9 @@ mod field {
     pub const FRAG_DATA: FieldFrom = 16..;
 }
 
+/// A keepalive frame, sent by the server to keep NAT mappings open
+pub const KEEPALIVE_PACKET: &[u8] = &[forwarder_type::KEEPALIVE];
+
 #[derive(Debug, Clone, Copy)]
 pub enum ParseError {
     NotParseable,
@@ -77,7 +84,7 @@ pub enum ForwarderFrame<'a> {
 
 impl<'a> Parser<'a> for ForwarderFrame<'a> {
     const MIN_LENGTH: usize = 1;
-    fn do_parse(bytes: &'a [u8]) -> Result<ForwarderFrame> {
+    fn do_parse(bytes: &'a [u8]) -> Result<ForwarderFrame<'a>> {
         let typ = bytes[0];
         let rest = &bytes[1..];
         let frame = match typ {
@@ -106,7 +113,7 @@ impl<'a> From<Ipv4<'a>> for OutAddr {
 
 impl<'a> Parser<'a> for Ipv4<'a> {
     const MIN_LENGTH: usize = 20;
-    fn do_parse(bytes: &'a [u8]) -> Result<Ipv4> {
+    fn do_parse(bytes: &'a [u8]) -> Result<Ipv4<'a>> {
         Ok(Ipv4 { payload: bytes })
     }
 }
@@ -122,7 +129,7 @@ impl<'a> Ipv4<'a> {
         octets.copy_from_slice(&self.payload[field::DST_IP]);
         octets.into()
     }
-    pub fn data(&self) -> &[u8] {
+    pub fn data(&self) -> &'a [u8] {
         self.payload
     }
 }
@@ -140,7 +147,7 @@ impl<'a> From<Ipv4Frag<'a>> for OutAddr {
 
 impl<'a> Parser<'a> for Ipv4Frag<'a> {
     const MIN_LENGTH: usize = 16;
-    fn do_parse(bytes: &'a [u8]) -> Result<Ipv4Frag> {
+    fn do_parse(bytes: &'a [u8]) -> Result<Ipv4Frag<'a>> {
         Ok(Ipv4Frag { payload: bytes })
     }
 }
@@ -177,6 +184,27 @@ impl<'a> Ipv4Frag<'a> {
     pub fn data(&self) -> &[u8] {
         &self.payload[field::FRAG_DATA]
     }
+    pub fn header(&self) -> FragHeader {
+        FragHeader {
+            src_ip: self.src_ip(),
+            dst_ip: self.dst_ip(),
+            id: self.id(),
+            part: self.part(),
+            total_part: self.total_part(),
+            pmtu: self.pmtu(),
+        }
+    }
+}
+
+/// The fields of an `Ipv4Frag` frame but its data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragHeader {
+    pub src_ip: Ipv4Addr,
+    pub dst_ip: Ipv4Addr,
+    pub id: u16,
+    pub part: u8,
+    pub total_part: u8,
+    pub pmtu: u16,
 }
 
 #[derive(Debug)]
@@ -187,49 +215,163 @@ pub struct Ping<'a> {
 impl<'a> Parser<'a> for Ping<'a> {
     const MIN_LENGTH: usize = 4;
     const MAX_LENGTH: usize = 4;
-    fn do_parse(bytes: &'a [u8]) -> Result<Ping> {
+    fn do_parse(bytes: &'a [u8]) -> Result<Ping<'a>> {
         Ok(Ping { payload: bytes })
     }
 }
 
 impl<'a> Ping<'a> {
+    pub fn payload(&self) -> [u8; 4] {
+        let mut payload = [0u8; 4];
+        payload.copy_from_slice(&self.payload[0..4]);
+        payload
+    }
+    /// The reply, which echoes the payload
     pub fn build(&self) -> Vec<u8> {
-        let mut out = vec![forwarder_type::PING];
-        out.extend_from_slice(&self.payload[0..4]);
-        out
+        encode::ping(self.payload())
     }
 }
 
-#[derive(Debug, Clone)]
-struct FragItem {
-    src_ip: Ipv4Addr,
-    dst_ip: Ipv4Addr,
-    id: u16,
-    part: u8,
+/// Encoders for every frame type, the inverse of [`ForwarderFrame::parse`]
+pub mod encode {
+    use super::{field, forwarder_type, FragHeader, Packet};
+
+    pub fn keepalive() -> Packet {
+        vec![forwarder_type::KEEPALIVE]
+    }
+    /// A frame carrying `packet`, a whole IPv4 packet
+    pub fn ipv4(packet: &[u8]) -> Packet {
+        let mut frame = Vec::with_capacity(1 + packet.len());
+        frame.push(forwarder_type::IPV4);
+        frame.extend_from_slice(packet);
+        frame
+    }
+    pub fn ping(payload: [u8; 4]) -> Packet {
+        let mut frame = vec![forwarder_type::PING];
+        frame.extend_from_slice(&payload);
+        frame
+    }
+    /// A frame carrying one part of an IPv4 packet. The length field is
+    /// taken from `data`.
+    pub fn ipv4_frag(header: &FragHeader, data: &[u8]) -> Packet {
+        let mut frame = Vec::with_capacity(1 + field::FRAG_DATA.start + data.len());
+        frame.push(forwarder_type::IPV4_FRAG);
+        frame.extend_from_slice(&header.src_ip.octets());
+        frame.extend_from_slice(&header.dst_ip.octets());
+        frame.extend_from_slice(&header.id.to_be_bytes());
+        frame.extend_from_slice(&[header.part, header.total_part]);
+        frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&header.pmtu.to_be_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+    pub fn auth_me() -> Packet {
+        vec![forwarder_type::AUTH_ME]
+    }
+    pub fn info() -> Packet {
+        vec![forwarder_type::INFO]
+    }
+}
+
+/// Largest packet reassembly produces, the IPv4 total length limit
+pub const MAX_REASSEMBLED_SIZE: usize = 65535;
+/// Smallest path MTU a peer may report: what is left of the 576 byte
+/// datagram every IPv4 host accepts after the IP, UDP and fragment headers.
+pub const MIN_PMTU: u16 = 576 - 20 - 8 - 1 - field::FRAG_DATA.start as u16;
+/// Largest path MTU whose frames, whole or fragmented, still parse
+pub const MAX_PMTU: u16 =
+    (<ForwarderFrame<'static> as Parser<'static>>::MAX_LENGTH - 1 - field::FRAG_DATA.start) as u16;
+/// Partial packets kept at once
+const FRAG_CACHE_SIZE: usize = 50;
+/// Partial packets one peer may keep at once, so a single client can't
+/// evict the partial packets of everyone else
+const FRAG_CACHE_PER_PEER: usize = 8;
+/// How long a partial packet waits for its missing parts
+const FRAG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counters of [`FragParser`]
+#[derive(SimpleObject, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FragStats {
+    /// Packets reassembled
+    pub completed: BigInt,
+    /// Fragments rejected for bad or inconsistent fields
+    pub invalid: BigInt,
+    /// Partial packets dropped after waiting too long
+    pub timed_out: BigInt,
+    /// Partial packets dropped to make room for new ones
+    pub evicted: BigInt,
+}
+
+impl std::ops::AddAssign for FragStats {
+    fn add_assign(&mut self, other: Self) {
+        self.completed.0 += other.completed.0;
+        self.invalid.0 += other.invalid.0;
+        self.timed_out.0 += other.timed_out.0;
+        self.evicted.0 += other.evicted.0;
+    }
+}
+
+struct FragEntry {
     total_part: u8,
-    len: u16,
     pmtu: u16,
-    data: Packet,
+    dst_ip: Ipv4Addr,
+    parts: Vec<Option<Packet>>,
+    received: usize,
+    updated: Instant,
 }
 
-impl FragItem {
-    fn from_frame(frag: Ipv4Frag<'_>) -> Self {
-        FragItem {
-            src_ip: frag.src_ip(),
-            dst_ip: frag.dst_ip(),
-            id: frag.id(),
-            part: frag.part(),
+impl FragEntry {
+    fn new(frag: &Ipv4Frag<'_>, now: Instant) -> Self {
+        FragEntry {
             total_part: frag.total_part(),
-            len: frag.len(),
             pmtu: frag.pmtu(),
-            data: Vec::from(frag.data()),
+            dst_ip: frag.dst_ip(),
+            parts: vec![None; frag.total_part() as usize],
+            received: 0,
+            updated: now,
         }
     }
+    fn matches(&self, frag: &Ipv4Frag<'_>) -> bool {
+        self.total_part == frag.total_part()
+            && self.pmtu == frag.pmtu()
+            && self.dst_ip == frag.dst_ip()
+    }
+    fn assemble(self) -> Packet {
+        let size = self.parts.iter().flatten().map(|p| p.len()).sum();
+        let mut packet = Vec::with_capacity(size);
+        for part in self.parts.into_iter().flatten() {
+            packet.extend_from_slice(&part);
+        }
+        packet
+    }
+}
+
+/// Whether the fields of a fragment agree with each other: every part but
+/// the last fills the pmtu, and the whole packet fits in an IPv4 packet.
+fn is_valid(frag: &Ipv4Frag<'_>) -> bool {
+    let (part, total_part) = (frag.part() as usize, frag.total_part() as usize);
+    let (len, pmtu) = (frag.len() as usize, frag.pmtu() as usize);
+    let is_last = part + 1 == total_part;
+    // the last part is at least a byte long
+    let min_last = if is_last { len } else { 1 };
+    part < total_part
+        && pmtu > 0
+        && frag.data().len() == len
+        && if is_last {
+            len > 0 && len <= pmtu
+        } else {
+            len == pmtu
+        }
+        && pmtu * (total_part - 1) + min_last <= MAX_REASSEMBLED_SIZE
 }
 
-type FragParserKey = (Ipv4Addr, u16);
+/// Partial packets are keyed by the sending peer as well as the LAN source,
+/// so one client cannot complete or spoil the packets of another.
+type FragParserKey = (SocketAddr, Ipv4Addr, u16);
 pub struct FragParser {
-    cache: LruCache<FragParserKey, Vec<Option<FragItem>>>,
+    cache: LruCache<FragParserKey, FragEntry>,
+    timeout: Duration,
+    stats: FragStats,
 }
 
 impl Default for FragParser {
@@ -241,54 +383,172 @@ impl Default for FragParser {
 impl FragParser {
     pub fn new() -> Self {
         Self {
-            cache: LruCache::new(NonZeroUsize::new(50).unwrap()),
+            cache: LruCache::new(NonZeroUsize::new(FRAG_CACHE_SIZE).unwrap()),
+            timeout: FRAG_TIMEOUT,
+            stats: FragStats::default(),
         }
     }
-    pub fn process(&mut self, frame: Ipv4Frag<'_>) -> Option<Packet> {
-        let src_ip = frame.src_ip();
-        let item = FragItem::from_frame(frame);
-        let key = (src_ip, item.id);
-        let list = if let Some(list) = self.cache.get_mut(&key) {
-            list
-        } else {
-            let mut vec = Vec::new();
-            vec.resize(item.total_part as usize, None);
-            self.cache.put(key, vec);
-            self.cache.get_mut(&key).unwrap()
-        };
-        let part = item.part as usize;
-        match list.get_mut(part) {
-            Some(v) => *v = Some(item),
-            None => return None,
-        };
-        if list.iter().all(|i| i.is_some()) {
-            let list: Vec<_> = self
-                .cache
-                .pop(&key)
-                .unwrap()
-                .into_iter()
-                .map(Option::unwrap)
-                .collect();
-            let size: usize = list.iter().fold(0, |acc, item| acc + item.len as usize);
-            let mut packet = vec![0u8; size];
-            for i in list {
-                let start = i.pmtu as usize * i.part as usize;
-                let end = start + i.len as usize;
-                match packet.get_mut(start..end) {
-                    Some(s) => s.copy_from_slice(&i.data),
-                    None => return None,
+    pub fn stats(&self) -> FragStats {
+        self.stats
+    }
+    pub fn process(&mut self, peer: SocketAddr, frame: &Ipv4Frag<'_>) -> Option<Packet> {
+        self.process_at(peer, frame, Instant::now())
+    }
+    pub fn process_at(
+        &mut self,
+        peer: SocketAddr,
+        frame: &Ipv4Frag<'_>,
+        now: Instant,
+    ) -> Option<Packet> {
+        self.expire(now);
+        if !is_valid(frame) {
+            self.stats.invalid.0 += 1;
+            return None;
+        }
+        if frame.total_part() == 1 {
+            self.stats.completed.0 += 1;
+            return Some(frame.data().to_vec());
+        }
+        let key = (peer, frame.src_ip(), frame.id());
+        let entry = match self.cache.get_mut(&key) {
+            Some(entry) if entry.matches(frame) => entry,
+            Some(_) => {
+                // A new packet reusing the id, or garbage. Either way the
+                // old parts cannot be trusted.
+                self.cache.pop(&key);
+                self.stats.invalid.0 += 1;
+                return None;
+            }
+            None => {
+                // oldest first
+                let own: Vec<_> = self
+                    .cache
+                    .iter()
+                    .rev()
+                    .map(|(key, _)| *key)
+                    .filter(|key| key.0 == peer)
+                    .collect();
+                if own.len() >= FRAG_CACHE_PER_PEER {
+                    self.cache.pop(&own[0]);
+                    self.stats.evicted.0 += 1;
+                } else if self.cache.len() == self.cache.cap().get() {
+                    self.stats.evicted.0 += 1;
                 }
+                self.cache.put(key, FragEntry::new(frame, now));
+                self.cache.get_mut(&key).unwrap()
             }
-            Some(packet)
+        };
+        let slot = &mut entry.parts[frame.part() as usize];
+        if slot.is_none() {
+            entry.received += 1;
+        }
+        *slot = Some(frame.data().to_vec());
+        entry.updated = now;
+
+        if entry.received == entry.total_part as usize {
+            self.stats.completed.0 += 1;
+            self.cache.pop(&key).map(FragEntry::assemble)
         } else {
             None
         }
     }
+    /// Drops partial packets that have waited too long. The least recently
+    /// used entry is also the one updated longest ago.
+    fn expire(&mut self, now: Instant) {
+        while let Some((_, entry)) = self.cache.peek_lru() {
+            if now.saturating_duration_since(entry.updated) < self.timeout {
+                break;
+            }
+            self.cache.pop_lru();
+            self.stats.timed_out.0 += 1;
+        }
+    }
+}
+
+/// The inverse of [`FragParser`]: splits IPv4 packets into `Ipv4Frag`
+/// frames no longer than a peer's path MTU.
+#[derive(Debug, Default)]
+pub struct Fragmenter {
+    next_id: u16,
+}
+
+impl Fragmenter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Frames carrying `packet`, a whole IPv4 packet, with at most `pmtu`
+    /// bytes of it in each. A packet that fits is sent as a single `Ipv4`
+    /// frame. `None` when the packet is malformed or needs more than 255
+    /// parts.
+    pub fn fragment(&mut self, packet: &[u8], pmtu: u16) -> Option<Vec<Packet>> {
+        if packet.len() < Ipv4::MIN_LENGTH || packet.len() > MAX_REASSEMBLED_SIZE || pmtu == 0 {
+            return None;
+        }
+        // not `Ipv4::parse`, which is limited to what fits in one frame
+        let ipv4 = Ipv4 { payload: packet };
+        if packet.len() <= pmtu as usize {
+            return Some(vec![encode::ipv4(packet)]);
+        }
+        let total_part = packet.len().div_ceil(pmtu as usize);
+        if total_part > u8::MAX as usize {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let frames = packet
+            .chunks(pmtu as usize)
+            .enumerate()
+            .map(|(part, data)| {
+                let header = FragHeader {
+                    src_ip: ipv4.src_ip(),
+                    dst_ip: ipv4.dst_ip(),
+                    id,
+                    part: part as u8,
+                    total_part: total_part as u8,
+                    pmtu,
+                };
+                encode::ipv4_frag(&header, data)
+            })
+            .collect();
+        Some(frames)
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{FragParser, Ipv4Frag, Parser};
+    use super::{
+        encode, ForwarderFrame, FragHeader, FragParser, FragStats, Fragmenter, Ipv4Frag, Parser,
+        FRAG_CACHE_PER_PEER, FRAG_CACHE_SIZE, FRAG_TIMEOUT, MAX_REASSEMBLED_SIZE,
+    };
+    use crate::util::BigInt;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::{Duration, Instant};
+
+    const PEER: &str = "127.0.0.1:10000";
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// Fragment `part` of `total` carrying `data`
+    fn frag(src: u8, id: u16, part: u8, total_part: u8, pmtu: u16, data: &[u8]) -> Vec<u8> {
+        let header = FragHeader {
+            src_ip: Ipv4Addr::new(10, 13, 37, src),
+            dst_ip: Ipv4Addr::new(10, 13, 37, 1),
+            id,
+            part,
+            total_part,
+            pmtu,
+        };
+        encode::ipv4_frag(&header, data)[1..].to_vec()
+    }
+
+    fn process(parser: &mut FragParser, peer: SocketAddr, bytes: &[u8]) -> Option<Vec<u8>> {
+        parser.process(peer, &Ipv4Frag::parse(bytes).unwrap())
+    }
 
     #[tokio::test]
     async fn frag_parser() {
@@ -337,10 +597,11 @@ mod test {
             3, 4, // data
         ])
         .unwrap();
-        assert_eq!(parser.process(frag1), None);
-        assert_eq!(parser.process(frag2), None);
-        assert_eq!(parser.process(frag3).unwrap(), vec![0, 1, 2, 3, 4]);
-        assert_eq!(parser.process(frag4).unwrap(), vec![0, 1, 2, 3, 4]);
+        let peer = PEER.parse().unwrap();
+        assert_eq!(parser.process(peer, &frag1), None);
+        assert_eq!(parser.process(peer, &frag2), None);
+        assert_eq!(parser.process(peer, &frag3).unwrap(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(parser.process(peer, &frag4).unwrap(), vec![0, 1, 2, 3, 4]);
     }
 
     #[tokio::test]
@@ -379,8 +640,335 @@ mod test {
             3, 4, // data
         ])
         .unwrap();
-        assert_eq!(parser.process(frag1), None);
-        assert_eq!(parser.process(frag2), None);
-        assert_eq!(parser.process(frag3), None);
+        let peer = PEER.parse().unwrap();
+        assert_eq!(parser.process(peer, &frag1), None);
+        assert_eq!(parser.process(peer, &frag2), None);
+        assert_eq!(parser.process(peer, &frag3), None);
+    }
+
+    #[test]
+    fn frag_parser_rejects_inconsistent_fields() {
+        let mut parser = FragParser::new();
+        let p = peer(1);
+        // declared len differs from the data
+        let mut bytes = frag(100, 1, 0, 2, 4, &[0, 1, 2, 3]);
+        bytes[13] = 3;
+        assert_eq!(process(&mut parser, p, &bytes), None);
+        // a middle part shorter than the pmtu
+        assert_eq!(
+            process(&mut parser, p, &frag(100, 2, 0, 2, 4, &[0, 1])),
+            None
+        );
+        // a last part longer than the pmtu
+        assert_eq!(
+            process(&mut parser, p, &frag(100, 3, 1, 2, 2, &[0, 1, 2])),
+            None
+        );
+        // zero pmtu
+        assert_eq!(process(&mut parser, p, &frag(100, 4, 0, 1, 0, &[])), None);
+        // larger than an IPv4 packet
+        let data = vec![0u8; 1024];
+        assert_eq!(
+            process(&mut parser, p, &frag(100, 5, 0, 255, 1024, &data)),
+            None
+        );
+        assert_eq!(parser.stats().invalid, BigInt(5));
+        assert_eq!(parser.cache.len(), 0);
+
+        // the largest packet that fits is accepted
+        let data = vec![7u8; 1000];
+        for part in 0..65 {
+            let bytes = frag(100, 6, part, 66, 1000, &data);
+            assert_eq!(process(&mut parser, p, &bytes), None);
+        }
+        let packet = process(&mut parser, p, &frag(100, 6, 65, 66, 1000, &data[..535])).unwrap();
+        assert_eq!(packet.len(), 65535);
+    }
+
+    #[test]
+    fn frag_parser_keys_by_peer() {
+        let mut parser = FragParser::new();
+        assert_eq!(
+            process(&mut parser, peer(1), &frag(100, 1, 0, 2, 2, &[0, 1])),
+            None
+        );
+        // another client claiming the same LAN address cannot complete it
+        assert_eq!(
+            process(&mut parser, peer(2), &frag(100, 1, 1, 2, 2, &[9])),
+            None
+        );
+        assert_eq!(
+            process(&mut parser, peer(1), &frag(100, 1, 1, 2, 2, &[2])).unwrap(),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn frag_parser_drops_reused_id() {
+        let mut parser = FragParser::new();
+        let p = peer(1);
+        assert_eq!(
+            process(&mut parser, p, &frag(100, 1, 0, 3, 2, &[0, 1])),
+            None
+        );
+        assert_eq!(process(&mut parser, p, &frag(100, 1, 1, 2, 2, &[2])), None);
+        assert_eq!(parser.stats().invalid, BigInt(1));
+        // the next packet with the id starts afresh
+        assert_eq!(
+            process(&mut parser, p, &frag(100, 1, 0, 2, 2, &[0, 1])),
+            None
+        );
+        assert_eq!(
+            process(&mut parser, p, &frag(100, 1, 1, 2, 2, &[2])).unwrap(),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn frag_parser_times_out() {
+        let mut parser = FragParser::new();
+        let p = peer(1);
+        let start = Instant::now();
+        let first = frag(100, 1, 0, 2, 2, &[0, 1]);
+        let second = frag(100, 1, 1, 2, 2, &[2]);
+        let parse = |bytes| Ipv4Frag::parse(bytes).unwrap();
+
+        assert_eq!(parser.process_at(p, &parse(&first), start), None);
+        let late = start + FRAG_TIMEOUT;
+        assert_eq!(parser.process_at(p, &parse(&second), late), None);
+        assert_eq!(parser.stats().timed_out, BigInt(1));
+
+        // the late part starts a new entry, which the first part completes
+        let in_time = late + FRAG_TIMEOUT - Duration::from_millis(1);
+        assert_eq!(
+            parser.process_at(p, &parse(&first), in_time).unwrap(),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn frag_parser_stats() {
+        let mut parser = FragParser::new();
+        for id in 0..FRAG_CACHE_SIZE as u16 + 3 {
+            process(&mut parser, peer(id), &frag(100, id, 0, 2, 2, &[0, 1]));
+        }
+        process(&mut parser, peer(1), &frag(100, 0, 0, 1, 2, &[0, 1]));
+        assert_eq!(
+            parser.stats(),
+            FragStats {
+                completed: BigInt(1),
+                invalid: BigInt(0),
+                timed_out: BigInt(0),
+                evicted: BigInt(3),
+            }
+        );
+    }
+
+    #[test]
+    fn frag_parser_per_peer_cap() {
+        let mut parser = FragParser::new();
+        let (flooder, victim) = (peer(1), peer(2));
+        assert_eq!(
+            process(&mut parser, victim, &frag(101, 0, 0, 2, 2, &[0, 1])),
+            None
+        );
+        for id in 0..FRAG_CACHE_SIZE as u16 {
+            process(&mut parser, flooder, &frag(100, id, 0, 2, 2, &[0, 1]));
+        }
+        assert_eq!(
+            parser.stats().evicted,
+            BigInt((FRAG_CACHE_SIZE - FRAG_CACHE_PER_PEER) as u64)
+        );
+        // only the flooder's own oldest packets were dropped
+        assert_eq!(
+            process(&mut parser, victim, &frag(101, 0, 1, 2, 2, &[2, 3])),
+            Some(vec![0, 1, 2, 3])
+        );
+        let last = FRAG_CACHE_SIZE as u16 - 1;
+        assert_eq!(
+            process(&mut parser, flooder, &frag(100, last, 1, 2, 2, &[2, 3])),
+            Some(vec![0, 1, 2, 3])
+        );
+        assert_eq!(
+            process(&mut parser, flooder, &frag(100, 0, 1, 2, 2, &[2, 3])),
+            None
+        );
+    }
+
+    /// An IPv4 packet of `len` bytes from 10.13.37.100 to 10.13.37.1
+    fn ipv4_packet(len: usize) -> Vec<u8> {
+        let mut packet: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        packet[0] = 0x45;
+        packet[12..16].copy_from_slice(&[10, 13, 37, 100]);
+        packet[16..20].copy_from_slice(&[10, 13, 37, 1]);
+        packet
+    }
+
+    /// Fragments `packet` and feeds the frames back through a `FragParser`
+    fn round_trip(packet: &[u8], pmtu: u16) -> Vec<u8> {
+        let frames = Fragmenter::new().fragment(packet, pmtu).unwrap();
+        let mut parser = FragParser::new();
+        let peer = PEER.parse().unwrap();
+        let mut reassembled = vec![];
+        for (i, frame) in frames.iter().enumerate() {
+            let data = match ForwarderFrame::parse(frame).unwrap() {
+                ForwarderFrame::Ipv4(ipv4) => Some(ipv4.data().to_vec()),
+                ForwarderFrame::Ipv4Frag(frag) => {
+                    assert!(frag.len() <= pmtu);
+                    parser.process(peer, &frag)
+                }
+                _ => panic!("unexpected frame"),
+            };
+            assert_eq!(data.is_some(), i + 1 == frames.len());
+            reassembled.extend(data.unwrap_or_default());
+        }
+        assert_eq!(parser.stats().invalid, BigInt(0));
+        reassembled
+    }
+
+    #[test]
+    fn fragmenter_round_trip() {
+        for &len in &[20, 100, 1299, 1300, 1301, 4000, MAX_REASSEMBLED_SIZE] {
+            for &pmtu in &[300, 1300, 1500] {
+                let packet = ipv4_packet(len);
+                assert_eq!(round_trip(&packet, pmtu), packet, "{} {}", len, pmtu);
+            }
+        }
+    }
+
+    #[test]
+    fn fragmenter_frames() {
+        let mut fragmenter = Fragmenter::new();
+        let packet = ipv4_packet(100);
+        let whole = fragmenter.fragment(&packet, 100).unwrap();
+        assert_eq!(whole.len(), 1);
+        assert_eq!(whole[0][0], 1);
+        assert_eq!(&whole[0][1..], &packet[..]);
+
+        let first = fragmenter.fragment(&packet, 40).unwrap();
+        let second = fragmenter.fragment(&packet, 40).unwrap();
+        assert_eq!(first.len(), 3);
+        let frag = Ipv4Frag::parse(&first[2][1..]).unwrap();
+        assert_eq!(frag.src_ip(), Ipv4Addr::new(10, 13, 37, 100));
+        assert_eq!(frag.dst_ip(), Ipv4Addr::new(10, 13, 37, 1));
+        assert_eq!((frag.part(), frag.total_part()), (2, 3));
+        assert_eq!((frag.len(), frag.pmtu()), (20, 40));
+        let id = Ipv4Frag::parse(&second[0][1..]).unwrap().id();
+        assert_ne!(frag.id(), id);
+    }
+
+    #[test]
+    fn fragmenter_refuses() {
+        let mut fragmenter = Fragmenter::new();
+        // not an IPv4 packet
+        assert_eq!(fragmenter.fragment(&[0; 10], 1300), None);
+        assert_eq!(fragmenter.fragment(&ipv4_packet(100), 0), None);
+        // more than 255 parts
+        assert_eq!(fragmenter.fragment(&ipv4_packet(2560), 10), None);
+        assert_eq!(
+            fragmenter.fragment(&ipv4_packet(2550), 10).unwrap().len(),
+            255
+        );
+    }
+
+    fn random_header(rng: &mut StdRng) -> FragHeader {
+        FragHeader {
+            src_ip: rng.gen::<[u8; 4]>().into(),
+            dst_ip: rng.gen::<[u8; 4]>().into(),
+            id: rng.gen(),
+            part: rng.gen(),
+            total_part: rng.gen(),
+            pmtu: rng.gen(),
+        }
+    }
+
+    fn random_bytes(rng: &mut StdRng, max_len: usize) -> Vec<u8> {
+        let len = rng.gen_range(0..=max_len);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0x51a9);
+        for _ in 0..1000 {
+            let header = random_header(&mut rng);
+            let data = random_bytes(&mut rng, 2000);
+            let frame = encode::ipv4_frag(&header, &data);
+            match ForwarderFrame::parse(&frame).unwrap() {
+                ForwarderFrame::Ipv4Frag(frag) => {
+                    assert_eq!(frag.header(), header);
+                    assert_eq!(frag.len() as usize, data.len());
+                    assert_eq!(frag.data(), &data[..]);
+                    assert_eq!(encode::ipv4_frag(&frag.header(), frag.data()), frame);
+                }
+                f => panic!("unexpected {:?}", f),
+            }
+
+            let packet = random_bytes(&mut rng, 2000);
+            let frame = encode::ipv4(&packet);
+            match ForwarderFrame::parse(&frame) {
+                Ok(ForwarderFrame::Ipv4(ipv4)) => {
+                    assert_eq!(ipv4.data(), &packet[..]);
+                    assert_eq!(encode::ipv4(ipv4.data()), frame);
+                }
+                // too short for an IPv4 header
+                Err(_) => assert!(packet.len() < 20),
+                Ok(f) => panic!("unexpected {:?}", f),
+            }
+
+            let payload: [u8; 4] = rng.gen();
+            match ForwarderFrame::parse(&encode::ping(payload)).unwrap() {
+                ForwarderFrame::Ping(ping) => {
+                    assert_eq!(ping.payload(), payload);
+                    assert_eq!(ping.build(), encode::ping(payload));
+                }
+                f => panic!("unexpected {:?}", f),
+            }
+        }
+    }
+
+    #[test]
+    fn encode_bare_frames() {
+        let frame = encode::keepalive();
+        assert!(matches!(
+            ForwarderFrame::parse(&frame),
+            Ok(ForwarderFrame::Keepalive)
+        ));
+        assert_eq!(frame, super::KEEPALIVE_PACKET);
+        let frame = encode::auth_me();
+        assert!(matches!(
+            ForwarderFrame::parse(&frame),
+            Ok(ForwarderFrame::AuthMe)
+        ));
+        let frame = encode::info();
+        assert!(matches!(
+            ForwarderFrame::parse(&frame),
+            Ok(ForwarderFrame::Info)
+        ));
+    }
+
+    #[test]
+    fn parse_random_bytes() {
+        let mut rng = StdRng::seed_from_u64(0xf2a9);
+        for _ in 0..10000 {
+            let mut bytes = random_bytes(&mut rng, 64);
+            if let Some(typ) = bytes.first_mut() {
+                *typ = [0, 1, 2, 3, 4, 0x10][rng.gen_range(0..6)];
+            }
+            // frames that parse are emitted back unchanged
+            let emitted = match ForwarderFrame::parse(&bytes) {
+                Ok(ForwarderFrame::Keepalive) => encode::keepalive(),
+                Ok(ForwarderFrame::AuthMe) => encode::auth_me(),
+                Ok(ForwarderFrame::Info) => encode::info(),
+                Ok(ForwarderFrame::Ping(ping)) => encode::ping(ping.payload()),
+                Ok(ForwarderFrame::Ipv4(ipv4)) => encode::ipv4(ipv4.data()),
+                Ok(ForwarderFrame::Ipv4Frag(frag)) if frag.len() as usize == frag.data().len() => {
+                    encode::ipv4_frag(&frag.header(), frag.data())
+                }
+                _ => continue,
+            };
+            // bare frames ignore anything after the type
+            assert_eq!(emitted, &bytes[..emitted.len()]);
+        }
     }
 }
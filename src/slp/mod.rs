@@ -3,21 +3,30 @@ pub(crate) mod packet;
 pub(crate) mod peer;
 pub(crate) mod peer_manager;
 pub mod plugin;
+pub(crate) mod queue;
 pub(crate) mod server;
 pub(crate) mod stream;
 
-pub use frame::{ForwarderFrame, FragParser, Parser};
-pub use packet::{InPacket, OutAddr, OutPacket, Packet};
-pub use peer::{Peer, PeerState};
-pub use peer_manager::{PeerManager, PeerManagerInfo};
-pub use plugin::BoxPlugin;
+pub use frame::{
+    encode, ForwarderFrame, FragHeader, FragParser, FragStats, Fragmenter, Parser,
+    MAX_REASSEMBLED_SIZE,
+};
+pub use packet::{InFrame, InPacket, LanPacket, OutAddr, OutPacket, Packet};
+pub use peer::{Peer, PeerConfig, PeerState};
+pub use peer_manager::{
+    DropInfo, PeerEvent, PeerEventKind, PeerInfo, PeerManager, PeerManagerInfo,
+};
+pub use plugin::{BoxPlugin, SharedPlugin};
+pub use queue::DropPolicy;
 pub use server::{ServerInfo, UDPServer, UDPServerBuilder};
 pub use std::net::SocketAddr;
 
 #[derive(Debug)]
 pub enum Event {
-    Close(SocketAddr),
     SendLAN(SocketAddr, OutPacket),
+    /// A whole IPv4 packet generated by the server, fragmented to each
+    /// peer's path MTU
+    SendIpv4(Packet, Vec<SocketAddr>),
 }
 
 pub fn log_err<T, E: std::fmt::Debug>(result: std::result::Result<T, E>, msg: &str) {
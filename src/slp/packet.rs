@@ -1,3 +1,5 @@
+use super::frame::ForwarderFrame;
+use std::borrow::Cow;
 pub use std::net::{Ipv4Addr, SocketAddr};
 
 #[derive(Debug, Clone)]
@@ -44,6 +46,69 @@ impl AsRef<[u8]> for InPacket {
     }
 }
 
+/// An IPv4 packet from a client's LAN, put back together when it was sent
+/// in fragments
+#[derive(Debug)]
+pub struct LanPacket<'a> {
+    src_ip: Ipv4Addr,
+    dst_ip: Ipv4Addr,
+    data: Cow<'a, [u8]>,
+}
+
+impl<'a> LanPacket<'a> {
+    pub fn new(src_ip: Ipv4Addr, dst_ip: Ipv4Addr, data: impl Into<Cow<'a, [u8]>>) -> Self {
+        LanPacket {
+            src_ip,
+            dst_ip,
+            data: data.into(),
+        }
+    }
+    pub fn src_ip(&self) -> Ipv4Addr {
+        self.src_ip
+    }
+    pub fn dst_ip(&self) -> Ipv4Addr {
+        self.dst_ip
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// A packet from a client as plugins see it: the raw frame, parsed once by
+/// the server.
+#[derive(Debug)]
+pub struct InFrame<'a> {
+    packet: &'a InPacket,
+    frame: Option<ForwarderFrame<'a>>,
+    lan: Option<LanPacket<'a>>,
+}
+
+impl<'a> InFrame<'a> {
+    pub fn new(
+        packet: &'a InPacket,
+        frame: Option<ForwarderFrame<'a>>,
+        lan: Option<LanPacket<'a>>,
+    ) -> Self {
+        InFrame { packet, frame, lan }
+    }
+    /// The frame as received
+    pub fn packet(&self) -> &InPacket {
+        self.packet
+    }
+    pub fn addr(&self) -> &SocketAddr {
+        self.packet.addr()
+    }
+    /// `None` when the frame is malformed
+    pub fn frame(&self) -> Option<&ForwarderFrame<'a>> {
+        self.frame.as_ref()
+    }
+    /// The LAN packet carried by an `Ipv4` frame, or completed by this
+    /// `Ipv4Frag` frame
+    pub fn lan(&self) -> Option<&LanPacket<'a>> {
+        self.lan.as_ref()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct OutPacket(Packet, OutAddr);
 
@@ -1,97 +1,161 @@
-use super::frame::{ForwarderFrame, Parser};
-use super::{log_err, Event, InPacket, OutPacket};
+use super::frame::{ForwarderFrame, Parser, MAX_PMTU, MIN_PMTU};
+use super::queue::{DropPolicy, Push, Queue};
+use super::{Event, InPacket, OutPacket};
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc;
-use tokio::time::{timeout, Instant};
+use tokio::time::Instant;
 
-const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
-
-#[derive(Debug, PartialEq)]
+/// Connected → Idle → Gone, driven by [`Peer::tick`]. Any LAN packet brings
+/// an idle peer back to Connected.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PeerState {
-    Connected(Instant),
+    /// Sending LAN packets
+    Connected,
+    /// No LAN packets for the idle timeout
     Idle,
+    /// Nothing at all for the timeout, about to be removed
+    Gone,
 }
 
 impl PeerState {
     pub fn is_connected(&self) -> bool {
-        matches!(self, &PeerState::Connected(_))
+        matches!(self, &PeerState::Connected)
     }
     pub fn is_idle(&self) -> bool {
         matches!(self, &PeerState::Idle)
     }
 }
 
+/// Settings shared by every peer
+#[derive(Debug, Clone)]
+pub struct PeerConfig {
+    /// Packets queued per peer before `drop_policy` applies
+    pub queue_size: usize,
+    pub drop_policy: DropPolicy,
+    /// A peer sending nothing for this long is disconnected
+    pub timeout: Duration,
+    /// A peer sending no LAN packets for this long is idle
+    pub idle_timeout: Duration,
+    /// Largest packet sent to a peer in one frame, until it reports its own.
+    /// Between [`MIN_PMTU`] and [`MAX_PMTU`].
+    pub pmtu: u16,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        Self {
+            queue_size: 10,
+            drop_policy: DropPolicy::DropNewest,
+            timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(5 * 60),
+            pmtu: 1300,
+        }
+    }
+}
+
+impl PeerConfig {
+    /// How often the peer states should be checked
+    pub fn sweep_interval(&self) -> Duration {
+        let shortest = self.timeout.min(self.idle_timeout);
+        (shortest / 10).clamp(Duration::from_millis(10), Duration::from_secs(1))
+    }
+}
+
 struct PeerInner {
-    rx: mpsc::Receiver<InPacket>,
+    queue: Arc<Queue<InPacket>>,
     addr: SocketAddr,
     event_send: mpsc::Sender<Event>,
 }
 pub struct Peer {
-    sender: mpsc::Sender<InPacket>,
+    queue: Arc<Queue<InPacket>>,
+    drop_policy: DropPolicy,
     pub(super) state: PeerState,
+    /// Last time any packet was received
+    last_seen: Instant,
+    /// Last time a LAN packet was received
+    last_lan: Instant,
+    /// Packets dropped because the queue was full
+    pub(super) dropped: u64,
+    /// Path MTU the peer uses for its own fragments
+    pmtu: Option<u16>,
+}
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
 }
 impl Peer {
-    pub fn new(addr: SocketAddr, event_send: mpsc::Sender<Event>) -> Self {
-        let (tx, rx) = mpsc::channel::<InPacket>(10);
+    pub fn new(addr: SocketAddr, event_send: mpsc::Sender<Event>, config: &PeerConfig) -> Self {
+        let queue = Arc::new(Queue::new(config.queue_size));
+        let task_queue = queue.clone();
         tokio::spawn(async move {
-            let exit_send = event_send.clone();
             let _ = Self::do_packet(PeerInner {
-                rx,
+                queue: task_queue,
                 addr,
                 event_send,
             })
             .await
             .map_err(|e| log::error!("peer task down {:?}", e));
-            log_err(
-                exit_send.send(Event::Close(addr)).await,
-                "peer task send close failed",
-            );
         });
+        let now = Instant::now();
         Self {
-            sender: tx,
-            state: PeerState::Connected(Instant::now()),
+            queue,
+            drop_policy: config.drop_policy,
+            state: PeerState::Connected,
+            last_seen: now,
+            last_lan: now,
+            dropped: 0,
+            pmtu: None,
         }
     }
     pub fn on_packet(
         &mut self,
         data: InPacket,
-    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        now: Instant,
+    ) -> std::result::Result<Push, Box<dyn std::error::Error>> {
         let frame = ForwarderFrame::parse(data.as_ref())?;
-        let now = Instant::now();
-        let state = match (frame, &self.state) {
-            (ForwarderFrame::Ipv4(..), _) | (ForwarderFrame::Ipv4Frag(..), _) => {
-                Some(PeerState::Connected(now))
-            }
-            (_, PeerState::Connected(last_time))
-                if now.duration_since(*last_time) < IDLE_TIMEOUT =>
-            {
-                None
+        self.last_seen = now;
+        if let ForwarderFrame::Ipv4(..) | ForwarderFrame::Ipv4Frag(..) = &frame {
+            self.last_lan = now;
+            self.state = PeerState::Connected;
+        }
+        if let ForwarderFrame::Ipv4Frag(frag) = &frame {
+            if frag.pmtu() > 0 {
+                self.pmtu = Some(frag.pmtu().clamp(MIN_PMTU, MAX_PMTU));
             }
-            (_, PeerState::Connected(_)) => Some(PeerState::Idle),
-            _ => None,
-        };
-        if let Some(state) = state {
-            self.state = state;
         }
 
-        Ok(self.sender.clone().try_send(data)?)
+        let push = self.queue.push(data, self.drop_policy);
+        if push != Push::Queued {
+            self.dropped += 1;
+        }
+        Ok(push)
+    }
+    /// Largest packet to send the peer in one frame
+    pub fn pmtu(&self, config: &PeerConfig) -> u16 {
+        self.pmtu.unwrap_or(config.pmtu)
+    }
+    /// Applies the timeouts and returns the new state
+    pub fn tick(&mut self, now: Instant, config: &PeerConfig) -> PeerState {
+        if now.duration_since(self.last_seen) >= config.timeout {
+            self.state = PeerState::Gone;
+        } else if self.state.is_connected()
+            && now.duration_since(self.last_lan) >= config.idle_timeout
+        {
+            self.state = PeerState::Idle;
+        }
+        self.state
     }
     async fn do_packet(inner: PeerInner) -> std::result::Result<(), Box<dyn std::error::Error>> {
         let PeerInner {
-            mut rx,
+            queue,
             addr,
             event_send,
         } = inner;
-        loop {
-            let packet = match timeout(Duration::from_secs(30), rx.recv()).await {
-                Ok(Some(packet)) => packet,
-                _ => {
-                    log::debug!("Timeout {}", addr);
-                    break;
-                }
-            };
-
+        // the queue is closed when the peer is removed
+        while let Some(packet) = queue.pop().await {
             let frame = ForwarderFrame::parse(packet.as_ref())?;
 
             match frame {
@@ -1,17 +1,79 @@
-use super::{Event, OutAddr, Peer};
+use super::frame::Fragmenter;
+use super::queue::Push;
+use super::{Event, InPacket, OutAddr, Packet, Peer, PeerConfig, PeerState};
+use crate::util::{send_to_many, BigInt};
+use async_graphql::{Enum, SimpleObject};
 use parking_lot::Mutex;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::{net::UdpSocket, sync::mpsc};
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, mpsc},
+    time::{interval, Instant, MissedTickBehavior},
+};
 
 pub struct PeerManagerInfo {
     /// The number of online clients
     pub online: i32,
-    /// The number of idle clients(not sending packets for 30s)
+    /// The number of idle clients(not sending LAN packets for the idle timeout)
     pub idle: i32,
 }
 
+/// The kind of a peer state transition
+#[derive(Enum, Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub enum PeerEventKind {
+    /// A new client sent its first packet
+    Connected,
+    /// The client stopped sending LAN packets
+    Idle,
+    /// An idle client sent a LAN packet again
+    Active,
+    /// The client timed out or was disconnected
+    Disconnected,
+}
+
+/// A peer state transition
+#[derive(SimpleObject, Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct PeerEvent {
+    pub kind: PeerEventKind,
+    /// The real address of the client
+    pub addr: String,
+}
+
+/// Packets the server gave up on since start
+#[derive(SimpleObject, Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct DropInfo {
+    /// Packets dropped because a peer's queue was full
+    dropped: BigInt,
+    /// Peers disconnected because their queue was full
+    disconnected: BigInt,
+    /// Packets the socket failed to send
+    send_failed: BigInt,
+}
+
+/// Infomation about a connected client
+#[derive(SimpleObject, Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct PeerInfo {
+    /// The real address of the client
+    addr: String,
+    /// Whether the client is idle
+    idle: bool,
+    /// Packets dropped because the client's queue was full
+    dropped: BigInt,
+    /// Largest packet the server sends the client in one frame
+    pmtu: u16,
+}
+
+#[derive(Default)]
+struct DropCounter {
+    dropped: AtomicU64,
+    disconnected: AtomicU64,
+    send_failed: AtomicU64,
+}
+
 struct InnerPeerManager {
     /// real ip to peer map
     cache: HashMap<SocketAddr, Peer>,
@@ -20,15 +82,26 @@ struct InnerPeerManager {
     map: HashMap<Ipv4Addr, SocketAddr>,
 
     ignore_idle: bool,
+    config: PeerConfig,
+    fragmenter: Fragmenter,
 }
 
 impl InnerPeerManager {
-    fn new(ignore_idle: bool) -> Self {
+    fn new(ignore_idle: bool, config: PeerConfig) -> Self {
         Self {
             cache: HashMap::new(),
             map: HashMap::new(),
             ignore_idle,
+            config,
+            fragmenter: Fragmenter::new(),
+        }
+    }
+    fn remove(&mut self, addr: &SocketAddr) -> Option<Peer> {
+        let peer = self.cache.remove(addr);
+        if peer.is_some() {
+            self.map.retain(|_, i| i != addr);
         }
+        peer
     }
 }
 
@@ -36,38 +109,151 @@ impl InnerPeerManager {
 pub struct PeerManager {
     udp_socket: Arc<UdpSocket>,
     inner: Arc<Mutex<InnerPeerManager>>,
+    drops: Arc<DropCounter>,
+    events: broadcast::Sender<PeerEvent>,
 }
 
 impl PeerManager {
-    pub fn new(udp_socket: Arc<UdpSocket>, ignore_idle: bool) -> Self {
+    pub fn new(udp_socket: Arc<UdpSocket>, ignore_idle: bool, config: PeerConfig) -> Self {
         Self {
             udp_socket,
-            inner: Arc::new(Mutex::new(InnerPeerManager::new(ignore_idle))),
+            inner: Arc::new(Mutex::new(InnerPeerManager::new(ignore_idle, config))),
+            drops: Default::default(),
+            events: broadcast::channel(64).0,
         }
     }
+    /// Subscribes to peer state transitions
+    pub fn subscribe(&self) -> broadcast::Receiver<PeerEvent> {
+        self.events.subscribe()
+    }
+    fn emit(&self, kind: PeerEventKind, addr: &SocketAddr) {
+        log::debug!("Peer {} {:?}", addr, kind);
+        // ignore the only error: no active receivers
+        let _ = self.events.send(PeerEvent {
+            kind,
+            addr: addr.to_string(),
+        });
+    }
+    /// Whether `addr` is a connected client
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.inner.lock().cache.contains_key(addr)
+    }
     pub async fn remove(&self, addr: &SocketAddr) {
-        let cache = &mut self.inner.lock().cache;
-        cache.remove(addr);
+        let removed = self.inner.lock().remove(addr);
+        if removed.is_some() {
+            self.emit(PeerEventKind::Disconnected, addr);
+        }
     }
-    pub async fn peer_mut<F>(&self, addr: &SocketAddr, event_send: &mpsc::Sender<Event>, func: F)
+    pub async fn peer_mut<F, R>(
+        &self,
+        addr: &SocketAddr,
+        event_send: &mpsc::Sender<Event>,
+        func: F,
+    ) -> R
     where
-        F: FnOnce(&mut Peer),
+        F: FnOnce(&mut Peer) -> R,
     {
-        let cache = &mut self.inner.lock().cache;
-        let peer = cache
+        let inner = &mut *self.inner.lock();
+        let config = &inner.config;
+        let peer = inner
+            .cache
             .entry(*addr)
-            .or_insert_with(|| Peer::new(*addr, event_send.clone()));
+            .or_insert_with(|| Peer::new(*addr, event_send.clone(), config));
         func(peer)
     }
+    /// Queues a packet from a client, applying the drop policy when its
+    /// queue is full.
+    pub async fn on_packet(&self, in_packet: InPacket, event_send: &mpsc::Sender<Event>) {
+        let addr = *in_packet.addr();
+        let (is_new, became_active, push) = {
+            let inner = &mut *self.inner.lock();
+            let is_new = !inner.cache.contains_key(&addr);
+            let config = &inner.config;
+            let peer = inner
+                .cache
+                .entry(addr)
+                .or_insert_with(|| Peer::new(addr, event_send.clone(), config));
+            let was_idle = peer.state.is_idle();
+            let push = peer.on_packet(in_packet, Instant::now());
+            let became_active = was_idle && !peer.state.is_idle();
+            if let Ok(Push::Disconnected) = push {
+                inner.remove(&addr);
+            }
+            (is_new, became_active, push)
+        };
+        if is_new {
+            self.emit(PeerEventKind::Connected, &addr);
+        }
+        if became_active {
+            self.emit(PeerEventKind::Active, &addr);
+        }
+        match push {
+            Ok(Push::Queued) | Err(_) => {}
+            Ok(Push::Disconnected) => {
+                log::info!("Disconnect slow peer {}", addr);
+                self.drops.disconnected.fetch_add(1, Ordering::Relaxed);
+                self.drops.dropped.fetch_add(1, Ordering::Relaxed);
+                self.emit(PeerEventKind::Disconnected, &addr);
+            }
+            Ok(Push::Dropped) | Ok(Push::Closed) => {
+                self.drops.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    /// Moves every peer through its state machine, removing the gone ones.
+    pub fn sweep(&self, now: Instant) {
+        let mut events = vec![];
+        {
+            let inner = &mut *self.inner.lock();
+            let config = &inner.config;
+            let mut gone = vec![];
+            for (addr, peer) in inner.cache.iter_mut() {
+                let before = peer.state;
+                match peer.tick(now, config) {
+                    PeerState::Gone => gone.push(*addr),
+                    PeerState::Idle if before != PeerState::Idle => {
+                        events.push((PeerEventKind::Idle, *addr))
+                    }
+                    _ => {}
+                }
+            }
+            for addr in gone {
+                log::debug!("Timeout {}", addr);
+                inner.remove(&addr);
+                events.push((PeerEventKind::Disconnected, addr));
+            }
+        }
+        for (kind, addr) in events {
+            self.emit(kind, &addr);
+        }
+    }
+    pub fn spawn_sweeper(&self) {
+        let peer_manager = self.clone();
+        let period = self.inner.lock().config.sweep_interval();
+        tokio::spawn(async move {
+            let mut timer = interval(period);
+            timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                timer.tick().await;
+                peer_manager.sweep(Instant::now());
+            }
+        });
+    }
     pub async fn send_broadcast(&self, packet: &[u8]) -> std::io::Result<usize> {
         let addrs = {
             let inner = &mut self.inner.lock();
-            inner
-                .cache.keys().copied()
-                .collect::<Vec<_>>()
+            inner.cache.keys().copied().collect::<Vec<_>>()
         };
         self.send_lan(packet, addrs).await
     }
+    /// [`send_ipv4`](Self::send_ipv4) to every peer
+    pub async fn broadcast_ipv4(&self, packet: &[u8]) -> std::io::Result<usize> {
+        let addrs = {
+            let inner = &mut self.inner.lock();
+            inner.cache.keys().copied().collect::<Vec<_>>()
+        };
+        self.send_ipv4(packet, addrs).await
+    }
     pub async fn get_dest_sockaddr(&self, from: SocketAddr, out_addr: OutAddr) -> Vec<SocketAddr> {
         let inner = &mut self.inner.lock();
         inner.map.insert(*out_addr.src_ip(), from);
@@ -85,13 +271,62 @@ impl PeerManager {
         }
     }
     pub async fn send_lan(&self, packet: &[u8], addrs: Vec<SocketAddr>) -> std::io::Result<usize> {
-        let len = packet.len();
-        let size: usize = addrs.len() * len;
-        for addr in addrs {
-            // TODO: handle error
-            let _ = self.udp_socket.send_to(packet, addr).await;
+        let sent = send_to_many(&self.udp_socket, packet, &addrs).await;
+        let failed = addrs.len() - sent;
+        if failed > 0 {
+            self.drops
+                .send_failed
+                .fetch_add(failed as u64, Ordering::Relaxed);
         }
-        Ok(size)
+        Ok(sent * packet.len())
+    }
+    /// Sends a whole IPv4 packet generated by the server, fragmented to each
+    /// peer's path MTU. Returns the bytes sent.
+    pub async fn send_ipv4(&self, packet: &[u8], addrs: Vec<SocketAddr>) -> std::io::Result<usize> {
+        let mut sent = 0;
+        for (frames, addrs) in self.fragment_ipv4(packet, addrs) {
+            for frame in frames {
+                sent += self.send_lan(&frame, addrs.clone()).await?;
+            }
+        }
+        Ok(sent)
+    }
+    /// The frames carrying a whole IPv4 packet to each peer, grouped by
+    /// path MTU. Peers whose MTU needs too many fragments are left out and
+    /// counted as failed sends.
+    pub fn fragment_ipv4(
+        &self,
+        packet: &[u8],
+        addrs: Vec<SocketAddr>,
+    ) -> Vec<(Vec<Packet>, Vec<SocketAddr>)> {
+        let mut by_pmtu: HashMap<u16, Vec<SocketAddr>> = HashMap::new();
+        let groups = {
+            let inner = &mut *self.inner.lock();
+            for addr in addrs {
+                let pmtu = inner
+                    .cache
+                    .get(&addr)
+                    .map(|peer| peer.pmtu(&inner.config))
+                    .unwrap_or(inner.config.pmtu);
+                by_pmtu.entry(pmtu).or_default().push(addr);
+            }
+            by_pmtu
+                .into_iter()
+                .map(|(pmtu, addrs)| (inner.fragmenter.fragment(packet, pmtu), addrs))
+                .collect::<Vec<_>>()
+        };
+        groups
+            .into_iter()
+            .filter_map(|(frames, addrs)| match frames {
+                Some(frames) => Some((frames, addrs)),
+                None => {
+                    self.drops
+                        .send_failed
+                        .fetch_add(addrs.len() as u64, Ordering::Relaxed);
+                    None
+                }
+            })
+            .collect()
     }
     pub async fn server_info(&self) -> PeerManagerInfo {
         let inner = &self.inner.lock();
@@ -99,4 +334,282 @@ impl PeerManager {
         let idle = inner.cache.values().filter(|i| i.state.is_idle()).count() as i32;
         PeerManagerInfo { online, idle }
     }
+    pub fn drop_info(&self) -> DropInfo {
+        DropInfo {
+            dropped: BigInt(self.drops.dropped.load(Ordering::Relaxed)),
+            disconnected: BigInt(self.drops.disconnected.load(Ordering::Relaxed)),
+            send_failed: BigInt(self.drops.send_failed.load(Ordering::Relaxed)),
+        }
+    }
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let inner = self.inner.lock();
+        inner
+            .cache
+            .iter()
+            .map(|(addr, peer)| PeerInfo {
+                addr: addr.to_string(),
+                idle: peer.state.is_idle(),
+                dropped: BigInt(peer.dropped),
+                pmtu: peer.pmtu(&inner.config),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PeerEventKind, PeerManager};
+    use crate::slp::{encode, ForwarderFrame, FragParser, Parser};
+    use crate::slp::{Event, InPacket, OutAddr, Packet, PeerConfig};
+    use crate::test::{make_frags, make_packet, recv_nothing, recv_packet};
+    use crate::util::BigInt;
+    use smoltcp::wire::Ipv4Address;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use tokio::net::UdpSocket;
+    use tokio::sync::{broadcast, mpsc};
+    use tokio::time::{sleep, Duration};
+
+    const A: &str = "1.2.3.4:1000";
+    const B: &str = "1.2.3.5:1000";
+
+    struct Fixture {
+        pm: PeerManager,
+        event_send: mpsc::Sender<Event>,
+        _event_recv: mpsc::Receiver<Event>,
+        events: broadcast::Receiver<super::PeerEvent>,
+    }
+
+    impl Fixture {
+        async fn new(idle_timeout: u64, timeout: u64) -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let config = PeerConfig {
+                idle_timeout: Duration::from_secs(idle_timeout),
+                timeout: Duration::from_secs(timeout),
+                ..Default::default()
+            };
+            let pm = PeerManager::new(Arc::new(socket), false, config);
+            pm.spawn_sweeper();
+            let (event_send, _event_recv) = mpsc::channel(100);
+            let events = pm.subscribe();
+            Self {
+                pm,
+                event_send,
+                _event_recv,
+                events,
+            }
+        }
+        async fn lan(&self, addr: &str, i: u8) {
+            let packet = make_packet(
+                Ipv4Address::new(10, 13, 37, i),
+                Ipv4Address::new(10, 13, 255, 255),
+            );
+            self.pm
+                .on_packet(
+                    InPacket::new(addr.parse().unwrap(), packet),
+                    &self.event_send,
+                )
+                .await;
+        }
+        async fn keepalive(&self, addr: &str) {
+            self.pm
+                .on_packet(
+                    InPacket::new(addr.parse().unwrap(), encode::keepalive()),
+                    &self.event_send,
+                )
+                .await;
+        }
+        async fn counts(&self) -> (i32, i32) {
+            let info = self.pm.server_info().await;
+            (info.online, info.idle)
+        }
+        fn events(&mut self) -> Vec<PeerEventKind> {
+            let mut kinds = vec![];
+            while let Ok(event) = self.events.try_recv() {
+                kinds.push(event.kind);
+            }
+            kinds
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_peer_goes_idle_then_gone() {
+        let mut f = Fixture::new(10, 30).await;
+        f.lan(A, 1).await;
+        sleep(Duration::from_secs(9)).await;
+        assert_eq!(f.counts().await, (1, 0));
+
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(f.counts().await, (1, 1));
+
+        sleep(Duration::from_secs(20)).await;
+        assert_eq!(f.counts().await, (0, 0));
+        assert_eq!(
+            f.events(),
+            vec![
+                PeerEventKind::Connected,
+                PeerEventKind::Idle,
+                PeerEventKind::Disconnected
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_does_not_keep_peer_active() {
+        let mut f = Fixture::new(5 * 60, 30).await;
+        f.lan(A, 1).await;
+        for _ in 0..16 {
+            sleep(Duration::from_secs(20)).await;
+            f.keepalive(A).await;
+        }
+        assert_eq!(f.counts().await, (1, 1));
+
+        sleep(Duration::from_secs(31)).await;
+        assert_eq!(f.counts().await, (0, 0));
+        assert_eq!(
+            f.events(),
+            vec![
+                PeerEventKind::Connected,
+                PeerEventKind::Idle,
+                PeerEventKind::Disconnected
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lan_packet_reactivates() {
+        let mut f = Fixture::new(10, 30).await;
+        f.lan(A, 1).await;
+        sleep(Duration::from_secs(11)).await;
+        assert_eq!(f.counts().await, (1, 1));
+
+        f.lan(A, 1).await;
+        assert_eq!(f.counts().await, (1, 0));
+        assert_eq!(
+            f.events(),
+            vec![
+                PeerEventKind::Connected,
+                PeerEventKind::Idle,
+                PeerEventKind::Active
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gone_peer_loses_its_virtual_ip() {
+        let f = Fixture::new(60, 30).await;
+        f.lan(A, 1).await;
+        let a: SocketAddr = A.parse().unwrap();
+        let b: SocketAddr = B.parse().unwrap();
+        let out = OutAddr::new(Ipv4Addr::new(10, 13, 37, 1), Ipv4Addr::new(10, 13, 37, 2));
+        f.pm.get_dest_sockaddr(a, out).await;
+
+        for _ in 0..3 {
+            sleep(Duration::from_secs(15)).await;
+            f.keepalive(B).await;
+        }
+        assert_eq!(f.counts().await, (1, 0));
+        let out = OutAddr::new(Ipv4Addr::new(10, 13, 37, 2), Ipv4Addr::new(10, 13, 37, 1));
+        assert_eq!(f.pm.get_dest_sockaddr(b, out).await, vec![]);
+    }
+
+    /// Two peers, the first of which sent fragments with `reported` as pmtu
+    async fn fragmenting_peers(
+        pmtu: u16,
+        reported: usize,
+    ) -> (PeerManager, SocketAddr, Vec<UdpSocket>) {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let server = socket.local_addr().unwrap();
+        let config = PeerConfig {
+            pmtu,
+            ..Default::default()
+        };
+        let pm = PeerManager::new(socket, false, config);
+        let (event_send, _event_recv) = mpsc::channel(100);
+
+        let mut clients = vec![];
+        for _ in 0..2 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect(server).await.unwrap();
+            clients.push(client);
+        }
+        let frame = make_packet(
+            Ipv4Address::new(10, 13, 37, 1),
+            Ipv4Address::new(10, 13, 37, 2),
+        );
+        let addr = clients[0].local_addr().unwrap();
+        for frag in make_frags(&frame, 1, reported) {
+            pm.on_packet(InPacket::new(addr, frag), &event_send).await;
+        }
+        (pm, server, clients)
+    }
+
+    fn ipv4_packet(len: usize) -> Vec<u8> {
+        let frame = make_packet(
+            Ipv4Address::new(10, 13, 37, 3),
+            Ipv4Address::new(10, 13, 37, 1),
+        );
+        let mut packet = frame[1..].to_vec();
+        packet.resize(len, 0);
+        packet
+    }
+
+    async fn recv_fragmented(client: &mut UdpSocket, server: SocketAddr, count: usize) -> Packet {
+        let mut parser = FragParser::new();
+        for i in 0..count {
+            let frame = recv_packet(client).await;
+            let frag = match ForwarderFrame::parse(&frame).unwrap() {
+                ForwarderFrame::Ipv4Frag(frag) => frag,
+                _ => panic!("expected a fragment"),
+            };
+            let reassembled = parser.process(server, &frag);
+            assert_eq!(reassembled.is_some(), i + 1 == count);
+            if let Some(reassembled) = reassembled {
+                recv_nothing(client).await;
+                return reassembled;
+            }
+        }
+        unreachable!()
+    }
+
+    #[tokio::test]
+    async fn send_ipv4_fragments_per_peer() {
+        let (pm, server, mut clients) = fragmenting_peers(200, 100).await;
+        let addrs: Vec<SocketAddr> = clients.iter().map(|c| c.local_addr().unwrap()).collect();
+        // the reported pmtu of 100 is raised to the minimum of 531
+        assert_eq!(pm.peers()[0].pmtu, 531);
+
+        let packet = ipv4_packet(1200);
+        pm.send_ipv4(&packet, addrs).await.unwrap();
+
+        for (client, count) in clients.iter_mut().zip([3, 6].iter()) {
+            assert_eq!(recv_fragmented(client, server, *count).await, packet);
+        }
+    }
+
+    #[tokio::test]
+    async fn send_ipv4_caps_reported_pmtu() {
+        let (pm, server, mut clients) = fragmenting_peers(200, 60000).await;
+        let addr = clients[0].local_addr().unwrap();
+        // frames larger than 2048 bytes would not parse
+        assert_eq!(pm.peers()[0].pmtu, 2031);
+
+        let packet = ipv4_packet(3000);
+        pm.send_ipv4(&packet, vec![addr]).await.unwrap();
+        assert_eq!(recv_fragmented(&mut clients[0], server, 2).await, packet);
+    }
+
+    #[tokio::test]
+    async fn send_ipv4_skips_unfragmentable() {
+        // 3000 bytes at a pmtu of 10 would take 300 parts
+        let (pm, server, mut clients) = fragmenting_peers(10, 100).await;
+        let addrs: Vec<SocketAddr> = clients.iter().map(|c| c.local_addr().unwrap()).collect();
+
+        let packet = ipv4_packet(3000);
+        pm.send_ipv4(&packet, addrs).await.unwrap();
+
+        assert_eq!(recv_fragmented(&mut clients[0], server, 6).await, packet);
+        recv_nothing(&mut clients[1]).await;
+        assert_eq!(pm.drop_info().send_failed, BigInt(1));
+    }
 }
@@ -1,15 +1,21 @@
-pub use crate::slp::{InPacket, OutPacket, Packet, PeerManager};
+pub use crate::slp::{Event, InFrame, InPacket, LanPacket, OutPacket, Packet, PeerManager};
 pub use async_trait::async_trait;
 use downcast_rs::{impl_downcast, Downcast};
 pub use std::net::SocketAddr;
+use tokio::sync::mpsc;
 
 pub struct Context<'a> {
     pub peer_manager: &'a PeerManager,
+    /// Packets sent as events go through every plugin's `out_packet`
+    pub event_send: &'a mpsc::Sender<Event>,
 }
 
 impl<'a> Context<'a> {
-    pub fn new(peer_manager: &'a PeerManager) -> Self {
-        Self { peer_manager }
+    pub fn new(peer_manager: &'a PeerManager, event_send: &'a mpsc::Sender<Event>) -> Self {
+        Self {
+            peer_manager,
+            event_send,
+        }
     }
 }
 
@@ -17,11 +23,18 @@ pub trait PluginType {
     fn create(context: Context) -> BoxPlugin;
 }
 
+/// Plugins are called concurrently from every receive loop, so they take
+/// `&self` and keep their own synchronization.
 #[async_trait]
 pub trait Plugin: Downcast {
-    async fn in_packet(&mut self, packet: &InPacket) -> Result<(), ()>;
-    async fn out_packet(&mut self, packet: &Packet, addrs: &[SocketAddr]) -> Result<(), ()>;
+    /// Called for every packet from a client, fragments included. Returning
+    /// `Err` drops it.
+    async fn in_packet(&self, frame: &InFrame<'_>) -> Result<(), ()>;
+    /// Called for every packet sent to clients. Removing an address skips
+    /// that client, returning `Err` drops the packet.
+    async fn out_packet(&self, packet: &Packet, addrs: &mut Vec<SocketAddr>) -> Result<(), ()>;
 }
 impl_downcast!(Plugin);
 
-pub type BoxPlugin = Box<dyn Plugin + Send + 'static>;
+pub type BoxPlugin = Box<dyn Plugin + Send + Sync + 'static>;
+pub type SharedPlugin = std::sync::Arc<dyn Plugin + Send + Sync + 'static>;
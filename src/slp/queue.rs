@@ -0,0 +1,161 @@
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use tokio::sync::Notify;
+
+#[derive(Debug)]
+pub struct DropPolicyParseError(String);
+impl std::fmt::Display for DropPolicyParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to parse drop policy: {}", self.0)
+    }
+}
+impl std::error::Error for DropPolicyParseError {}
+
+/// What to do with a packet when a peer's queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Drop the oldest queued packet to make room
+    DropOldest,
+    /// Drop the incoming packet
+    DropNewest,
+    /// Disconnect the slow peer
+    Disconnect,
+}
+
+impl std::fmt::Display for DropPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DropPolicy::DropOldest => write!(f, "drop-oldest"),
+            DropPolicy::DropNewest => write!(f, "drop-newest"),
+            DropPolicy::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+impl FromStr for DropPolicy {
+    type Err = DropPolicyParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "drop-oldest" => Ok(DropPolicy::DropOldest),
+            "drop-newest" => Ok(DropPolicy::DropNewest),
+            "disconnect" => Ok(DropPolicy::Disconnect),
+            _ => Err(DropPolicyParseError(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Push {
+    Queued,
+    /// A packet was dropped, either the oldest one or the pushed one
+    Dropped,
+    /// The queue was full and has been closed
+    Disconnected,
+    /// The queue is closed, the pushed packet is dropped
+    Closed,
+}
+
+struct QueueInner<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+/// Bounded single-consumer queue that applies a [`DropPolicy`] when full.
+pub struct Queue<T> {
+    inner: Mutex<QueueInner<T>>,
+    notify: Notify,
+    capacity: usize,
+}
+
+impl<T> Queue<T> {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            inner: Mutex::new(QueueInner {
+                items: VecDeque::with_capacity(capacity),
+                closed: false,
+            }),
+            notify: Notify::new(),
+            capacity,
+        }
+    }
+    pub fn push(&self, item: T, policy: DropPolicy) -> Push {
+        let mut inner = self.inner.lock();
+        if inner.closed {
+            return Push::Closed;
+        }
+        let result = if inner.items.len() < self.capacity {
+            inner.items.push_back(item);
+            Push::Queued
+        } else {
+            match policy {
+                DropPolicy::DropOldest => {
+                    inner.items.pop_front();
+                    inner.items.push_back(item);
+                    Push::Dropped
+                }
+                DropPolicy::DropNewest => Push::Dropped,
+                DropPolicy::Disconnect => {
+                    inner.items.clear();
+                    inner.closed = true;
+                    Push::Disconnected
+                }
+            }
+        };
+        drop(inner);
+        self.notify.notify_one();
+        result
+    }
+    /// Waits for the next item. Returns `None` once the queue is closed.
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                if let Some(item) = inner.items.pop_front() {
+                    return Some(item);
+                }
+                if inner.closed {
+                    return None;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+    pub fn close(&self) {
+        self.inner.lock().closed = true;
+        self.notify.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DropPolicy, Push, Queue};
+
+    #[tokio::test]
+    async fn drop_policy() {
+        let queue = Queue::new(2);
+        assert_eq!(queue.push(1, DropPolicy::DropNewest), Push::Queued);
+        assert_eq!(queue.push(2, DropPolicy::DropNewest), Push::Queued);
+        assert_eq!(queue.push(3, DropPolicy::DropNewest), Push::Dropped);
+        assert_eq!(queue.push(4, DropPolicy::DropOldest), Push::Dropped);
+        assert_eq!(queue.pop().await, Some(2));
+        assert_eq!(queue.pop().await, Some(4));
+
+        queue.push(5, DropPolicy::Disconnect);
+        queue.push(6, DropPolicy::Disconnect);
+        assert_eq!(queue.push(7, DropPolicy::Disconnect), Push::Disconnected);
+        assert_eq!(queue.push(8, DropPolicy::DropNewest), Push::Closed);
+        assert_eq!(queue.pop().await, None);
+    }
+
+    #[tokio::test]
+    async fn close_wakes_consumer() {
+        let queue = std::sync::Arc::new(Queue::<i32>::new(2));
+        let q = queue.clone();
+        let task = tokio::spawn(async move { q.pop().await });
+        tokio::task::yield_now().await;
+        queue.close();
+        assert_eq!(task.await.unwrap(), None);
+    }
+}
@@ -1,32 +1,37 @@
 use super::{
-    frame::{ForwarderFrame, Parser},
+    frame::{ForwarderFrame, FragParser, FragStats, Parser, KEEPALIVE_PACKET, MAX_PMTU, MIN_PMTU},
     log_warn,
-    peer_manager::{PeerManager, PeerManagerInfo},
-    plugin::{BoxPlugin, Context, PluginType},
+    peer::PeerConfig,
+    peer_manager::{DropInfo, PeerEvent, PeerInfo, PeerManager, PeerManagerInfo},
+    plugin::{Context, PluginType, SharedPlugin},
+    queue::DropPolicy,
     stream::spawn_stream,
-    Event, InPacket, Packet,
+    Event, InFrame, InPacket, LanPacket, Packet,
 };
-use crate::util::{create_socket, FilterSameExt};
+use crate::util::{create_socket, recv_batch, FilterSameExt, RecvBatch, RECV_BATCH_SIZE};
 use async_graphql::SimpleObject;
 use futures::prelude::*;
 use futures::stream::{BoxStream, StreamExt};
+use parking_lot::{Mutex, RwLock};
 use serde::Serialize;
+use std::any::TypeId;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::{any::TypeId, collections::HashMap};
 use tokio::io::Result;
 use tokio::net::UdpSocket;
-use tokio::sync::{broadcast, mpsc, Mutex};
-use tokio_stream::wrappers::{BroadcastStream, ReceiverStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{interval, Duration};
+use tokio_stream::wrappers::{BroadcastStream, IntervalStream, ReceiverStream};
 
 type ServerInfoStream = BoxStream<'static, ServerInfo>;
+type PeerEventStream = BoxStream<'static, PeerEvent>;
 
 /// Infomation about this server
 #[derive(SimpleObject, Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct ServerInfo {
     /// The number of online clients
     online: i32,
-    /// The number of idle clients(not sending packets for 30s)
+    /// The number of idle clients(not sending LAN packets for the idle timeout, 5 minutes by default)
     idle: i32,
     /// The version of the server
     version: String,
@@ -35,56 +40,101 @@ pub struct ServerInfo {
 pub struct UDPServerConfig {
     ignore_idle: bool,
     find_free_port: bool,
+    shards: usize,
+    event_queue_size: usize,
+    keepalive_interval: Option<Duration>,
+    peer: PeerConfig,
 }
 
+/// Plugins in registration order, which is the order they see packets in
+type PluginMap = Vec<(TypeId, SharedPlugin)>;
+
 pub struct Inner {
-    plugin: HashMap<TypeId, BoxPlugin>,
+    /// Replaced as a whole when a plugin is added, so the packet path only
+    /// clones an `Arc` and never waits on a plugin.
+    plugin: RwLock<Arc<PluginMap>>,
+    /// One per receive task. Kernel hashing keeps a client on one shard, so
+    /// all of its fragments reach the same parser and shards never contend.
+    frag_parsers: Vec<Mutex<FragParser>>,
 }
 
 impl Inner {
-    fn new() -> Arc<Mutex<Self>> {
-        Arc::new(Mutex::new(Self {
-            plugin: HashMap::new(),
-        }))
+    fn new(shards: usize) -> Arc<Self> {
+        Arc::new(Self {
+            plugin: RwLock::new(Arc::new(Vec::new())),
+            frag_parsers: (0..shards).map(|_| Mutex::new(FragParser::new())).collect(),
+        })
+    }
+    fn plugins(&self) -> Arc<PluginMap> {
+        self.plugin.read().clone()
+    }
+    /// Runs the `out_packet` hooks. Returns false if the packet is dropped.
+    async fn out_packet(&self, packet: &Packet, addrs: &mut Vec<SocketAddr>) -> bool {
+        for (_, p) in self.plugins().iter() {
+            if p.out_packet(packet, addrs).await.is_err() {
+                return false;
+            }
+        }
+        true
     }
 }
 
 #[derive(Clone)]
 pub struct UDPServer {
     peer_manager: PeerManager,
+    event_send: mpsc::Sender<Event>,
     info_sender: broadcast::Sender<ServerInfo>,
-    inner: Arc<Mutex<Inner>>,
+    inner: Arc<Inner>,
     local_addr: SocketAddr,
 }
 
-async fn find_port(mut addr: SocketAddr) -> Result<(SocketAddr, UdpSocket)> {
+async fn bind_shards(addr: &SocketAddr, shards: usize) -> Result<Vec<UdpSocket>> {
+    if shards <= 1 {
+        return Ok(vec![create_socket(addr, false).await?]);
+    }
+    let mut sockets = Vec::with_capacity(shards);
+    for _ in 0..shards {
+        sockets.push(create_socket(addr, true).await?);
+    }
+    Ok(sockets)
+}
+
+async fn find_port(mut addr: SocketAddr, shards: usize) -> Result<(SocketAddr, Vec<UdpSocket>)> {
     for port in addr.port()..65535 {
         addr.set_port(port);
-        match create_socket(&addr).await {
+        // probe without SO_REUSEPORT, so we don't join another server's group
+        if shards > 1 && create_socket(&addr, false).await.is_err() {
+            continue;
+        }
+        match bind_shards(&addr, shards).await {
             Ok(l) => return Ok((addr, l)),
             _ => continue,
         }
     }
-    Err(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        "Can't find avaliable port",
-    ))
+    Err(std::io::Error::other("Can't find avaliable port"))
 }
 
 impl UDPServer {
     pub async fn new(addr: &SocketAddr, config: UDPServerConfig) -> Result<Self> {
-        let inner = Inner::new();
-        let (event_send, event_recv) = mpsc::channel::<Event>(100);
-        let (local_addr, socket) = if config.find_free_port {
-            find_port(*addr).await?
+        let (event_send, event_recv) = mpsc::channel::<Event>(config.event_queue_size.max(1));
+        let (local_addr, sockets) = if config.find_free_port {
+            find_port(*addr, config.shards).await?
         } else {
-            (*addr, create_socket(addr).await?)
+            (*addr, bind_shards(addr, config.shards).await?)
         };
-        let shared_socket = Arc::new(socket);
-        let peer_manager = PeerManager::new(shared_socket.clone(), config.ignore_idle);
+        let sockets: Vec<_> = sockets.into_iter().map(Arc::new).collect();
+        let peer_manager = PeerManager::new(sockets[0].clone(), config.ignore_idle, config.peer);
+        let inner = Inner::new(sockets.len());
 
-        Self::spawn_recv(&inner, shared_socket.clone(), &peer_manager, &event_send);
+        // kernel hashing keeps each client on one shard
+        for (shard, socket) in sockets.iter().enumerate() {
+            Self::spawn_recv(&inner, shard, socket.clone(), &peer_manager, &event_send);
+        }
         Self::spawn_event(&inner, event_recv, &peer_manager);
+        peer_manager.spawn_sweeper();
+        if let Some(period) = config.keepalive_interval {
+            Self::spawn_keepalive(&peer_manager, period);
+        }
 
         let info_sender = spawn_stream(&peer_manager, |pm| async move {
             server_info_from_peer(&pm).await
@@ -92,13 +142,26 @@ impl UDPServer {
 
         Ok(Self {
             peer_manager,
+            event_send,
             info_sender,
             inner,
             local_addr,
         })
     }
+    fn spawn_keepalive(peer_manager: &PeerManager, period: Duration) {
+        let peer_manager = peer_manager.clone();
+        tokio::spawn(IntervalStream::new(interval(period)).for_each(move |_| {
+            let pm = peer_manager.clone();
+            async move {
+                log_warn(
+                    pm.send_broadcast(KEEPALIVE_PACKET).await,
+                    "failed to send keepalive",
+                );
+            }
+        }));
+    }
     fn spawn_event(
-        inner: &Arc<Mutex<Inner>>,
+        inner: &Arc<Inner>,
         event_recv: mpsc::Receiver<Event>,
         peer_manager: &PeerManager,
     ) {
@@ -107,7 +170,7 @@ impl UDPServer {
         tokio::spawn(async move { Self::event_task(&inner, event_recv, &peer_manager).await });
     }
     async fn event_task(
-        inner: &Arc<Mutex<Inner>>,
+        inner: &Arc<Inner>,
         event_recv: mpsc::Receiver<Event>,
         peer_manager: &PeerManager,
     ) {
@@ -117,21 +180,29 @@ impl UDPServer {
                 let peer_manager = peer_manager.clone();
                 async move {
                     match event {
-                        Event::Close(addr) => {
-                            peer_manager.remove(&addr).await;
-                        }
                         Event::SendLAN(from, out_packet) => {
                             let (packet, out_addr) = out_packet.split();
-                            let addrs = peer_manager.get_dest_sockaddr(from, out_addr).await;
-                            for p in &mut inner.lock().await.plugin.values_mut() {
-                                if p.out_packet(&packet, &addrs).await.is_err() {
-                                    return;
+                            let mut addrs = peer_manager.get_dest_sockaddr(from, out_addr).await;
+                            if inner.out_packet(&packet, &mut addrs).await {
+                                log_warn(
+                                    peer_manager.send_lan(&packet, addrs).await,
+                                    "failed to send lan packet",
+                                );
+                            }
+                        }
+                        Event::SendIpv4(packet, addrs) => {
+                            // hooks see every frame as it goes out
+                            for (frames, addrs) in peer_manager.fragment_ipv4(&packet, addrs) {
+                                for frame in frames {
+                                    let mut addrs = addrs.clone();
+                                    if inner.out_packet(&frame, &mut addrs).await {
+                                        log_warn(
+                                            peer_manager.send_lan(&frame, addrs).await,
+                                            "failed to send ipv4 packet",
+                                        );
+                                    }
                                 }
                             }
-                            log_warn(
-                                peer_manager.send_lan(&packet, addrs).await,
-                                "failed to send lan packet",
-                            );
                         }
                     }
                 }
@@ -139,7 +210,8 @@ impl UDPServer {
             .await
     }
     fn spawn_recv(
-        inner: &Arc<Mutex<Inner>>,
+        inner: &Arc<Inner>,
+        shard: usize,
         udp_socket: Arc<UdpSocket>,
         peer_manager: &PeerManager,
         event_send: &mpsc::Sender<Event>,
@@ -149,49 +221,77 @@ impl UDPServer {
         let peer_manager = peer_manager.clone();
         let event_send = event_send.clone();
         tokio::spawn(async move {
-            if let Err(e) = Self::recv_task(&inner, udp_socket, &peer_manager, &event_send).await {
+            if let Err(e) =
+                Self::recv_task(&inner, shard, udp_socket, &peer_manager, &event_send).await
+            {
                 log::error!("Recv task down: {:?}", e);
             }
         });
     }
     async fn recv_task(
-        inner: &Arc<Mutex<Inner>>,
+        inner: &Arc<Inner>,
+        shard: usize,
         udp_socket: Arc<UdpSocket>,
         peer_manager: &PeerManager,
         event_send: &mpsc::Sender<Event>,
     ) -> std::io::Result<()> {
+        let frag_parser = &inner.frag_parsers[shard];
+        let mut batch = RecvBatch::new(RECV_BATCH_SIZE, 65536);
         loop {
-            let mut buf = vec![0u8; 65536];
-            let (size, addr) = udp_socket.recv_from(&mut buf).await?;
-            buf.truncate(size);
-
-            let in_packet = InPacket::new(addr, buf);
-
-            let inner = inner.clone();
-            let peer_manager = peer_manager.clone();
-            let event_send = event_send.clone();
-
-            let addr = *in_packet.addr();
-            for p in &mut inner.lock().await.plugin.values_mut() {
-                if p.in_packet(&in_packet).await.is_err() {
-                    continue;
-                }
+            recv_batch(&udp_socket, &mut batch).await?;
+            // copied out first, so the batch isn't borrowed across an await
+            let packets: Vec<_> = batch
+                .iter()
+                .map(|(data, addr)| InPacket::new(addr, data.to_vec()))
+                .collect();
+            for in_packet in packets {
+                Self::on_packet(
+                    inner,
+                    frag_parser,
+                    &udp_socket,
+                    peer_manager,
+                    event_send,
+                    in_packet,
+                )
+                .await;
             }
-            let frame = match ForwarderFrame::parse(in_packet.as_ref()) {
-                Ok(f) => f,
-                Err(_) => continue,
-            };
-            if let ForwarderFrame::Ping(ping) = &frame {
-                Self::send_client(&udp_socket, vec![addr], &ping.build()).await;
-                continue;
+        }
+    }
+    async fn on_packet(
+        inner: &Arc<Inner>,
+        frag_parser: &Mutex<FragParser>,
+        udp_socket: &UdpSocket,
+        peer_manager: &PeerManager,
+        event_send: &mpsc::Sender<Event>,
+        in_packet: InPacket,
+    ) {
+        let addr = *in_packet.addr();
+        let frame = ForwarderFrame::parse(in_packet.as_ref()).ok();
+        let lan = match &frame {
+            Some(ForwarderFrame::Ipv4(ipv4)) => {
+                Some(LanPacket::new(ipv4.src_ip(), ipv4.dst_ip(), ipv4.data()))
             }
-            peer_manager
-                .peer_mut(&addr, &event_send, move |peer| {
-                    // ignore packet when channel is full
-                    let _ = peer.on_packet(in_packet);
-                })
-                .await;
+            Some(ForwarderFrame::Ipv4Frag(frag)) => frag_parser
+                .lock()
+                .process(addr, frag)
+                .map(|p| LanPacket::new(frag.src_ip(), frag.dst_ip(), p)),
+            _ => None,
+        };
+        let in_frame = InFrame::new(&in_packet, frame, lan);
+        for (_, p) in inner.plugins().iter() {
+            if p.in_packet(&in_frame).await.is_err() {
+                return;
+            }
+        }
+        match in_frame.frame() {
+            Some(ForwarderFrame::Ping(ping)) => {
+                Self::send_client(udp_socket, vec![addr], &ping.build()).await;
+                return;
+            }
+            Some(_) => {}
+            None => return,
         }
+        peer_manager.on_packet(in_packet, event_send).await;
     }
     async fn send_client(socket: &UdpSocket, addrs: Vec<SocketAddr>, packet: &Packet) {
         for addr in addrs {
@@ -204,6 +304,26 @@ impl UDPServer {
     pub async fn server_info(&self) -> ServerInfo {
         server_info_from_peer(&self.peer_manager).await
     }
+    pub fn drop_info(&self) -> DropInfo {
+        self.peer_manager.drop_info()
+    }
+    /// Fragment reassembly since start, summed over all shards
+    pub fn frag_stats(&self) -> FragStats {
+        let mut stats = FragStats::default();
+        for parser in &self.inner.frag_parsers {
+            stats += parser.lock().stats();
+        }
+        stats
+    }
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.peer_manager.peers()
+    }
+    /// Peer state transitions from now on
+    pub fn peer_event_stream(&self) -> PeerEventStream {
+        BroadcastStream::new(self.peer_manager.subscribe())
+            .filter_map(|event| future::ready(event.ok()))
+            .boxed()
+    }
     pub async fn server_info_stream(&self) -> ServerInfoStream {
         let stream = BroadcastStream::new(self.info_sender.subscribe())
             .take_while(|info| future::ready(info.is_ok()))
@@ -214,25 +334,30 @@ impl UDPServer {
             .filter_same()
             .boxed()
     }
+    /// Plugins see packets in the order they are added. Adding a plugin
+    /// again replaces it in place.
     pub async fn add_plugin<T>(&self)
     where
         T: PluginType + 'static,
     {
-        let plugin = T::create(Context::new(&self.peer_manager));
-        self.inner
-            .lock()
-            .await
-            .plugin
-            .insert(TypeId::of::<T>(), plugin);
+        let plugin: SharedPlugin =
+            T::create(Context::new(&self.peer_manager, &self.event_send)).into();
+        let mut map = self.inner.plugin.write();
+        let mut plugins = PluginMap::clone(&map);
+        match plugins.iter_mut().find(|(id, _)| *id == TypeId::of::<T>()) {
+            Some(entry) => entry.1 = plugin,
+            None => plugins.push((TypeId::of::<T>(), plugin)),
+        }
+        *map = Arc::new(plugins);
     }
     pub async fn get_plugin<T, F, R>(&self, func: F) -> R
     where
         T: PluginType + 'static,
-        F: Fn(Option<&mut T>) -> R,
+        F: Fn(Option<&T>) -> R,
     {
-        let mut inner = self.inner.lock().await;
-        let plugin = inner.plugin.get_mut(&TypeId::of::<T>()).unwrap();
-        func(plugin.as_any_mut().downcast_mut::<T>())
+        let plugins = self.inner.plugins();
+        let plugin = plugins.iter().find(|(id, _)| *id == TypeId::of::<T>());
+        func(plugin.and_then(|(_, p)| p.as_any().downcast_ref::<T>()))
     }
     #[allow(dead_code)]
     pub fn local_addr(&self) -> &SocketAddr {
@@ -262,6 +387,10 @@ impl UDPServerBuilder {
         UDPServerBuilder(UDPServerConfig {
             ignore_idle: false,
             find_free_port: false,
+            shards: 1,
+            event_queue_size: 100,
+            keepalive_interval: None,
+            peer: PeerConfig::default(),
         })
     }
     #[allow(dead_code)]
@@ -273,6 +402,48 @@ impl UDPServerBuilder {
         self.0.ignore_idle = v;
         self
     }
+    /// Number of sockets bound to the address with `SO_REUSEPORT`, each with
+    /// its own receive loop.
+    pub fn shards(mut self, v: usize) -> Self {
+        self.0.shards = v.max(1);
+        self
+    }
+    /// Packets queued per peer before the drop policy applies
+    pub fn peer_queue_size(mut self, v: usize) -> Self {
+        self.0.peer.queue_size = v;
+        self
+    }
+    pub fn drop_policy(mut self, v: DropPolicy) -> Self {
+        self.0.peer.drop_policy = v;
+        self
+    }
+    /// Path MTU of a client until it sends fragments of its own, clamped
+    /// to what every host accepts and what a frame can carry
+    pub fn pmtu(mut self, v: u16) -> Self {
+        self.0.peer.pmtu = v.clamp(MIN_PMTU, MAX_PMTU);
+        self
+    }
+    /// Events waiting for the event loop. When it's full, peers wait and
+    /// their queues start dropping.
+    pub fn event_queue_size(mut self, v: usize) -> Self {
+        self.0.event_queue_size = v;
+        self
+    }
+    /// Disconnect a client after sending nothing for this long
+    pub fn timeout(mut self, v: Duration) -> Self {
+        self.0.peer.timeout = v;
+        self
+    }
+    /// Mark a client idle after sending no LAN packets for this long
+    pub fn idle_timeout(mut self, v: Duration) -> Self {
+        self.0.peer.idle_timeout = v;
+        self
+    }
+    /// Send keepalives to every client, so their NAT mappings stay open
+    pub fn keepalive_interval(mut self, v: Option<Duration>) -> Self {
+        self.0.keepalive_interval = v;
+        self
+    }
     pub async fn build(self, addr: &SocketAddr) -> Result<UDPServer> {
         let udp_server = UDPServer::new(addr, self.0).await?;
         Ok(udp_server)
@@ -281,13 +452,269 @@ impl UDPServerBuilder {
 
 #[cfg(test)]
 mod test {
-    use super::UDPServerBuilder;
-    use crate::plugin::{self, traffic::TrafficPlugin};
-    use crate::test::{client_connect, make_packet, make_server, recv_packet};
+    use super::{DropPolicy, Event, UDPServerBuilder};
+    use crate::graphql::{schema, Ctx};
+    use crate::plugin::{
+        self,
+        blocker::BlockerPlugin,
+        traffic::{TalkerKind, TrafficPlugin},
+    };
+    use crate::slp::{encode, PeerEventKind};
+    use crate::test::{
+        client_connect, make_frags, make_packet, make_server, make_server_with,
+        make_sharded_server, make_tcp_packet, recv_packet,
+    };
+    use crate::util::BigInt;
+    use futures::StreamExt;
     use smoltcp::wire::*;
+    use tokio::net::UdpSocket;
+    use tokio::time::{self, sleep, timeout, Duration};
+
+    const BROADCAST: Ipv4Address = Ipv4Address::new(10, 13, 255, 255);
+    const KEEPALIVE: &[u8] = &[0];
+
+    fn client_ip(i: u8) -> Ipv4Address {
+        Ipv4Address::new(10, 13, 37, 100 + i)
+    }
+
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Moves the clock forward at once, so the sweeper sees exactly `d` pass
+    /// however slow the machine is
+    async fn advance(d: Duration) {
+        time::pause();
+        time::advance(d).await;
+        time::resume();
+    }
+
+    /// The next peer event, waiting for the sweeper if needed
+    async fn next_event(events: &mut super::PeerEventStream) -> PeerEventKind {
+        timeout(Duration::from_secs(5), events.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .kind
+    }
+
+    /// Connects `count` clients. Each sends a packet to its own LAN address,
+    /// which comes back once the server has mapped the address.
+    async fn connect_clients(addr: std::net::SocketAddr, count: u8) -> Vec<UdpSocket> {
+        let mut sockets = vec![];
+        for i in 0..count {
+            let mut socket = client_connect(addr).await;
+            let packet = make_packet(client_ip(i), client_ip(i));
+            socket.send(&packet).await.unwrap();
+            assert_eq!(recv_packet(&mut socket).await, packet);
+            sockets.push(socket);
+        }
+        sockets
+    }
+
+    /// Client 1 goes idle: it only sends keepalives after connecting
+    async fn idle_scenario(ignore_idle: bool) -> (super::UDPServer, Vec<UdpSocket>) {
+        let (udp_server, addr) = make_server_with(
+            UDPServerBuilder::new()
+                .ignore_idle(ignore_idle)
+                .idle_timeout(IDLE_TIMEOUT)
+                .timeout(TIMEOUT),
+        )
+        .await;
+        let mut events = udp_server.peer_event_stream();
+        let mut sockets = connect_clients(addr, 3).await;
+        advance(IDLE_TIMEOUT).await;
+        for _ in 0..3 {
+            while next_event(&mut events).await != PeerEventKind::Idle {}
+        }
+        // client 0 and 2 stay active
+        let packet0 = make_packet(client_ip(0), client_ip(2));
+        let packet2 = make_packet(client_ip(2), client_ip(0));
+        sockets[0].send(&packet0).await.unwrap();
+        sockets[2].send(&packet2).await.unwrap();
+        sockets[1].send(KEEPALIVE).await.unwrap();
+        assert_eq!(recv_packet(&mut sockets[2]).await, packet0);
+        assert_eq!(recv_packet(&mut sockets[0]).await, packet2);
+        (udp_server, sockets)
+    }
+
+    #[tokio::test]
+    async fn test_idle_count() {
+        let (udp_server, _sockets) = idle_scenario(false).await;
+        let info = udp_server.server_info().await;
+        assert_eq!(info.online, 3);
+        assert_eq!(info.idle, 1);
+    }
+
+    #[tokio::test]
+    async fn test_idle_receives_broadcast() {
+        let (_udp_server, mut sockets) = idle_scenario(false).await;
+        let packet = make_packet(client_ip(0), BROADCAST);
+        sockets[0].send(&packet).await.unwrap();
+
+        assert_eq!(recv_packet(&mut sockets[1]).await, packet);
+        assert_eq!(recv_packet(&mut sockets[2]).await, packet);
+    }
+
+    #[tokio::test]
+    async fn test_ignore_idle_skips_broadcast() {
+        let (_udp_server, mut sockets) = idle_scenario(true).await;
+        let packet = make_packet(client_ip(0), BROADCAST);
+        sockets[0].send(&packet).await.unwrap();
+
+        assert_eq!(recv_packet(&mut sockets[2]).await, packet);
+        // a broadcast goes out in one batch, so it would be here already
+        assert!(sockets[1].try_recv(&mut [0u8; 64]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ignore_idle_still_unicasts() {
+        let (_udp_server, mut sockets) = idle_scenario(true).await;
+        let packet = make_packet(client_ip(0), client_ip(1));
+        sockets[0].send(&packet).await.unwrap();
+
+        assert_eq!(recv_packet(&mut sockets[1]).await, packet);
+    }
+
+    #[tokio::test]
+    async fn test_ignore_idle_wakes_up() {
+        let (udp_server, mut sockets) = idle_scenario(true).await;
+        let wake = make_packet(client_ip(1), client_ip(0));
+        sockets[1].send(&wake).await.unwrap();
+        assert_eq!(recv_packet(&mut sockets[0]).await, wake);
+        assert_eq!(udp_server.server_info().await.idle, 0);
+
+        let packet = make_packet(client_ip(0), BROADCAST);
+        sockets[0].send(&packet).await.unwrap();
+        assert_eq!(recv_packet(&mut sockets[1]).await, packet);
+    }
+
+    #[tokio::test]
+    async fn test_peer_events() {
+        let (udp_server, addr) = make_server_with(
+            UDPServerBuilder::new()
+                .idle_timeout(IDLE_TIMEOUT)
+                .timeout(TIMEOUT),
+        )
+        .await;
+        let mut events = udp_server.peer_event_stream();
+        let mut socket = client_connect(addr).await;
+        socket
+            .send(&make_packet(client_ip(0), BROADCAST))
+            .await
+            .unwrap();
+        assert_eq!(next_event(&mut events).await, PeerEventKind::Connected);
+        advance(IDLE_TIMEOUT).await;
+        assert_eq!(next_event(&mut events).await, PeerEventKind::Idle);
+
+        // the ping is answered after the keepalive is handled
+        socket.send(KEEPALIVE).await.unwrap();
+        let ping = encode::ping([1, 2, 3, 4]);
+        socket.send(&ping).await.unwrap();
+        assert_eq!(recv_packet(&mut socket).await, ping);
+        assert_eq!(udp_server.server_info().await.idle, 1);
+
+        socket
+            .send(&make_packet(client_ip(0), BROADCAST))
+            .await
+            .unwrap();
+        assert_eq!(next_event(&mut events).await, PeerEventKind::Active);
+        advance(IDLE_TIMEOUT).await;
+        assert_eq!(next_event(&mut events).await, PeerEventKind::Idle);
+        advance(TIMEOUT).await;
+        assert_eq!(next_event(&mut events).await, PeerEventKind::Disconnected);
+        assert_eq!(udp_server.server_info().await.online, 0);
+    }
+
+    #[tokio::test]
+    async fn test_keepalive() {
+        let (_udp_server, addr) = make_server_with(
+            UDPServerBuilder::new().keepalive_interval(Some(Duration::from_millis(50))),
+        )
+        .await;
+        let mut socket = client_connect(addr).await;
+        socket.send(KEEPALIVE).await.unwrap();
+
+        assert_eq!(recv_packet(&mut socket).await, KEEPALIVE);
+    }
 
     const ADDR: &str = "127.0.0.1:12121";
 
+    /// Runs an admin query against the server's GraphQL schema
+    async fn admin_query(udp_server: &super::UDPServer, query: &str) -> serde_json::Value {
+        let ctx = Ctx::new(udp_server.clone(), Some("token".to_string()));
+        let response = schema(&ctx).execute(query).await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        response.data.into_json().unwrap()
+    }
+
+    /// Sends `count` LAN packets at once, so the server receives them in one
+    /// batch before the peer's queue is drained
+    async fn burst(addr: std::net::SocketAddr, count: usize) -> UdpSocket {
+        let socket = client_connect(addr).await;
+        for _ in 0..count {
+            socket
+                .send(&make_packet(client_ip(0), BROADCAST))
+                .await
+                .unwrap();
+        }
+        sleep(Duration::from_millis(50)).await;
+        socket
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops() {
+        let (udp_server, addr) = make_server_with(
+            UDPServerBuilder::new()
+                .peer_queue_size(1)
+                .drop_policy(DropPolicy::DropNewest),
+        )
+        .await;
+        let socket = burst(addr, 5).await;
+
+        let data = admin_query(
+            &udp_server,
+            r#"{ dropInfo(token: "token") { dropped disconnected } peers(token: "token") { addr dropped } }"#,
+        )
+        .await;
+        assert_eq!(data["dropInfo"]["dropped"], "4");
+        assert_eq!(data["dropInfo"]["disconnected"], "0");
+        let peers = data["peers"].as_array().unwrap();
+        assert_eq!(peers.len(), 1);
+        let port = socket.local_addr().unwrap().port();
+        assert!(peers[0]["addr"]
+            .as_str()
+            .unwrap()
+            .ends_with(&format!(":{}", port)));
+        assert_eq!(peers[0]["dropped"], "4");
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_disconnects() {
+        let (udp_server, addr) = make_server_with(
+            UDPServerBuilder::new()
+                .peer_queue_size(1)
+                .drop_policy(DropPolicy::Disconnect),
+        )
+        .await;
+        let mut events = udp_server.peer_event_stream();
+        let _socket = burst(addr, 2).await;
+
+        let data = admin_query(
+            &udp_server,
+            r#"{ dropInfo(token: "token") { dropped disconnected } peers(token: "token") { addr } }"#,
+        )
+        .await;
+        assert_eq!(data["dropInfo"]["dropped"], "1");
+        assert_eq!(data["dropInfo"]["disconnected"], "1");
+        assert_eq!(data["peers"].as_array().unwrap().len(), 0);
+
+        let kinds: Vec<_> = (&mut events).take(2).map(|e| e.kind).collect().await;
+        assert_eq!(
+            kinds,
+            vec![PeerEventKind::Connected, PeerEventKind::Disconnected]
+        );
+    }
+
     #[tokio::test]
     async fn test_get_plugin() {
         let udp_server = UDPServerBuilder::new()
@@ -297,15 +724,136 @@ mod test {
             .unwrap();
         plugin::register_plugins(&udp_server).await;
         let traffic = udp_server
-            .get_plugin::<TrafficPlugin, _, _>(|traffic| traffic.map(|t| t.clone()))
+            .get_plugin::<TrafficPlugin, _, _>(|traffic| traffic.cloned())
             .await;
         assert!(traffic.is_some(), "Traffic should be Some");
     }
 
+    #[tokio::test]
+    async fn test_block_rules() {
+        let (udp_server, addr) = make_server().await;
+        plugin::register_plugins(&udp_server).await;
+        udp_server
+            .get_plugin::<BlockerPlugin, _, _>(|b| {
+                b.map(|b| b.set_block_rules(vec!["tcp:5000".parse().unwrap()]))
+            })
+            .await;
+
+        let socket1 = client_connect(addr).await;
+        let mut socket2 = client_connect(addr).await;
+        let ip1 = Ipv4Address::new(10, 13, 37, 100);
+        let ip2 = Ipv4Address::new(10, 13, 37, 101);
+        socket2.send(&make_packet(ip2, ip1)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        socket1
+            .send(&make_tcp_packet(ip1, ip2, 5000))
+            .await
+            .unwrap();
+        let allowed = make_tcp_packet(ip1, ip2, 5001);
+        socket1.send(&allowed).await.unwrap();
+
+        assert_eq!(recv_packet(&mut socket2).await, allowed);
+    }
+
+    #[tokio::test]
+    async fn test_block_fragmented() {
+        let (udp_server, addr) = make_server().await;
+        plugin::register_plugins(&udp_server).await;
+        udp_server
+            .get_plugin::<BlockerPlugin, _, _>(|b| {
+                b.map(|b| b.set_block_rules(vec!["tcp:5000".parse().unwrap()]))
+            })
+            .await;
+
+        let socket1 = client_connect(addr).await;
+        let mut socket2 = client_connect(addr).await;
+        let ip1 = Ipv4Address::new(10, 13, 37, 100);
+        let ip2 = Ipv4Address::new(10, 13, 37, 101);
+        socket2.send(&make_packet(ip2, ip1)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let frags = make_frags(&make_tcp_packet(ip1, ip2, 5000), 1, 24);
+        assert_eq!(frags.len(), 2);
+        for frag in &frags {
+            socket1.send(frag).await.unwrap();
+        }
+        let allowed = make_tcp_packet(ip1, ip2, 5001);
+        socket1.send(&allowed).await.unwrap();
+
+        // the packet is only known to be blocked once it is complete
+        assert_eq!(recv_packet(&mut socket2).await, frags[0]);
+        assert_eq!(recv_packet(&mut socket2).await, allowed);
+        assert_eq!(udp_server.frag_stats().completed, BigInt(1));
+    }
+
+    #[tokio::test]
+    async fn test_ipv4_hooks_see_fragments() {
+        let (udp_server, addr) = make_server().await;
+        udp_server.add_plugin::<TrafficPlugin>().await;
+        let mut client = client_connect(addr).await;
+        client.send(KEEPALIVE).await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+
+        let mut packet = make_packet(client_ip(1), client_ip(0))[1..].to_vec();
+        packet.resize(3000, 0);
+        let client_addr = client.local_addr().unwrap();
+        udp_server
+            .event_send
+            .send(Event::SendIpv4(packet, vec![client_addr]))
+            .await
+            .unwrap();
+        // 3000 bytes at the default pmtu of 1300
+        let mut sent = 0;
+        for _ in 0..3 {
+            sent += recv_packet(&mut client).await.len() as u64;
+        }
+        sleep(Duration::from_millis(20)).await;
+
+        let talkers = udp_server
+            .get_plugin::<TrafficPlugin, _, _>(|t| t.map(|t| t.top_talkers(TalkerKind::Peer, 1)))
+            .await
+            .unwrap();
+        assert_eq!(talkers[0].total.upload, BigInt(sent));
+        assert_eq!(talkers[0].total.upload_packet, BigInt(3));
+    }
+
     #[tokio::test]
     async fn test_server() {
         let (_udp_server, addr) = make_server().await;
+        relay_between_two_clients(addr).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sharded_server() {
+        let (_udp_server, addr) = make_sharded_server(4).await;
+        relay_between_two_clients(addr).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sharded_reassembly() {
+        let (udp_server, addr) = make_sharded_server(4).await;
+        let ip = Ipv4Address::new(10, 13, 37, 100);
+        let other = Ipv4Address::new(10, 13, 37, 101);
+        let frags = make_frags(&make_tcp_packet(ip, other, 5000), 1, 24);
+
+        // clients spread over the shards, each completing one packet
+        let mut sockets = vec![];
+        for _ in 0..8 {
+            let socket = client_connect(addr).await;
+            for frag in &frags {
+                socket.send(frag).await.unwrap();
+            }
+            sockets.push(socket);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(udp_server.frag_stats().completed, BigInt(8));
+    }
 
+    async fn relay_between_two_clients(addr: std::net::SocketAddr) {
         let mut socket1 = client_connect(addr).await;
         let mut socket2 = client_connect(addr).await;
 
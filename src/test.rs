@@ -1,4 +1,4 @@
-use crate::slp::{UDPServer, UDPServerBuilder};
+use crate::slp::{encode, FragHeader, UDPServer, UDPServerBuilder};
 use smoltcp::phy::ChecksumCapabilities;
 use smoltcp::wire::*;
 use std::net::SocketAddr;
@@ -15,16 +15,65 @@ pub fn make_packet(src_addr: Ipv4Address, dst_addr: Ipv4Address) -> Vec<u8> {
         payload_len: 0,
         hop_limit: 64,
     };
-    let mut bytes = vec![0xa5; 1 + repr.buffer_len()];
-    let mut packet = Ipv4Packet::new_unchecked(&mut bytes[1..]);
+    let mut bytes = vec![0xa5; repr.buffer_len()];
+    let mut packet = Ipv4Packet::new_unchecked(&mut bytes);
     repr.emit(&mut packet, &ChecksumCapabilities::default());
-    bytes[0] = 1;
 
-    bytes
+    encode::ipv4(&bytes)
+}
+
+/// A forwarder IPv4 frame carrying a bare TCP header to `dst_port`.
+pub fn make_tcp_packet(src_addr: Ipv4Address, dst_addr: Ipv4Address, dst_port: u16) -> Vec<u8> {
+    let repr = Ipv4Repr {
+        src_addr,
+        dst_addr,
+        next_header: IpProtocol::Tcp,
+        payload_len: 20,
+        hop_limit: 64,
+    };
+    let mut bytes = vec![0u8; repr.buffer_len() + repr.payload_len];
+    let mut packet = Ipv4Packet::new_unchecked(&mut bytes);
+    repr.emit(&mut packet, &ChecksumCapabilities::default());
+    let tcp = &mut bytes[repr.buffer_len()..];
+    tcp[0..2].copy_from_slice(&12345u16.to_be_bytes());
+    tcp[2..4].copy_from_slice(&dst_port.to_be_bytes());
+    // data offset: 5 words
+    tcp[12] = 0x50;
+
+    encode::ipv4(&bytes)
+}
+
+/// Splits a forwarder IPv4 frame into `Ipv4Frag` frames of `pmtu` bytes.
+pub fn make_frags(frame: &[u8], id: u16, pmtu: usize) -> Vec<Vec<u8>> {
+    let packet = Ipv4Packet::new_unchecked(&frame[1..]);
+    let chunks: Vec<_> = frame[1..].chunks(pmtu).collect();
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(part, chunk)| {
+            let header = FragHeader {
+                src_ip: packet.src_addr().into(),
+                dst_ip: packet.dst_addr().into(),
+                id,
+                part: part as u8,
+                total_part: chunks.len() as u8,
+                pmtu: pmtu as u16,
+            };
+            encode::ipv4_frag(&header, chunk)
+        })
+        .collect()
 }
 
 pub async fn make_server() -> (UDPServer, SocketAddr) {
-    let udp_server = UDPServerBuilder::new()
+    make_sharded_server(1).await
+}
+
+pub async fn make_sharded_server(shards: usize) -> (UDPServer, SocketAddr) {
+    make_server_with(UDPServerBuilder::new().shards(shards)).await
+}
+
+pub async fn make_server_with(builder: UDPServerBuilder) -> (UDPServer, SocketAddr) {
+    let udp_server = builder
         .find_free_port(true)
         .build(&ADDR.parse().unwrap())
         .await
@@ -45,6 +94,23 @@ pub async fn recv_packet(socket: &mut UdpSocket) -> Vec<u8> {
     buf
 }
 
+/// Asserts that nothing arrives for a while. A late packet can slip past on
+/// a slow machine, so prefer waiting for a reply that has to come after it.
+pub async fn recv_nothing(socket: &mut UdpSocket) {
+    let mut buf = vec![0u8; 65536];
+    let r = timeout(Duration::from_millis(100), socket.recv(&mut buf)).await;
+    assert!(r.is_err(), "Unexpected packet {:?}", r);
+}
+
+/// Discards everything received so far
+pub async fn drain(socket: &mut UdpSocket) {
+    let mut buf = vec![0u8; 65536];
+    while timeout(Duration::from_millis(50), socket.recv(&mut buf))
+        .await
+        .is_ok()
+    {}
+}
+
 pub async fn client_connect(addr: SocketAddr) -> UdpSocket {
     let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
     socket.connect(addr).await.unwrap();
@@ -0,0 +1,294 @@
+//! Batched UDP I/O.
+//!
+//! On Linux, `recvmmsg(2)` and `sendmmsg(2)` move a whole batch of datagrams
+//! in one syscall. Other platforms fall back to one `recv_from`/`send_to` per
+//! datagram.
+//!
+//! UDP GSO (`UDP_SEGMENT`) is not used: it splits one buffer into several
+//! datagrams for a single destination, while the relay sends one datagram to
+//! many destinations.
+
+use std::{io, net::SocketAddr};
+use tokio::net::UdpSocket;
+
+/// Number of datagrams received per syscall.
+pub const RECV_BATCH_SIZE: usize = 32;
+
+/// Reusable buffers for [`recv_batch`].
+pub struct RecvBatch {
+    bufs: Vec<Vec<u8>>,
+    /// Buffer index, length and source of each datagram received
+    meta: Vec<(usize, usize, SocketAddr)>,
+    #[cfg(target_os = "linux")]
+    headers: sys::RecvHeaders,
+}
+
+impl RecvBatch {
+    pub fn new(batch_size: usize, buf_size: usize) -> Self {
+        let batch_size = batch_size.max(1);
+        Self {
+            bufs: vec![vec![0u8; buf_size]; batch_size],
+            meta: Vec::with_capacity(batch_size),
+            #[cfg(target_os = "linux")]
+            headers: sys::RecvHeaders::new(batch_size),
+        }
+    }
+    /// Datagrams received by the last call to [`recv_batch`].
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> + '_ {
+        self.meta
+            .iter()
+            .map(move |&(index, len, addr)| (&self.bufs[index][..len], addr))
+    }
+}
+
+/// Waits for at least one datagram and receives as many as are ready, up to
+/// the batch size. Returns the number of datagrams received.
+pub async fn recv_batch(socket: &UdpSocket, batch: &mut RecvBatch) -> io::Result<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        use tokio::io::Interest;
+
+        let fd = socket.as_raw_fd();
+        let RecvBatch {
+            bufs,
+            meta,
+            headers,
+        } = batch;
+        socket
+            .async_io(Interest::READABLE, || {
+                sys::recvmmsg(fd, bufs, headers, meta)
+            })
+            .await
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let (len, addr) = socket.recv_from(&mut batch.bufs[0]).await?;
+        batch.meta.clear();
+        batch.meta.push((0, len, addr));
+        Ok(1)
+    }
+}
+
+/// Sends `packet` to every address in `addrs`. Returns the number of
+/// datagrams accepted by the kernel; failed destinations are skipped.
+pub async fn send_to_many(socket: &UdpSocket, packet: &[u8], addrs: &[SocketAddr]) -> usize {
+    if let [addr] = addrs {
+        return socket.send_to(packet, addr).await.map_or(0, |_| 1);
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use socket2::SockAddr;
+        use std::os::fd::AsRawFd;
+        use tokio::io::Interest;
+
+        let fd = socket.as_raw_fd();
+        let addrs: Vec<SockAddr> = addrs.iter().map(|addr| SockAddr::from(*addr)).collect();
+        let mut sent = 0;
+        let mut offset = 0;
+        while offset < addrs.len() {
+            match socket
+                .async_io(Interest::WRITABLE, || {
+                    sys::sendmmsg(fd, packet, &addrs[offset..])
+                })
+                .await
+            {
+                Ok(n) => {
+                    sent += n;
+                    offset += n;
+                }
+                // sendmmsg only reports an error for the first message of the batch
+                Err(_) => offset += 1,
+            }
+        }
+        sent
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let mut sent = 0;
+        for addr in addrs {
+            if socket.send_to(packet, addr).await.is_ok() {
+                sent += 1;
+            }
+        }
+        sent
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use socket2::SockAddr;
+    use std::{io, mem, net::SocketAddr, os::fd::RawFd, ptr};
+
+    /// The kernel refuses more than `UIO_MAXIOV` messages per call.
+    const MAX_BATCH: usize = 1024;
+
+    pub fn sendmmsg(fd: RawFd, packet: &[u8], addrs: &[SockAddr]) -> io::Result<usize> {
+        let mut iov = libc::iovec {
+            iov_base: packet.as_ptr() as *mut libc::c_void,
+            iov_len: packet.len(),
+        };
+        let iov: *mut libc::iovec = &mut iov;
+        let mut msgs: Vec<libc::mmsghdr> = addrs
+            .iter()
+            .take(MAX_BATCH)
+            .map(|addr| {
+                // Safety: an all-zero msghdr is a valid empty header.
+                let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+                hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+                hdr.msg_namelen = addr.len();
+                hdr.msg_iov = iov;
+                hdr.msg_iovlen = 1;
+                libc::mmsghdr {
+                    msg_hdr: hdr,
+                    msg_len: 0,
+                }
+            })
+            .collect();
+        // Safety: every header points to an address and an iovec that outlive the call.
+        let n = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), msgs.len() as _, 0) };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    /// `recvmmsg` headers, kept between calls so a batch allocates nothing.
+    pub struct RecvHeaders {
+        storages: Vec<libc::sockaddr_storage>,
+        iovs: Vec<libc::iovec>,
+        msgs: Vec<libc::mmsghdr>,
+    }
+
+    // Safety: the raw pointers in `iovs` and `msgs` are rewritten before each
+    // `recvmmsg` call and never dereferenced outside of it.
+    unsafe impl Send for RecvHeaders {}
+
+    impl RecvHeaders {
+        pub fn new(batch_size: usize) -> Self {
+            // Safety: all-zero sockaddr_storage, iovec and mmsghdr are valid.
+            unsafe {
+                Self {
+                    storages: vec![mem::zeroed(); batch_size],
+                    iovs: vec![mem::zeroed(); batch_size],
+                    msgs: vec![mem::zeroed(); batch_size],
+                }
+            }
+        }
+    }
+
+    pub fn recvmmsg(
+        fd: RawFd,
+        bufs: &mut [Vec<u8>],
+        headers: &mut RecvHeaders,
+        meta: &mut Vec<(usize, usize, SocketAddr)>,
+    ) -> io::Result<usize> {
+        let RecvHeaders {
+            storages,
+            iovs,
+            msgs,
+        } = headers;
+        for (((buf, iov), storage), msg) in bufs
+            .iter_mut()
+            .zip(iovs.iter_mut())
+            .zip(storages.iter_mut())
+            .zip(msgs.iter_mut())
+        {
+            iov.iov_base = buf.as_mut_ptr() as *mut libc::c_void;
+            iov.iov_len = buf.len();
+            // Safety: an all-zero msghdr is a valid empty header.
+            let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+            hdr.msg_name = storage as *mut libc::sockaddr_storage as *mut libc::c_void;
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = iov;
+            hdr.msg_iovlen = 1;
+            msg.msg_hdr = hdr;
+            msg.msg_len = 0;
+        }
+        // Safety: every header points to a buffer and an address storage that outlive the call.
+        let n = unsafe {
+            libc::recvmmsg(
+                fd,
+                msgs.as_mut_ptr(),
+                msgs.len().min(bufs.len()) as _,
+                libc::MSG_DONTWAIT as _,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        meta.clear();
+        for (index, (msg, storage)) in msgs
+            .iter()
+            .zip(storages.iter())
+            .take(n as usize)
+            .enumerate()
+        {
+            // Safety: the kernel initialized `msg_namelen` bytes of the storage.
+            let addr = unsafe { SockAddr::new(*storage, msg.msg_hdr.msg_namelen) };
+            // datagrams without an IP source are skipped, the rest keep their buffer
+            if let Some(addr) = addr.as_socket() {
+                meta.push((index, msg.msg_len as usize, addr));
+            }
+        }
+        Ok(meta.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{recv_batch, send_to_many, RecvBatch};
+    use tokio::net::UdpSocket;
+
+    #[test]
+    fn iter_keeps_buffer_of_each_datagram() {
+        let mut batch = RecvBatch::new(3, 4);
+        batch.bufs[0].copy_from_slice(&[1, 1, 1, 1]);
+        batch.bufs[2].copy_from_slice(&[3, 3, 3, 3]);
+        let (a, b) = (
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:3".parse().unwrap(),
+        );
+        // the second datagram had no usable source and was skipped
+        batch.meta = vec![(0, 2, a), (2, 3, b)];
+        let got: Vec<_> = batch.iter().collect();
+        assert_eq!(got, vec![(&[1u8, 1][..], a), (&[3u8, 3, 3][..], b)]);
+    }
+
+    #[tokio::test]
+    async fn send_and_recv_batch() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut clients = vec![];
+        for _ in 0..3 {
+            clients.push(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        }
+        let addrs: Vec<_> = clients.iter().map(|c| c.local_addr().unwrap()).collect();
+
+        assert_eq!(send_to_many(&server, &[1, 2, 3], &addrs).await, 3);
+        let mut buf = [0u8; 16];
+        for client in &clients {
+            let size = client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..size], &[1, 2, 3]);
+            client
+                .send_to(&[4, 5], server.local_addr().unwrap())
+                .await
+                .unwrap();
+        }
+
+        let mut batch = RecvBatch::new(8, 64);
+        let mut received = vec![];
+        while received.len() < clients.len() {
+            recv_batch(&server, &mut batch).await.unwrap();
+            for (data, addr) in batch.iter() {
+                assert_eq!(data, &[4, 5]);
+                received.push(addr);
+            }
+        }
+        received.sort();
+        let mut expected = addrs.clone();
+        expected.sort();
+        assert_eq!(received, expected);
+    }
+}
@@ -0,0 +1,58 @@
+use async_graphql::{InputValueError, InputValueResult, Scalar, ScalarType, Value};
+use serde::{Deserialize, Serialize};
+
+/// An unsigned 64-bit integer. GraphQL `Int` is 32-bit, so it is sent as a
+/// string.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct BigInt(pub u64);
+
+#[Scalar]
+impl ScalarType for BigInt {
+    fn parse(value: Value) -> InputValueResult<Self> {
+        match &value {
+            Value::String(s) => Ok(BigInt(s.parse()?)),
+            Value::Number(n) => n
+                .as_u64()
+                .map(BigInt)
+                .ok_or_else(|| InputValueError::custom("expected an unsigned integer")),
+            _ => Err(InputValueError::expected_type(value)),
+        }
+    }
+    fn to_value(&self) -> Value {
+        Value::String(self.0.to_string())
+    }
+}
+
+impl From<u64> for BigInt {
+    fn from(v: u64) -> Self {
+        BigInt(v)
+    }
+}
+
+/// Clamps to an `Int` field, for fields kept for old clients
+pub fn saturate(v: u64) -> i32 {
+    v.min(i32::MAX as u64) as i32
+}
+
+#[cfg(test)]
+mod test {
+    use super::{saturate, BigInt};
+    use async_graphql::{ScalarType, Value};
+
+    #[test]
+    fn test_big_int() {
+        let big = BigInt(u64::MAX);
+        assert_eq!(big.to_value(), Value::String("18446744073709551615".into()));
+        assert_eq!(BigInt::parse(big.to_value()).unwrap(), big);
+        assert_eq!(BigInt::parse(Value::from(42)).unwrap(), BigInt(42));
+        assert!(BigInt::parse(Value::from(-1)).is_err());
+        assert!(BigInt::parse(Value::Boolean(true)).is_err());
+        assert_eq!(serde_json::to_string(&BigInt(7)).unwrap(), "7");
+
+        assert_eq!(saturate(7), 7);
+        assert_eq!(saturate(u64::MAX), i32::MAX);
+    }
+}
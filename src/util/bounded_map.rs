@@ -0,0 +1,159 @@
+//! A map of per-client counters that a flood of new keys can't grow or
+//! wipe out.
+
+use lru::LruCache;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::num::NonZeroUsize;
+
+/// Keys are admitted while there is room. Once the map is full, new keys
+/// go to a small overflow cache, where they push out each other rather
+/// than established keys. [`promote`](Self::promote) moves the heaviest
+/// of them in, replacing lighter established keys.
+#[derive(Debug)]
+pub struct BoundedMap<K: Hash + Eq, V> {
+    main: HashMap<K, V>,
+    overflow: LruCache<K, V>,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> BoundedMap<K, V> {
+    pub fn new(capacity: usize, overflow: usize) -> Self {
+        Self {
+            main: HashMap::new(),
+            overflow: LruCache::new(NonZeroUsize::new(overflow.max(1)).unwrap()),
+            capacity,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.main.len() + self.overflow.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.main.contains_key(key) || self.overflow.contains(key)
+    }
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.main.get(key).or_else(|| self.overflow.peek(key))
+    }
+    pub fn get_or_default(&mut self, key: K) -> &mut V
+    where
+        V: Default,
+    {
+        if self.overflow.contains(&key) {
+            return self.overflow.get_mut(&key).unwrap();
+        }
+        if self.main.contains_key(&key) || self.main.len() < self.capacity {
+            return self.main.entry(key).or_default();
+        }
+        self.overflow.get_or_insert_mut(key, V::default)
+    }
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.main.remove(key).or_else(|| self.overflow.pop(key))
+    }
+    pub fn clear(&mut self) {
+        self.main.clear();
+        self.overflow.clear();
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.main.iter().chain(self.overflow.iter())
+    }
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        self.main.retain(&mut f);
+        let removed: Vec<K> = self
+            .overflow
+            .iter_mut()
+            .filter_map(|(key, value)| {
+                if f(key, value) {
+                    None
+                } else {
+                    Some(key.clone())
+                }
+            })
+            .collect();
+        for key in removed {
+            self.overflow.pop(&key);
+        }
+    }
+    /// Moves overflow keys into the map, heaviest first, while there is
+    /// room or they outweigh the lightest established key, which takes
+    /// their place in the overflow.
+    pub fn promote(&mut self, weight: impl Fn(&V) -> u64) {
+        if self.overflow.is_empty() {
+            return;
+        }
+        let mut candidates: Vec<(K, u64)> = self
+            .overflow
+            .iter()
+            .map(|(key, value)| (key.clone(), weight(value)))
+            .collect();
+        candidates.sort_by_key(|(_, w)| std::cmp::Reverse(*w));
+        let mut lightest: Vec<(K, u64)> = self
+            .main
+            .iter()
+            .map(|(key, value)| (key.clone(), weight(value)))
+            .collect();
+        lightest.sort_by_key(|(_, w)| *w);
+        let mut lightest = lightest.into_iter();
+
+        for (key, w) in candidates {
+            let displaced = if self.main.len() < self.capacity {
+                None
+            } else {
+                match lightest.next() {
+                    Some((old, old_w)) if old_w < w => Some(old),
+                    _ => break,
+                }
+            };
+            // pop first, so pushing the displaced key never evicts
+            if let Some(value) = self.overflow.pop(&key) {
+                self.main.insert(key, value);
+            }
+            if let Some(old) = displaced {
+                let value = self.main.remove(&old).unwrap();
+                self.overflow.push(old, value);
+            }
+        }
+    }
+    /// The established keys
+    pub fn main(&self) -> &HashMap<K, V> {
+        &self.main
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BoundedMap;
+
+    fn keys(map: &BoundedMap<u32, u64>) -> Vec<u32> {
+        let mut keys: Vec<_> = map.main().keys().copied().collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut map = BoundedMap::new(2, 2);
+        *map.get_or_default(1) += 100;
+        *map.get_or_default(2) += 50;
+        for key in 10..20 {
+            *map.get_or_default(key) += 1;
+        }
+        // established keys are untouched, overflow keeps the newest
+        assert_eq!(keys(&map), vec![1, 2]);
+        assert_eq!(map.len(), 4);
+        assert!(map.contains_key(&19));
+        assert!(!map.contains_key(&10));
+
+        *map.get_or_default(19) += 60;
+        map.promote(|v| *v);
+        assert_eq!(keys(&map), vec![1, 19]);
+        assert_eq!(map.get(&2), Some(&50));
+
+        map.retain(|_, v| *v > 1);
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.remove(&2), Some(50));
+        assert_eq!(map.remove(&2), None);
+    }
+}
@@ -3,7 +3,11 @@ use std::{io, net::SocketAddr};
 use socket2::{Domain, Socket, Type};
 use tokio::net::UdpSocket;
 
-pub async fn create_socket(addr: &SocketAddr) -> io::Result<UdpSocket> {
+/// Creates a nonblocking UDP socket bound to `addr`.
+///
+/// With `reuse_port`, `SO_REUSEPORT` is set so that several sockets can bind
+/// the same address and the kernel spreads clients between them.
+pub async fn create_socket(addr: &SocketAddr, reuse_port: bool) -> io::Result<UdpSocket> {
     let udp = match addr {
         SocketAddr::V4(_) => Socket::new(Domain::IPV4, Type::DGRAM, None)?,
         SocketAddr::V6(_) => Socket::new(Domain::IPV6, Type::DGRAM, None)?,
@@ -15,7 +19,24 @@ pub async fn create_socket(addr: &SocketAddr) -> io::Result<UdpSocket> {
     udp.set_recv_buffer_size(size)?;
     udp.set_send_buffer_size(size)?;
 
+    if reuse_port {
+        set_reuse_port(&udp)?;
+    }
+
     udp.bind(&(*addr).into())?;
 
     UdpSocket::from_std(udp.into())
 }
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
@@ -1,5 +1,13 @@
+mod batch_io;
+mod big_int;
+mod bounded_map;
 mod create_socket;
 mod filter_same;
+mod state_file;
 
+pub use batch_io::*;
+pub use big_int::*;
+pub use bounded_map::*;
 pub use create_socket::*;
 pub use filter_same::*;
+pub use state_file::*;
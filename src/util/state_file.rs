@@ -0,0 +1,63 @@
+//! State kept on disk between restarts.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// `<path>.tmp`, so state files that only differ in extension never share
+/// a temporary file.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(".tmp");
+    tmp.into()
+}
+
+/// Writes to a temporary file first, so a crash never leaves a torn file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = tmp_path(path);
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(tmp, path)
+}
+
+/// Runs `save` on the blocking pool, so a slow disk never holds up the
+/// packet path. Failures are logged, naming the state as `what`.
+pub async fn save_blocking<F>(path: PathBuf, what: &str, save: F)
+where
+    F: FnOnce(&Path) -> io::Result<()> + Send + 'static,
+{
+    let result = tokio::task::spawn_blocking(move || save(&path))
+        .await
+        .map_err(io::Error::from)
+        .and_then(|r| r);
+    if let Err(e) = result {
+        log::warn!("Failed to save {}: {}", what, e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{tmp_path, write_atomic};
+    use std::path::Path;
+
+    #[test]
+    fn test_tmp_path_keeps_extension() {
+        assert_eq!(
+            tmp_path(Path::new("/var/lib/slp/quota.json")),
+            Path::new("/var/lib/slp/quota.json.tmp")
+        );
+        assert_ne!(
+            tmp_path(Path::new("state.quota")),
+            tmp_path(Path::new("state.traffic"))
+        );
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let path = std::env::temp_dir().join(format!("slp-state-{}.json", std::process::id()));
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert!(!tmp_path(&path).exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}